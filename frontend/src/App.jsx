@@ -11,6 +11,7 @@ function App() {
   const [sessionId, setSessionId] = useState(null);
   const [isLoading, setIsLoading] = useState(false);
   const [filterOptions, setFilterOptions] = useState(null);
+  const [parseStatus, setParseStatus] = useState(null);
   const [filters, setFilters] = useState({
     level: null,
     categories: [],
@@ -32,28 +33,66 @@ function App() {
     console.log(`File uploaded successfully with session ID: ${uploadedSessionId}`);
     setSessionId(uploadedSessionId);
     setIsLoading(true);
+    setParseStatus(null);
     
-    // Start polling for filter options
-    pollForFilterOptions(uploadedSessionId, 0);
+    // Start polling for the parsing status
+    pollSessionStatus(uploadedSessionId);
   };
   
-  const pollForFilterOptions = async (uploadedSessionId, attempt) => {
-    const maxAttempts = 10;
+  const pollSessionStatus = async (uploadedSessionId) => {
     const delayMs = 1000;
     
-    if (attempt >= maxAttempts) {
-      console.error(`Failed to fetch filter options after ${maxAttempts} attempts`);
+    try {
+      const response = await fetch(`/api/sessions/${uploadedSessionId}/status`);
+      if (!response.ok) {
+        throw new Error(`Failed to fetch session status (${response.status})`);
+      }
+      
+      const status = await response.json();
+      setParseStatus(status);
+      
+      // Unloaded sessions are stored on disk, querying them loads them again
+      if (status.state === 'ready' || status.state === 'unloaded') {
+        fetchFilterOptions(uploadedSessionId);
+        return;
+      }
+      
+      if (status.state === 'evicted') {
+        console.error('Session evicted:', status.reason);
+        setIsLoading(false);
+        toast({
+          title: 'Error',
+          description: `The session was removed from the server: ${status.reason}`,
+          variant: 'destructive',
+        });
+        return;
+      }
+      
+      if (status.state === 'failed') {
+        console.error('Parsing failed:', status.error);
+        setIsLoading(false);
+        toast({
+          title: 'Error',
+          description: `Failed to parse log file: ${status.error}`,
+          variant: 'destructive',
+        });
+        return;
+      }
+      
+      // Still queued or parsing
+      setTimeout(() => pollSessionStatus(uploadedSessionId), delayMs);
+    } catch (error) {
+      console.error('Error fetching session status:', error);
       setIsLoading(false);
       toast({
         title: 'Error',
-        description: `Failed to fetch filter options after ${maxAttempts} attempts. The log file may be too large or in an incorrect format.`,
+        description: error.message,
         variant: 'destructive',
       });
-      return;
     }
-    
-    console.log(`Fetching filter options, attempt ${attempt + 1}/${maxAttempts}`);
-    
+  };
+  
+  const fetchFilterOptions = async (uploadedSessionId) => {
     try {
       const url = `/api/filter-options?session_id=${uploadedSessionId}`;
       console.log(`Making request to: ${url}`);
@@ -61,13 +100,6 @@ function App() {
       const response = await fetch(url);
       console.log(`Response status: ${response.status}`);
       
-      if (response.status === 404) {
-        // Session not found yet, the file might still be processing
-        console.log('Session not found yet, waiting before retrying...');
-        setTimeout(() => pollForFilterOptions(uploadedSessionId, attempt + 1), delayMs);
-        return;
-      }
-      
       if (!response.ok) {
         // Try to get more detailed error information
         let errorMessage = 'Failed to fetch filter options';
@@ -95,9 +127,12 @@ function App() {
       });
     } catch (error) {
       console.error('Error fetching filter options:', error);
-      
-      // If there was an error that's not a 404, still retry
-      setTimeout(() => pollForFilterOptions(uploadedSessionId, attempt + 1), delayMs);
+      setIsLoading(false);
+      toast({
+        title: 'Error',
+        description: error.message,
+        variant: 'destructive',
+      });
     }
   };
   
@@ -127,13 +162,14 @@ function App() {
   const handleRetry = () => {
     if (sessionId) {
       setIsLoading(true);
-      pollForFilterOptions(sessionId, 0);
+      pollSessionStatus(sessionId);
     }
   };
   
   const handleReset = () => {
     setSessionId(null);
     setFilterOptions(null);
+    setParseStatus(null);
     setIsLoading(false);
     setTimeRange(null);
     setFilters({
@@ -171,14 +207,22 @@ function App() {
                 <div className="inline-block animate-spin rounded-full h-8 w-8 border-b-2 border-primary mb-4"></div>
                 <h2 className="text-lg font-semibold mb-2">Processing Log File</h2>
                 <p className="text-gray-500 mb-4">
-                  This may take a moment for large files...
+                  {parseStatus && parseStatus.state === 'parsing' && parseStatus.total_bytes > 0
+                    ? `Parsed ${Math.floor((parseStatus.bytes_read / parseStatus.total_bytes) * 100)}% of the file...`
+                    : 'This may take a moment for large files...'}
                 </p>
               </div>
             ) : !filterOptions ? (
               <div className="text-center py-20">
                 <div className="bg-red-100 text-red-800 p-4 rounded-lg mb-6 inline-block">
-                  <p className="font-bold">Failed to fetch filter options</p>
-                  <p className="text-sm mt-1">The log file may be too large or in an incorrect format.</p>
+                  <p className="font-bold">Failed to load log file</p>
+                  <p className="text-sm mt-1">
+                    {parseStatus && parseStatus.state === 'failed'
+                      ? parseStatus.error
+                      : parseStatus && parseStatus.state === 'evicted'
+                        ? `The session was removed from the server: ${parseStatus.reason}`
+                        : 'The log file may be too large or in an incorrect format.'}
+                  </p>
                 </div>
                 <div className="flex justify-center space-x-4">
                   <button
@@ -23,17 +23,17 @@ const FileUpload = ({ onUploadSuccess }) => {
     setIsDragging(false);
     
     if (e.dataTransfer.files && e.dataTransfer.files.length > 0) {
-      const file = e.dataTransfer.files[0];
-      setFileName(file.name);
-      uploadFile(file);
+      const files = Array.from(e.dataTransfer.files);
+      setFileName(files.map((file) => file.name).join(', '));
+      uploadFiles(files);
     }
   };
 
   const handleFileChange = (e) => {
     if (e.target.files && e.target.files.length > 0) {
-      const file = e.target.files[0];
-      setFileName(file.name);
-      uploadFile(file);
+      const files = Array.from(e.target.files);
+      setFileName(files.map((file) => file.name).join(', '));
+      uploadFiles(files);
     }
   };
 
@@ -41,12 +41,12 @@ const FileUpload = ({ onUploadSuccess }) => {
     fileInputRef.current.click();
   };
 
-  const uploadFile = async (file) => {
+  const uploadFiles = async (files) => {
     setIsUploading(true);
     
     try {
       const formData = new FormData();
-      formData.append('file', file);
+      files.forEach((file) => formData.append('file', file));
 
       const response = await fetch('/api/upload', {
         method: 'POST',
@@ -87,7 +87,8 @@ const FileUpload = ({ onUploadSuccess }) => {
               type="file"
               ref={fileInputRef}
               className="hidden"
-              accept=".log,.txt"
+              multiple
+              accept=".log,.txt,.json,.jsonl,.gz,.zst,.xz,.zip,.tar,.tgz"
               onChange={handleFileChange}
             />
             <Upload className="h-12 w-12 mx-auto mb-4 text-gray-400" />
@@ -106,7 +107,7 @@ const FileUpload = ({ onUploadSuccess }) => {
           </div>
         </CardContent>
         <CardFooter className="text-sm text-gray-500">
-          Supported formats: .log, .txt
+          Supported formats: .log, .txt, journalctl -o json exports as .json or .jsonl (optionally compressed as .gz, .zst or .xz), .zip and .tar archives
         </CardFooter>
       </Card>
 
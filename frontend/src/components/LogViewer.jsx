@@ -167,6 +167,7 @@ const LogViewer = ({ sessionId, filters, pagination, onPageChange, onPerPageChan
                             <span className="text-xs text-gray-500 mt-1">
                               {entry.file}:{entry.line} ({entry.function})
                               {entry.object && <> &lt;{entry.object}&gt;</>}
+                              {entry.line_number > 0 && <> · {entry.source} line {entry.line_number}</>}
                             </span>
                           </div>
                         </td>
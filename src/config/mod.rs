@@ -0,0 +1,126 @@
+//! Runtime configuration read from environment variables
+
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use encoding_rs::Encoding;
+
+// Default maximum size of an upload, in megabytes
+const DEFAULT_MAX_UPLOAD_SIZE_MB: u64 = 500;
+
+// Where the entries of parsed sessions are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionBackend {
+    // In memory, sessions must fit in memory
+    Memory,
+    // In column files on disk that are memory-mapped, for logs larger than memory
+    Mapped,
+    // In an SQLite database that also stores the sessions, filters are run as SQL queries
+    Sqlite,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Maximum number of bytes accepted for a single upload request
+    pub max_upload_size: u64,
+    // Directory of server-side log files that can be tailed, tailing is disabled when unset
+    pub tail_dir: Option<PathBuf>,
+    // Encoding of log lines that aren't valid UTF-8, they are decoded lossily when unset
+    pub fallback_encoding: Option<&'static Encoding>,
+    // Directory where parsed sessions are stored, sessions are kept in memory only when unset
+    pub data_dir: Option<PathBuf>,
+    // Sessions that haven't been used for this long are unloaded if sessions are stored,
+    // and deleted otherwise
+    pub session_ttl: Option<Duration>,
+    // Estimated memory that entries of all sessions may use, in bytes
+    pub memory_budget: Option<u64>,
+    // Where the entries of parsed sessions are kept
+    pub session_backend: SessionBackend,
+    // Directory of the files of mapped sessions, the system temporary directory when unset
+    pub mapped_dir: Option<PathBuf>,
+    // Database of the SQLite backend, `sessions.db` in the data directory when unset
+    pub sqlite_path: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        // Eviction policies are disabled with 0
+        let session_ttl_secs = env_scaled("SESSION_TTL_MINUTES", 0, 60);
+        let memory_budget = env_scaled("MEMORY_BUDGET_MB", 0, 1024 * 1024);
+
+        Config {
+            max_upload_size: env_scaled(
+                "MAX_UPLOAD_SIZE_MB",
+                DEFAULT_MAX_UPLOAD_SIZE_MB,
+                1024 * 1024,
+            ),
+            tail_dir: env::var_os("TAIL_DIR").map(PathBuf::from),
+            fallback_encoding: env_encoding("FALLBACK_ENCODING"),
+            data_dir: env::var_os("DATA_DIR").map(PathBuf::from).or_else(|| {
+                // Cloudron keeps this directory across restarts and updates of the app
+                env::var_os("CLOUDRON_APP_DATA_DIR")
+                    .map(|data_dir| PathBuf::from(data_dir).join("sessions"))
+            }),
+            session_ttl: (session_ttl_secs > 0).then(|| Duration::from_secs(session_ttl_secs)),
+            memory_budget: (memory_budget > 0).then_some(memory_budget),
+            session_backend: env_backend("SESSION_BACKEND"),
+            mapped_dir: env::var_os("MAPPED_DIR").map(PathBuf::from),
+            sqlite_path: env::var_os("SQLITE_PATH").map(PathBuf::from),
+        }
+    }
+}
+
+// Read an encoding label such as "latin1" or "shift_jis" from the environment
+fn env_encoding(name: &str) -> Option<&'static Encoding> {
+    let label = env::var(name).ok()?;
+    let encoding = Encoding::for_label(label.trim().as_bytes());
+    if encoding.is_none() {
+        log::warn!("Unknown encoding for {}: {:?}, ignoring it", name, label);
+    }
+    encoding
+}
+
+// Read the session backend from the environment, entries are kept in memory by default
+fn env_backend(name: &str) -> SessionBackend {
+    match env::var(name).as_deref().map(str::trim) {
+        Err(_) | Ok("memory") => SessionBackend::Memory,
+        Ok("mapped") => SessionBackend::Mapped,
+        Ok("sqlite") => SessionBackend::Sqlite,
+        Ok(value) => {
+            log::warn!("Unknown backend for {}: {:?}, using memory", name, value);
+            SessionBackend::Memory
+        }
+    }
+}
+
+// Read an unsigned integer from the environment and multiply it by a unit, such as
+// megabytes to bytes. Values that overflow are rejected like invalid ones.
+fn env_scaled(name: &str, default: u64, unit: u64) -> u64 {
+    let value = env_u64(name, default);
+    value.checked_mul(unit).unwrap_or_else(|| {
+        log::error!(
+            "Value for {} is too large: {}, using default {}",
+            name,
+            value,
+            default
+        );
+        default * unit
+    })
+}
+
+// Read an unsigned integer from the environment, falling back to a default
+fn env_u64(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            log::warn!(
+                "Invalid value for {}: {:?}, using default {}",
+                name,
+                value,
+                default
+            );
+            default
+        }),
+        Err(_) => default,
+    }
+}
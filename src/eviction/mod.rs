@@ -0,0 +1,341 @@
+//! Eviction of sessions to keep the resources of the server bounded
+//!
+//! Two policies are enforced periodically, each enabled in the configuration:
+//! - sessions that haven't been used for longer than the TTL are unloaded if
+//!   they are stored on disk, or deleted otherwise
+//! - when the entries of all sessions exceed the memory budget, the least
+//!   recently used sessions are unloaded or deleted the same way
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::handlers::sessions::persist_session;
+use crate::models::{AppState, Session, SessionStatus};
+
+// Time between two runs of the eviction policies
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+// Enforce the eviction policies until the server stops
+pub async fn run(state: Arc<AppState>) {
+    log::info!(
+        "Evicting sessions idle for {:?}, memory budget of {:?} bytes",
+        state.config.session_ttl,
+        state.config.memory_budget
+    );
+
+    let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Storing sessions before unloading them hits the disk
+        let task_state = state.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || evict_sessions(&task_state)).await {
+            log::error!("Session eviction task failed: {}", e);
+        }
+    }
+}
+
+// Whether a session can be evicted, sessions still receiving entries are left alone
+fn is_evictable(status: &SessionStatus) -> bool {
+    match status {
+        SessionStatus::Ready { .. }
+        | SessionStatus::Unloaded { .. }
+        | SessionStatus::Failed { .. }
+        | SessionStatus::Evicted { .. } => true,
+        SessionStatus::Queued | SessionStatus::Parsing { .. } | SessionStatus::Live { .. } => false,
+    }
+}
+
+fn evict_sessions(state: &AppState) {
+    let last_access = state.last_access.lock().unwrap().clone();
+    let evictable: HashMap<String, SessionStatus> = state
+        .sessions
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, status)| is_evictable(status))
+        .map(|(session_id, status)| (session_id.clone(), status.clone()))
+        .collect();
+
+    if let Some(ttl) = state.config.session_ttl {
+        evict_idle_sessions(state, &evictable, &last_access, ttl);
+    }
+    if let Some(budget) = state.config.memory_budget {
+        enforce_memory_budget(state, &evictable, &last_access, budget as usize);
+    }
+}
+
+// Free the sessions that haven't been used for longer than the TTL
+fn evict_idle_sessions(
+    state: &AppState,
+    evictable: &HashMap<String, SessionStatus>,
+    last_access: &HashMap<String, Instant>,
+    ttl: Duration,
+) {
+    for (session_id, status) in evictable {
+        let idle = last_access
+            .get(session_id)
+            .map_or(Duration::MAX, |time| time.elapsed());
+        if idle <= ttl {
+            continue;
+        }
+
+        match status {
+            // Already only on disk, stored sessions are kept until they are deleted
+            SessionStatus::Unloaded { .. } => {}
+            // Keep evicted sessions known until they expire in turn, so clients can tell why
+            SessionStatus::Evicted { .. } | SessionStatus::Failed { .. } => {
+                state.remove_session(session_id);
+                log::info!("Forgot session {} after {:?}", session_id, idle);
+            }
+            _ if unload_session(state, session_id, status) => {
+                log::info!("Unloaded session {}, idle for {:?}", session_id, idle);
+            }
+            _ if state.store.is_none() => {
+                let reason = format!("Not used for {} minutes", idle.as_secs() / 60);
+                if evict_session(state, session_id, status, reason) {
+                    log::info!("Evicted session {}, idle for {:?}", session_id, idle);
+                }
+            }
+            // Changed since it was found idle, or couldn't be stored
+            _ => {}
+        }
+    }
+}
+
+// Drop the entries of a session once they are stored, keeping the session as
+// unloaded. Returns whether the session was unloaded.
+fn unload_session(state: &AppState, session_id: &str, status: &SessionStatus) -> bool {
+    if !persist_session(state, session_id) {
+        return false;
+    }
+
+    // Held so that the stored copy stays the one of this version
+    let stored_version = state.stored_version(session_id);
+    let stored_version = stored_version.lock().unwrap();
+    let session = match *stored_version {
+        // Not a use of the session, it still expires after the TTL since its last use
+        Some(version) => take_unchanged(state, session_id, status, Some(version), |session| {
+            SessionStatus::Unloaded {
+                entries: session.entries.len(),
+            }
+        }),
+        None => None,
+    };
+    session.is_some()
+}
+
+// Delete a session that isn't stored, reporting it as evicted for the given reason.
+// Returns whether the session was deleted.
+fn evict_session(
+    state: &AppState,
+    session_id: &str,
+    status: &SessionStatus,
+    reason: String,
+) -> bool {
+    let session = take_unchanged(state, session_id, status, None, |_| {
+        SessionStatus::Evicted { reason }
+    });
+    if session.is_none() {
+        return false;
+    }
+    state.live_subscribers.lock().unwrap().remove(session_id);
+    // Evicted sessions expire after the TTL in turn
+    state.touch(session_id);
+    true
+}
+
+// Remove a session from memory and replace its status, unless its status changed
+// since `status` was read or its version isn't `version`. The session is returned
+// so that its entries are freed once the sessions are unlocked.
+fn take_unchanged(
+    state: &AppState,
+    session_id: &str,
+    status: &SessionStatus,
+    version: Option<u64>,
+    replacement: impl FnOnce(&Session) -> SessionStatus,
+) -> Option<Session> {
+    let mut logs = state.parsed_logs.write().unwrap();
+    let mut sessions = state.sessions.write().unwrap();
+    let unchanged = logs
+        .get(session_id)
+        .is_some_and(|session| version.is_none_or(|version| session.version() == version))
+        && sessions.get(session_id) == Some(status);
+    if !unchanged {
+        return None;
+    }
+
+    let session = logs.remove(session_id).unwrap();
+    sessions.insert(session_id.to_string(), replacement(&session));
+    Some(session)
+}
+
+// Free the least recently used sessions until the entries of all sessions fit the budget
+fn enforce_memory_budget(
+    state: &AppState,
+    evictable: &HashMap<String, SessionStatus>,
+    last_access: &HashMap<String, Instant>,
+    budget: usize,
+) {
+    let (mut total, mut candidates) = {
+        let logs = state.parsed_logs.read().unwrap();
+        let total: usize = logs.values().map(|session| session.memory_size()).sum();
+        let candidates: Vec<(String, usize)> = logs
+            .iter()
+            .filter(|(session_id, _)| evictable.contains_key(*session_id))
+            .map(|(session_id, session)| (session_id.clone(), session.memory_size()))
+            .collect();
+        (total, candidates)
+    };
+    if total <= budget {
+        return;
+    }
+
+    // Least recently used first
+    candidates.sort_by_key(|(session_id, _)| last_access.get(session_id).copied());
+    for (session_id, size) in candidates {
+        if total <= budget {
+            break;
+        }
+
+        if unload_session(state, &session_id, &evictable[&session_id]) {
+            log::info!(
+                "Unloaded session {} ({} bytes) to stay within the memory budget",
+                session_id,
+                size
+            );
+        } else if state.store.is_none()
+            && evict_session(
+                state,
+                &session_id,
+                &evictable[&session_id],
+                "Memory budget exceeded".to_string(),
+            )
+        {
+            log::info!(
+                "Evicted session {} ({} bytes) to stay within the memory budget",
+                session_id,
+                size
+            );
+        } else {
+            // Changed since the candidates were picked, or couldn't be stored
+            continue;
+        }
+        total -= size;
+    }
+
+    if total > budget {
+        log::warn!(
+            "Sessions use about {} bytes, over the memory budget of {} bytes, but none can be evicted",
+            total,
+            budget
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, RwLock};
+
+    use gstreamer::DebugLevel;
+
+    use super::*;
+    use crate::config::{Config, SessionBackend};
+    use crate::models::SessionEntry;
+
+    // State keeping the given sessions in memory only, accessed in the given order
+    fn state_with(sessions: &[(&str, SessionStatus)], config: Config) -> AppState {
+        let state = AppState {
+            config,
+            parsed_logs: RwLock::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
+            live_subscribers: Mutex::new(HashMap::new()),
+            temp_dir: tempfile::tempdir().unwrap(),
+            store: None,
+            stored_versions: Mutex::new(HashMap::new()),
+            last_access: Mutex::new(HashMap::new()),
+            mapped_dir: None,
+        };
+        for (session_id, status) in sessions {
+            let mut session = Session::new();
+            let entries = (0..10)
+                .map(|ts| {
+                    SessionEntry::for_test(&mut session.strings, ts, "a", DebugLevel::Info, "")
+                })
+                .collect();
+            session.insert_entries(entries).unwrap();
+            state
+                .parsed_logs
+                .write()
+                .unwrap()
+                .insert(session_id.to_string(), session);
+            state.set_status(session_id, status.clone());
+        }
+        state
+    }
+
+    fn config() -> Config {
+        Config {
+            max_upload_size: 0,
+            tail_dir: None,
+            fallback_encoding: None,
+            data_dir: None,
+            session_ttl: None,
+            memory_budget: None,
+            session_backend: SessionBackend::Memory,
+            mapped_dir: None,
+            sqlite_path: None,
+        }
+    }
+
+    fn status(state: &AppState, session_id: &str) -> Option<SessionStatus> {
+        state.sessions.read().unwrap().get(session_id).cloned()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_sessions_over_the_budget() {
+        let ready = SessionStatus::Ready { entries: 10 };
+        let live = SessionStatus::Live { bytes_read: 0 };
+        let mut state = state_with(
+            &[
+                ("live", live.clone()),
+                ("old", ready.clone()),
+                ("new", ready.clone()),
+            ],
+            config(),
+        );
+        let size = state.parsed_logs.read().unwrap()["new"].memory_size();
+        // Room for two of the three sessions
+        state.config.memory_budget = Some(2 * size as u64);
+        evict_sessions(&state);
+
+        // The live session is the least recently used, but still receives entries
+        assert_eq!(status(&state, "live"), Some(live));
+        assert!(matches!(
+            status(&state, "old"),
+            Some(SessionStatus::Evicted { .. })
+        ));
+        assert_eq!(status(&state, "new"), Some(ready));
+        let logs = state.parsed_logs.read().unwrap();
+        assert!(logs.contains_key("live") && logs.contains_key("new"));
+        assert!(!logs.contains_key("old"));
+    }
+
+    #[test]
+    fn evicts_then_forgets_idle_sessions() {
+        let ready = SessionStatus::Ready { entries: 10 };
+        let mut config = config();
+        config.session_ttl = Some(Duration::ZERO);
+        let state = state_with(&[("idle", ready)], config);
+
+        evict_sessions(&state);
+        assert!(matches!(
+            status(&state, "idle"),
+            Some(SessionStatus::Evicted { .. })
+        ));
+        assert!(state.parsed_logs.read().unwrap().is_empty());
+
+        evict_sessions(&state);
+        assert_eq!(status(&state, "idle"), None);
+    }
+}
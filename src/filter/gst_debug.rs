@@ -0,0 +1,313 @@
+//! Thresholds in the syntax of `GST_DEBUG`, for the `gst_debug` filter parameter
+//!
+//! `*:WARN,v4l2*:DEBUG,GST_PADS:LOG` is a comma-separated list of category
+//! patterns and levels, applied the way GStreamer applies them when logging:
+//!
+//! - patterns match whole category names, with `*` and `?` wildcards
+//! - levels are names or numbers, a threshold showing its level and the more
+//!   severe ones
+//! - the last pattern matching a category sets its threshold
+//! - a level alone sets the threshold of the categories no pattern matches,
+//!   which is `NONE` otherwise
+//!
+//! Unlike GStreamer, which ignores them, invalid levels are reported.
+
+use std::collections::{BTreeMap, HashMap};
+
+use gstreamer::DebugLevel;
+use regex::Regex;
+
+use super::language::ParseError;
+use super::{glob_regex, parse_level};
+use crate::models::{EntryFields, Interner, Symbol};
+use crate::storage::{level_value, symbol_value};
+
+// Thresholds of categories, as levels numbered the way GStreamer numbers them
+#[derive(Debug)]
+pub struct Thresholds {
+    // Threshold of the categories no pattern matches
+    default: u64,
+    patterns: Vec<(Regex, u64)>,
+}
+
+impl Thresholds {
+    pub fn parse(spec: &str) -> Result<Self, ParseError> {
+        let mut thresholds = Thresholds {
+            default: 0,
+            patterns: Vec::new(),
+        };
+
+        let mut start = 0;
+        for item in spec.split(',') {
+            let position = start;
+            start += item.len() + 1;
+            if item.trim().is_empty() {
+                continue;
+            }
+
+            match item.split_once(':') {
+                Some((pattern, level)) => {
+                    let threshold = parse_threshold(level, position + pattern.len() + 1)?;
+                    let regex =
+                        Regex::new(&glob_regex(pattern.trim())).expect("glob patterns are escaped");
+                    thresholds.patterns.push((regex, threshold));
+                }
+                None => thresholds.default = parse_threshold(item, position)?,
+            }
+        }
+
+        Ok(thresholds)
+    }
+
+    // Threshold of a category
+    fn threshold(&self, category: &str) -> u64 {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|(regex, _)| regex.is_match(category))
+            .map_or(self.default, |(_, threshold)| *threshold)
+    }
+
+    // Resolve the threshold of the categories of a session
+    pub fn bind(&self, strings: &Interner) -> BoundThresholds {
+        let default = max_level_value(self.default);
+        BoundThresholds {
+            default,
+            // Categories are compared without the padding of aligned logs
+            categories: strings
+                .iter()
+                .map(|(symbol, string)| (symbol, max_level_value(self.threshold(string.trim()))))
+                .filter(|(_, max_level)| *max_level != default)
+                .collect(),
+        }
+    }
+}
+
+// A level name, or a number that may be any number like for GStreamer
+fn parse_threshold(level: &str, position: usize) -> Result<u64, ParseError> {
+    let name = level.trim();
+    if !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(name.parse().unwrap_or(u64::MAX));
+    }
+    parse_level(name)
+        .map(|level| match level {
+            // The only level whose number isn't its position in `LEVELS`
+            DebugLevel::Memdump => 9,
+            level => level_value(level) as u64,
+        })
+        .ok_or_else(|| {
+            ParseError::new(
+                position + level.len() - level.trim_start().len(),
+                format!(
+                    "unknown level `{}`, expected a number or a name such as WARNING",
+                    name
+                ),
+            )
+        })
+}
+
+// Position in `LEVELS` of the most verbose level shown with a threshold. 8 isn't a
+// level of GStreamer, it shows the levels up to TRACE.
+fn max_level_value(threshold: u64) -> u32 {
+    match threshold {
+        0..=7 => threshold as u32,
+        8 => 7,
+        _ => 8,
+    }
+}
+
+// Thresholds bound to a session, as the most verbose level shown by each category
+pub struct BoundThresholds {
+    default: u32,
+    // Strings of the session whose threshold isn't the default one
+    categories: HashMap<Symbol, u32>,
+}
+
+impl BoundThresholds {
+    pub fn matches(&self, entry: &impl EntryFields) -> bool {
+        let max_level = self
+            .categories
+            .get(&entry.category())
+            .copied()
+            .unwrap_or(self.default);
+        level_value(entry.level()) <= max_level
+    }
+
+    // Condition on the entries of SQLite sessions
+    pub fn sql(&self) -> String {
+        let mut by_threshold: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+        for (symbol, max_level) in &self.categories {
+            by_threshold
+                .entry(*max_level)
+                .or_default()
+                .push(symbol_value(*symbol).to_string());
+        }
+        if by_threshold.is_empty() {
+            return format!("level <= {}", self.default);
+        }
+
+        let cases: Vec<String> = by_threshold
+            .into_iter()
+            .map(|(max_level, symbols)| {
+                format!(
+                    "WHEN category IN ({}) THEN {}",
+                    symbols.join(", "),
+                    max_level
+                )
+            })
+            .collect();
+        format!(
+            "level <= CASE {} ELSE {} END",
+            cases.join(" "),
+            self.default
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{SessionEntry, LEVELS};
+
+    const CATEGORIES: [&str; 6] = [
+        "GST_PADS",
+        "GST_PADS_EXTRA",
+        "v4l2src",
+        "v4l2sink",
+        "v4l2 ",
+        "queue2",
+    ];
+
+    fn threshold(spec: &str, category: &str) -> u64 {
+        Thresholds::parse(spec).unwrap().threshold(category)
+    }
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(threshold("", "queue2"), 0);
+        assert_eq!(threshold("WARN", "queue2"), 2);
+        assert_eq!(threshold("*:warning", "queue2"), 2);
+        assert_eq!(threshold(" queue2 : Log ", "queue2"), 6);
+        assert_eq!(threshold("*:MEMDUMP", "queue2"), 9);
+        assert_eq!(threshold(",,*:INFO,", "queue2"), 4);
+    }
+
+    #[test]
+    fn parses_numeric_levels() {
+        assert_eq!(threshold("*:0", "queue2"), 0);
+        assert_eq!(threshold("*:5", "queue2"), 5);
+        assert_eq!(threshold("8", "queue2"), 8);
+        assert_eq!(threshold("*:9", "queue2"), 9);
+        // Any number is a threshold, like for GStreamer
+        assert_eq!(threshold("*:42", "queue2"), 42);
+        assert_eq!(threshold("*:99999999999999999999999", "queue2"), u64::MAX);
+
+        assert_eq!(max_level_value(0), 0);
+        assert_eq!(max_level_value(7), 7);
+        assert_eq!(max_level_value(8), 7);
+        assert_eq!(max_level_value(9), 8);
+        assert_eq!(max_level_value(u64::MAX), 8);
+    }
+
+    #[test]
+    fn reports_unknown_levels() {
+        let error = Thresholds::parse("*:WARN,queue2: LOUD").unwrap_err();
+        assert_eq!(error.position, 15);
+        assert_eq!(
+            error.message,
+            "unknown level `LOUD`, expected a number or a name such as WARNING"
+        );
+        assert_eq!(
+            Thresholds::parse("*:WARN,  verbose").unwrap_err().position,
+            9
+        );
+        assert_eq!(Thresholds::parse("queue2:").unwrap_err().position, 7);
+        assert_eq!(Thresholds::parse("*:-1").unwrap_err().position, 2);
+    }
+
+    #[test]
+    fn last_match_wins() {
+        assert_eq!(threshold("v4l2*:DEBUG,v4l2src:ERROR", "v4l2src"), 1);
+        assert_eq!(threshold("v4l2*:DEBUG,v4l2src:ERROR", "v4l2sink"), 5);
+        assert_eq!(threshold("v4l2src:ERROR,v4l2*:DEBUG", "v4l2src"), 5);
+        assert_eq!(threshold("*:LOG,GST_PADS:NONE", "GST_PADS"), 0);
+        // A level alone only applies where no pattern matches, wherever it is
+        assert_eq!(threshold("GST_PADS:LOG,WARN", "GST_PADS"), 6);
+        assert_eq!(threshold("GST_PADS:LOG,WARN", "queue2"), 2);
+        assert_eq!(threshold("WARN,ERROR", "queue2"), 1);
+    }
+
+    #[test]
+    fn wildcards_match_whole_names() {
+        assert_eq!(threshold("GST_PADS:LOG", "GST_PADS_EXTRA"), 0);
+        assert_eq!(threshold("GST_PADS*:LOG", "GST_PADS_EXTRA"), 6);
+        assert_eq!(threshold("v4l2?rc:LOG", "v4l2src"), 6);
+        assert_eq!(threshold("v4l2?rc:LOG", "v4l2srcs"), 0);
+        assert_eq!(threshold("*2:LOG", "queue2"), 6);
+        assert_eq!(threshold("*2:LOG", "v4l2src"), 0);
+        // Only `*` and `?` are special
+        assert_eq!(threshold("queue.:LOG", "queue2"), 0);
+        assert_eq!(threshold("queue.:LOG", "queue."), 6);
+    }
+
+    #[test]
+    fn bound_thresholds_ignore_padding() {
+        let mut strings = Interner::default();
+        let padded = SessionEntry::for_test(&mut strings, 0, "v4l2      ", DebugLevel::Debug, "");
+        let thresholds = Thresholds::parse("v4l2:DEBUG").unwrap().bind(&strings);
+        assert!(thresholds.matches(&padded));
+    }
+
+    #[test]
+    fn sql_agrees_with_matches() {
+        let mut strings = Interner::default();
+        let entries: Vec<SessionEntry> = CATEGORIES
+            .iter()
+            .flat_map(|category| LEVELS.iter().map(move |level| (*category, *level)))
+            .map(|(category, level)| SessionEntry::for_test(&mut strings, 0, category, level, ""))
+            .collect();
+
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection
+            .execute(
+                "CREATE TABLE entries (id INTEGER, category INTEGER, level INTEGER)",
+                [],
+            )
+            .unwrap();
+        for (id, entry) in entries.iter().enumerate() {
+            connection
+                .execute(
+                    "INSERT INTO entries VALUES (?1, ?2, ?3)",
+                    (id, symbol_value(entry.category), level_value(entry.level)),
+                )
+                .unwrap();
+        }
+
+        for spec in [
+            "",
+            "WARN",
+            "*:9",
+            "*:42",
+            "*:WARN,v4l2*:DEBUG,GST_PADS:LOG",
+            "v4l2*:DEBUG,v4l2src:ERROR,INFO",
+            "GST_PADS*:TRACE,queue?:8,v4l2:FIXME",
+        ] {
+            let thresholds = Thresholds::parse(spec).unwrap().bind(&strings);
+            let expected: Vec<usize> = (0..entries.len())
+                .filter(|id| thresholds.matches(&entries[*id]))
+                .collect();
+
+            let query = format!(
+                "SELECT id FROM entries WHERE {} ORDER BY id",
+                thresholds.sql()
+            );
+            let mut statement = connection.prepare(&query).unwrap();
+            let found: Vec<usize> = statement
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap();
+            assert_eq!(found, expected, "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,672 @@
+//! Query language of the `q` filter parameter
+//!
+//! A query combines conditions on the fields of entries with `and`, `or`, `not`
+//! and parentheses, `not` binding tighter than `and`, itself tighter than `or`:
+//!
+//! ```text
+//! level>=WARN and (category:v4l2* or object:~"queue\d+") and not message:"dropping"
+//! ```
+//!
+//! - `field:value` matches the whole value, with `*` and `?` as wildcards.
+//!   Messages match when they contain the value.
+//! - `field:~regex` matches when the regex matches part of the value
+//! - `<`, `<=`, `>` and `>=` compare levels by severity, `level>=WARN` selecting
+//!   warnings and errors, and numbers by value
+//!
+//! Values are a word, or a string in double quotes with `\"` and `\\` escapes.
+//! Keywords and level names are case-insensitive.
+
+use std::fmt;
+
+use gstreamer::DebugLevel;
+
+use super::parse_level;
+
+// Parentheses deeper than this are refused instead of overflowing the stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Condition(Condition),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub field: Field,
+    pub operator: Operator,
+    pub value: Value,
+    // Byte offset of the value in the query, to report values that turn out to be invalid
+    pub position: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Level,
+    Category,
+    Thread,
+    Object,
+    Function,
+    File,
+    Message,
+    Source,
+    Unit,
+    Pid,
+    JournalPid,
+    // Line in the code that logged the entry
+    Line,
+    // Line of the entry in its source file
+    LineNumber,
+}
+
+// Name of each field in queries
+const FIELDS: [(&str, Field); 13] = [
+    ("level", Field::Level),
+    ("category", Field::Category),
+    ("thread", Field::Thread),
+    ("object", Field::Object),
+    ("function", Field::Function),
+    ("file", Field::File),
+    ("message", Field::Message),
+    ("source", Field::Source),
+    ("unit", Field::Unit),
+    ("pid", Field::Pid),
+    ("journal_pid", Field::JournalPid),
+    ("line", Field::Line),
+    ("line_number", Field::LineNumber),
+];
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        FIELDS
+            .iter()
+            .find(|(field_name, _)| field_name.eq_ignore_ascii_case(name))
+            .map(|(_, field)| *field)
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(
+            self,
+            Field::Pid | Field::JournalPid | Field::Line | Field::LineNumber
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    // `:`
+    Match,
+    // `:~`
+    Regex,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Operator {
+    fn symbol(self) -> &'static str {
+        match self {
+            Operator::Match => ":",
+            Operator::Regex => ":~",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+        }
+    }
+
+    // Whether a value compares to another one, with the operator between them
+    pub fn compare<T: Ord>(self, value: T, other: T) -> bool {
+        match self {
+            Operator::Match => value == other,
+            Operator::Less => value < other,
+            Operator::LessOrEqual => value <= other,
+            Operator::Greater => value > other,
+            Operator::GreaterOrEqual => value >= other,
+            Operator::Regex => unreachable!("regexes only apply to text"),
+        }
+    }
+}
+
+// Values are checked against their field while parsing
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Number(u64),
+    Level(DebugLevel),
+}
+
+// A query that couldn't be parsed, or one of its values that is invalid
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    // Byte offset in the query
+    pub position: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(position: usize, message: impl Into<String>) -> Self {
+        ParseError {
+            position,
+            message: message.into(),
+        }
+    }
+
+    // The error along with the input, pointing at the position of the error
+    pub fn describe(&self, name: &str, input: &str) -> String {
+        let column = input[..self.position].chars().count();
+        format!(
+            "Invalid {} at column {}: {}\n{}\n{}^",
+            name,
+            column + 1,
+            self.message,
+            input,
+            " ".repeat(column)
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.position)
+    }
+}
+
+// Parse a query into the expression it stands for
+pub fn parse(query: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser {
+        query,
+        position: 0,
+        depth: 0,
+    };
+    let expr = parser.parse_or()?;
+    parser.skip_whitespace();
+    match parser.peek() {
+        None => Ok(expr),
+        Some(')') => Err(parser.error("unmatched `)`")),
+        Some(_) => Err(parser.error("expected `and` or `or` between conditions")),
+    }
+}
+
+struct Parser<'a> {
+    query: &'a str,
+    // Byte offset of the next character
+    position: usize,
+    // Number of parentheses and `not` around the current position
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.query[self.position..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError::new(self.position, message)
+    }
+
+    // Consume a keyword if it comes next, as a whole word
+    fn keyword(&mut self, keyword: &str) -> bool {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let matches = rest.len() >= keyword.len()
+            && rest.is_char_boundary(keyword.len())
+            && rest[..keyword.len()].eq_ignore_ascii_case(keyword)
+            && rest[keyword.len()..]
+                .chars()
+                .next()
+                .is_none_or(|c| c.is_whitespace() || c == '(' || c == ')');
+        if matches {
+            self.position += keyword.len();
+        }
+        matches
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_and()?;
+        while self.keyword("or") {
+            let right = self.parse_and()?;
+            expr = Expr::Or(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_unary()?;
+        while self.keyword("and") {
+            let right = self.parse_unary()?;
+            expr = Expr::And(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error("query is nested too deeply"));
+        }
+
+        let expr = if self.keyword("not") {
+            Expr::Not(Box::new(self.parse_unary()?))
+        } else if self.peek() == Some('(') {
+            let open = self.position;
+            self.bump();
+            let expr = self.parse_or()?;
+            self.skip_whitespace();
+            match self.peek() {
+                Some(')') => {
+                    self.bump();
+                }
+                Some(_) => return Err(self.error("expected `and`, `or` or `)`")),
+                None => return Err(ParseError::new(open, "unclosed `(`")),
+            }
+            expr
+        } else {
+            Expr::Condition(self.parse_condition()?)
+        };
+
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn parse_condition(&mut self) -> Result<Condition, ParseError> {
+        self.skip_whitespace();
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            self.bump();
+        }
+        let name = &self.query[start..self.position];
+        if name.is_empty() {
+            return Err(match self.peek() {
+                None => self.error("expected a condition, the query ends here"),
+                Some(c) => self.error(format!("expected a condition, found `{}`", c)),
+            });
+        }
+        let field = Field::from_name(name).ok_or_else(|| {
+            let names: Vec<&str> = FIELDS.iter().map(|(name, _)| *name).collect();
+            ParseError::new(
+                start,
+                format!(
+                    "unknown field `{}`, expected one of {}",
+                    name,
+                    names.join(", ")
+                ),
+            )
+        })?;
+
+        self.skip_whitespace();
+        let operator_position = self.position;
+        let operator = self.parse_operator().ok_or_else(|| {
+            self.error(format!(
+                "expected an operator after `{}`: `:`, `:~`, `<`, `<=`, `>` or `>=`",
+                name
+            ))
+        })?;
+
+        self.skip_whitespace();
+        let position = self.position;
+        let text = self.parse_value()?;
+
+        let value =
+            match (field, operator) {
+                (Field::Level, Operator::Regex) => {
+                    return Err(ParseError::new(
+                        operator_position,
+                        "`:~` only applies to text fields",
+                    ));
+                }
+                (Field::Level, _) => Value::Level(parse_level(&text).ok_or_else(|| {
+                    ParseError::new(position, format!("unknown level `{}`", text))
+                })?),
+                (_, Operator::Regex) if field.is_numeric() => {
+                    return Err(ParseError::new(
+                        operator_position,
+                        "`:~` only applies to text fields",
+                    ));
+                }
+                _ if field.is_numeric() => Value::Number(text.parse().map_err(|_| {
+                    ParseError::new(position, format!("expected a number, found `{}`", text))
+                })?),
+                (_, Operator::Match | Operator::Regex) => Value::Text(text),
+                _ => {
+                    return Err(ParseError::new(
+                        operator_position,
+                        format!("`{}` only applies to levels and numbers", operator.symbol()),
+                    ));
+                }
+            };
+
+        Ok(Condition {
+            field,
+            operator,
+            value,
+            position,
+        })
+    }
+
+    fn parse_operator(&mut self) -> Option<Operator> {
+        // Longest operators first
+        const OPERATORS: [(&str, Operator); 6] = [
+            (":~", Operator::Regex),
+            ("<=", Operator::LessOrEqual),
+            (">=", Operator::GreaterOrEqual),
+            (":", Operator::Match),
+            ("<", Operator::Less),
+            (">", Operator::Greater),
+        ];
+        let (symbol, operator) = OPERATORS
+            .into_iter()
+            .find(|(symbol, _)| self.rest().starts_with(symbol))?;
+        self.position += symbol.len();
+        Some(operator)
+    }
+
+    // A word, up to whitespace or a parenthesis, or a quoted string
+    fn parse_value(&mut self) -> Result<String, ParseError> {
+        let start = self.position;
+        if self.peek() != Some('"') {
+            while self
+                .peek()
+                .is_some_and(|c| !c.is_whitespace() && c != '(' && c != ')')
+            {
+                self.bump();
+            }
+            if self.position == start {
+                return Err(self.error("expected a value"));
+            }
+            return Ok(self.query[start..self.position].to_string());
+        }
+
+        self.bump();
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.bump() {
+                    Some(c @ ('"' | '\\')) => value.push(c),
+                    // Other escapes are kept as they are, for regexes
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => break,
+                },
+                Some(c) => value.push(c),
+                None => break,
+            }
+        }
+        Err(ParseError::new(start, "unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The expression with its grouping spelled out, conditions as `field:value`
+    fn show(expr: &Expr) -> String {
+        match expr {
+            Expr::And(left, right) => format!("(and {} {})", show(left), show(right)),
+            Expr::Or(left, right) => format!("(or {} {})", show(left), show(right)),
+            Expr::Not(expr) => format!("(not {})", show(expr)),
+            Expr::Condition(condition) => {
+                let value = match condition.value {
+                    Value::Text(ref text) => text.clone(),
+                    Value::Number(number) => number.to_string(),
+                    Value::Level(level) => format!("{:?}", level),
+                };
+                let name = FIELDS
+                    .iter()
+                    .find(|(_, field)| *field == condition.field)
+                    .unwrap()
+                    .0;
+                format!("{}{}{}", name, condition.operator.symbol(), value)
+            }
+        }
+    }
+
+    fn parsed(query: &str) -> String {
+        show(&parse(query).unwrap())
+    }
+
+    fn condition(query: &str) -> Condition {
+        match parse(query).unwrap() {
+            Expr::Condition(condition) => condition,
+            expr => panic!("{} isn't a condition", show(&expr)),
+        }
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_tighter_than_or() {
+        assert_eq!(
+            parsed("category:a or thread:b and not object:c"),
+            "(or category:a (and thread:b (not object:c)))"
+        );
+        assert_eq!(
+            parsed("not category:a and thread:b or object:c"),
+            "(or (and (not category:a) thread:b) object:c)"
+        );
+        assert_eq!(
+            parsed("category:a and thread:b and object:c"),
+            "(and (and category:a thread:b) object:c)"
+        );
+    }
+
+    #[test]
+    fn parentheses_group() {
+        assert_eq!(
+            parsed("(category:a or thread:b) and object:c"),
+            "(and (or category:a thread:b) object:c)"
+        );
+        assert_eq!(
+            parsed("not (category:a or thread:b)"),
+            "(not (or category:a thread:b))"
+        );
+        assert_eq!(parsed("((category:a))"), "category:a");
+    }
+
+    #[test]
+    fn keywords_and_fields_ignore_case() {
+        assert_eq!(
+            parsed("CATEGORY:a AND Not thread:b OR object:c"),
+            "(or (and category:a (not thread:b)) object:c)"
+        );
+    }
+
+    #[test]
+    fn keywords_are_whole_words() {
+        assert_eq!(
+            parsed("category:android and thread:notify"),
+            "(and category:android thread:notify)"
+        );
+        assert_eq!(
+            parse("category:a orb:x").unwrap_err(),
+            ParseError::new(11, "expected `and` or `or` between conditions")
+        );
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(
+            condition(r#"message:"a (quoted) value""#).value,
+            Value::Text("a (quoted) value".to_string())
+        );
+        assert_eq!(
+            condition(r#"message:"say \"hi\" \\ bye""#).value,
+            Value::Text(r#"say "hi" \ bye"#.to_string())
+        );
+        // Other escapes are left for regexes
+        assert_eq!(
+            condition(r#"object:~"queue\d+""#).value,
+            Value::Text(r"queue\d+".to_string())
+        );
+        assert_eq!(condition(r#"message:"""#).value, Value::Text(String::new()));
+    }
+
+    #[test]
+    fn unterminated_strings() {
+        assert_eq!(
+            parse(r#"message:"open"#).unwrap_err(),
+            ParseError::new(8, "unterminated string")
+        );
+        assert_eq!(
+            parse(r#"message:"escaped \"#).unwrap_err(),
+            ParseError::new(8, "unterminated string")
+        );
+    }
+
+    #[test]
+    fn values_and_operators() {
+        let level = condition("level>=warn");
+        assert_eq!(level.operator, Operator::GreaterOrEqual);
+        assert_eq!(level.value, Value::Level(DebugLevel::Warning));
+        assert_eq!(level.position, 7);
+
+        let pid = condition("pid < 42");
+        assert_eq!(pid.operator, Operator::Less);
+        assert_eq!(pid.value, Value::Number(42));
+        assert_eq!(pid.position, 6);
+
+        assert_eq!(condition("thread:0x7d*").operator, Operator::Match);
+        assert_eq!(condition("file :~ \\.c$").operator, Operator::Regex);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}category:a{}", "(".repeat(depth), ")".repeat(depth));
+        // The condition itself counts as one level
+        assert!(parse(&nested(MAX_DEPTH - 1)).is_ok());
+        let error = parse(&nested(MAX_DEPTH)).unwrap_err();
+        assert_eq!(error.message, "query is nested too deeply");
+        assert_eq!(error.position, MAX_DEPTH);
+
+        let negated = format!("{}category:a", "not ".repeat(MAX_DEPTH));
+        assert_eq!(
+            parse(&negated).unwrap_err().message,
+            "query is nested too deeply"
+        );
+        // Depth is about nesting, not the number of conditions
+        let long = vec!["(category:a)"; 2 * MAX_DEPTH].join(" or ");
+        assert!(parse(&long).is_ok());
+    }
+
+    #[test]
+    fn operators_must_fit_the_field() {
+        assert_eq!(
+            parse("level:~warn").unwrap_err(),
+            ParseError::new(5, "`:~` only applies to text fields")
+        );
+        assert_eq!(
+            parse("pid:~1").unwrap_err(),
+            ParseError::new(3, "`:~` only applies to text fields")
+        );
+        assert_eq!(
+            parse("message>3").unwrap_err(),
+            ParseError::new(7, "`>` only applies to levels and numbers")
+        );
+        assert_eq!(
+            parse("category <= a").unwrap_err(),
+            ParseError::new(9, "`<=` only applies to levels and numbers")
+        );
+    }
+
+    #[test]
+    fn values_must_fit_the_field() {
+        assert_eq!(
+            parse("pid:abc").unwrap_err(),
+            ParseError::new(4, "expected a number, found `abc`")
+        );
+        assert_eq!(
+            parse("line_number>-1").unwrap_err(),
+            ParseError::new(12, "expected a number, found `-1`")
+        );
+        assert_eq!(
+            parse("level:LOUD").unwrap_err(),
+            ParseError::new(6, "unknown level `LOUD`")
+        );
+    }
+
+    #[test]
+    fn malformed_queries() {
+        assert_eq!(
+            parse("").unwrap_err(),
+            ParseError::new(0, "expected a condition, the query ends here")
+        );
+        assert_eq!(
+            parse("category:a and").unwrap_err(),
+            ParseError::new(14, "expected a condition, the query ends here")
+        );
+        assert_eq!(
+            parse("category:a and )").unwrap_err(),
+            ParseError::new(15, "expected a condition, found `)`")
+        );
+        assert_eq!(
+            parse("(category:a").unwrap_err(),
+            ParseError::new(0, "unclosed `(`")
+        );
+        assert_eq!(
+            parse("category:a)").unwrap_err(),
+            ParseError::new(10, "unmatched `)`")
+        );
+        assert_eq!(
+            parse("category:").unwrap_err(),
+            ParseError::new(9, "expected a value")
+        );
+        assert_eq!(parse("category a").unwrap_err().position, 9);
+        assert!(parse("categry:a")
+            .unwrap_err()
+            .message
+            .starts_with("unknown field `categry`, expected one of level, category"));
+    }
+
+    #[test]
+    fn describe_points_at_the_column() {
+        let query = "level>=LOUD";
+        let error = parse(query).unwrap_err();
+        assert_eq!(
+            error.describe("query", query),
+            "Invalid query at column 8: unknown level `LOUD`\nlevel>=LOUD\n       ^"
+        );
+
+        // Columns count characters, not bytes
+        let query = r#"message:"café" nope"#;
+        let error = parse(query).unwrap_err();
+        assert_eq!(error.position, 16);
+        assert_eq!(
+            error.describe("query", query),
+            format!(
+                "Invalid query at column 16: expected `and` or `or` between conditions\n{}\n{}^",
+                query,
+                " ".repeat(15)
+            )
+        );
+
+        let error = parse("").unwrap_err();
+        assert_eq!(
+            error.describe("query", ""),
+            "Invalid query at column 1: expected a condition, the query ends here\n\n^"
+        );
+    }
+}
@@ -0,0 +1,946 @@
+//! Selection of the entries matching a filter, shared by every endpoint
+//!
+//! The filter of a request is compiled once into a `FilterPlan`: regexes are
+//! compiled and the time range is converted to nanoseconds. The plan is then
+//! bound to a session, which resolves the filtered values to the symbols of the
+//! session, and selects entries the way the backend of the session allows: as
+//! a query for SQLite sessions, through the indexes of mapped sessions, or by
+//! checking every entry of sessions in memory.
+//!
+//! Besides its fields, a filter can hold a query in the language of the
+//! `language` module, and thresholds in the syntax of `GST_DEBUG`, combined with
+//! the fields like any other condition.
+
+mod gst_debug;
+mod language;
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io;
+use std::ops::Range;
+
+use axum::http::StatusCode;
+use gstreamer::DebugLevel;
+use itertools::{EitherOrBoth, Itertools};
+use regex::Regex;
+use rusqlite::types::Value;
+
+use crate::models::{
+    level_from_name, writing_error, ApiError, Entries, EntryFields, LogFilter, Session,
+    SessionEntry, Symbol, LEVELS,
+};
+use crate::storage::{level_value, symbol_value, IndexedField};
+use gst_debug::{BoundThresholds, Thresholds};
+use language::{Expr, Field, Operator, ParseError};
+
+// Level with the given name or number, named the way `GST_DEBUG` names them but
+// ignoring case, `WARN` also standing for warnings. Numbers are those of GStreamer,
+// 8 isn't a level of its own and stands for the levels up to `TRACE`.
+pub fn parse_level(name: &str) -> Option<DebugLevel> {
+    if let Ok(number) = name.parse::<u8>() {
+        return match number {
+            0..=7 => Some(LEVELS[number as usize]),
+            8 => Some(DebugLevel::Trace),
+            9 => Some(DebugLevel::Memdump),
+            _ => None,
+        };
+    }
+    const NAMES: [(&str, DebugLevel); 10] = [
+        ("NONE", DebugLevel::None),
+        ("ERROR", DebugLevel::Error),
+        ("WARNING", DebugLevel::Warning),
+        ("WARN", DebugLevel::Warning),
+        ("FIXME", DebugLevel::Fixme),
+        ("INFO", DebugLevel::Info),
+        ("DEBUG", DebugLevel::Debug),
+        ("LOG", DebugLevel::Log),
+        ("TRACE", DebugLevel::Trace),
+        ("MEMDUMP", DebugLevel::Memdump),
+    ];
+    NAMES
+        .into_iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|(_, level)| level)
+}
+
+// A filter with its regexes compiled, ready to be applied to any session
+#[derive(Debug)]
+pub struct FilterPlan {
+    filter: LogFilter,
+    message: Option<Regex>,
+    function: Option<Regex>,
+    // Time range in nanoseconds, inclusive. Timestamps of the filter are in
+    // milliseconds or microseconds and compared to truncated timestamps of entries.
+    min_ts: Option<u64>,
+    max_ts: Option<u64>,
+    // Query of the `q` parameter
+    query: Option<Predicate>,
+    thresholds: Option<Thresholds>,
+}
+
+impl FilterPlan {
+    pub fn compile(filter: &LogFilter) -> Result<Self, ApiError> {
+        let unit = if filter.use_microseconds {
+            1_000
+        } else {
+            1_000_000
+        };
+
+        Ok(FilterPlan {
+            filter: filter.clone(),
+            message: compile_regex("message", filter.message_regex.as_deref())?,
+            function: compile_regex("function", filter.function_regex.as_deref())?,
+            min_ts: filter
+                .min_timestamp
+                .map(|min_ts| min_ts.saturating_mul(unit)),
+            max_ts: filter
+                .max_timestamp
+                .map(|max_ts| max_ts.saturating_add(1).saturating_mul(unit) - 1),
+            query: match filter.q.as_deref() {
+                Some(q) if !q.trim().is_empty() => Some(compile_query(q)?),
+                _ => None,
+            },
+            thresholds: match filter.gst_debug.as_deref() {
+                Some(spec) if !spec.trim().is_empty() => {
+                    Some(Thresholds::parse(spec).map_err(|e| invalid("gst_debug", spec, e))?)
+                }
+                _ => None,
+            },
+        })
+    }
+
+    // Whether the filter selects every entry
+    pub fn is_empty(&self) -> bool {
+        self.is_time_range() && self.min_ts.is_none() && self.max_ts.is_none()
+    }
+
+    // Whether the filter only selects a time range, if any
+    fn is_time_range(&self) -> bool {
+        let filter = &self.filter;
+        filter.source.is_none()
+            && filter.level.is_none()
+            && filter.categories.is_empty()
+            && filter.message_regex.is_none()
+            && filter.pid.is_none()
+            && filter.thread.is_none()
+            && filter.object.is_none()
+            && filter.function_regex.is_none()
+            && filter.unit.is_none()
+            && filter.journal_pid.is_none()
+            && filter.line_from.is_none()
+            && filter.line_to.is_none()
+            && self.query.is_none()
+            && self.thresholds.is_none()
+    }
+
+    // Resolve the filtered values against the strings of a session, entries only store symbols
+    pub fn bind<'a>(&'a self, session: &'a Session) -> BoundFilter<'a> {
+        let filter = &self.filter;
+        let strings = &session.strings;
+
+        let categories = if filter.categories.is_empty() {
+            None
+        } else {
+            let symbols: Vec<Symbol> = strings
+                .iter()
+                .filter(|(_, string)| {
+                    filter
+                        .categories
+                        .iter()
+                        .any(|cat| cat == string || cat.trim() == string.trim())
+                })
+                .map(|(symbol, _)| symbol)
+                .collect();
+            log::debug!(
+                "Categories {:?} resolved to {} symbol(s)",
+                filter.categories,
+                symbols.len()
+            );
+            Some(symbols)
+        };
+
+        BoundFilter {
+            plan: self,
+            session,
+            source: filter
+                .source
+                .as_ref()
+                .map(|source| session.source_index(source)),
+            level: filter.level.as_deref().map(level_from_name),
+            categories,
+            thread: filter.thread.as_deref().map(|thread| strings.get(thread)),
+            object: filter.object.as_deref().map(|object| strings.get(object)),
+            unit: filter.unit.as_deref().map(|unit| strings.get(unit)),
+            functions: self.function.as_ref().map(|regex| {
+                strings
+                    .iter()
+                    .filter(|(_, string)| regex.is_match(string))
+                    .map(|(symbol, _)| symbol)
+                    .collect()
+            }),
+            query: self.query.as_ref().map(|query| query.bind(session)),
+            thresholds: self
+                .thresholds
+                .as_ref()
+                .map(|thresholds| thresholds.bind(strings)),
+        }
+    }
+}
+
+fn compile_query(q: &str) -> Result<Predicate, ApiError> {
+    language::parse(q)
+        .and_then(|expr| Predicate::compile(&expr))
+        .map_err(|e| invalid("query", q, e))
+}
+
+fn invalid(name: &str, input: &str, e: ParseError) -> ApiError {
+    let msg = e.describe(name, input);
+    log::error!("{}", msg);
+    ApiError {
+        status: StatusCode::BAD_REQUEST,
+        message: msg,
+    }
+}
+
+// A query with its patterns compiled
+#[derive(Debug)]
+enum Predicate {
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+    // Text fields match a regex, values and wildcards of `:` are turned into one
+    Text(Field, Regex),
+    Level(Operator, DebugLevel),
+    Number(Field, Operator, u64),
+}
+
+impl Predicate {
+    fn compile(expr: &Expr) -> Result<Self, ParseError> {
+        let compile = |expr: &Expr| Self::compile(expr).map(Box::new);
+        let condition = match expr {
+            Expr::And(left, right) => return Ok(Predicate::And(compile(left)?, compile(right)?)),
+            Expr::Or(left, right) => return Ok(Predicate::Or(compile(left)?, compile(right)?)),
+            Expr::Not(expr) => return Ok(Predicate::Not(compile(expr)?)),
+            Expr::Condition(condition) => condition,
+        };
+
+        Ok(match condition.value {
+            language::Value::Level(level) => Predicate::Level(condition.operator, level),
+            language::Value::Number(number) => {
+                Predicate::Number(condition.field, condition.operator, number)
+            }
+            language::Value::Text(ref text) => {
+                let pattern = match condition.operator {
+                    Operator::Regex => text.clone(),
+                    _ if condition.field == Field::Message => regex::escape(text),
+                    _ => glob_regex(text),
+                };
+                let regex = Regex::new(&pattern).map_err(|e| {
+                    ParseError::new(condition.position, format!("invalid regex: {}", e))
+                })?;
+                Predicate::Text(condition.field, regex)
+            }
+        })
+    }
+
+    // Resolve text conditions to the strings of the session that match them
+    fn bind<'a>(&'a self, session: &Session) -> BoundPredicate<'a> {
+        let bind = |predicate: &'a Predicate| Box::new(predicate.bind(session));
+        match *self {
+            Predicate::And(ref left, ref right) => BoundPredicate::And(bind(left), bind(right)),
+            Predicate::Or(ref left, ref right) => BoundPredicate::Or(bind(left), bind(right)),
+            Predicate::Not(ref predicate) => BoundPredicate::Not(bind(predicate)),
+            Predicate::Text(Field::Message, ref regex) => BoundPredicate::Message(regex),
+            Predicate::Text(Field::Source, ref regex) => BoundPredicate::Sources(
+                session
+                    .sources
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, source)| regex.is_match(&source.name))
+                    .map(|(index, _)| index)
+                    .collect(),
+            ),
+            Predicate::Text(field, ref regex) => BoundPredicate::Symbols(
+                field,
+                session
+                    .strings
+                    .iter()
+                    .filter(|(_, string)| regex.is_match(string))
+                    .map(|(symbol, _)| symbol)
+                    .collect(),
+            ),
+            Predicate::Level(operator, level) => BoundPredicate::Levels(
+                LEVELS
+                    .into_iter()
+                    // Levels compare by severity, the opposite of their order
+                    .filter(|known| {
+                        operator.compare(
+                            std::cmp::Reverse(level_value(*known)),
+                            std::cmp::Reverse(level_value(level)),
+                        )
+                    })
+                    .collect(),
+            ),
+            Predicate::Number(field, operator, number) => {
+                BoundPredicate::Number(field, operator, number)
+            }
+        }
+    }
+}
+
+// Regex matching the whole of a value, `*` and `?` matching any characters
+fn glob_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+// A query bound to a session
+enum BoundPredicate<'a> {
+    And(Box<BoundPredicate<'a>>, Box<BoundPredicate<'a>>),
+    Or(Box<BoundPredicate<'a>>, Box<BoundPredicate<'a>>),
+    Not(Box<BoundPredicate<'a>>),
+    // Strings of the session matching a condition on an interned field
+    Symbols(Field, HashSet<Symbol>),
+    Message(&'a Regex),
+    Sources(HashSet<usize>),
+    Levels(Vec<DebugLevel>),
+    Number(Field, Operator, u64),
+}
+
+impl BoundPredicate<'_> {
+    fn matches(&self, entry: &impl EntryFields) -> bool {
+        match *self {
+            BoundPredicate::And(ref left, ref right) => left.matches(entry) && right.matches(entry),
+            BoundPredicate::Or(ref left, ref right) => left.matches(entry) || right.matches(entry),
+            BoundPredicate::Not(ref predicate) => !predicate.matches(entry),
+            BoundPredicate::Symbols(field, ref symbols) => {
+                let symbol = match field {
+                    Field::Category => Some(entry.category()),
+                    Field::Thread => Some(entry.thread()),
+                    Field::Object => entry.object(),
+                    Field::Function => Some(entry.function()),
+                    Field::File => Some(entry.file()),
+                    Field::Unit => entry.unit(),
+                    _ => unreachable!("{:?} isn't interned", field),
+                };
+                symbol.is_some_and(|symbol| symbols.contains(&symbol))
+            }
+            BoundPredicate::Message(regex) => regex.is_match(&entry.message()),
+            BoundPredicate::Sources(ref sources) => sources.contains(&entry.source()),
+            BoundPredicate::Levels(ref levels) => levels.contains(&entry.level()),
+            BoundPredicate::Number(field, operator, number) => {
+                let value = match field {
+                    Field::Pid => Some(entry.pid() as u64),
+                    Field::JournalPid => entry.journal_pid().map(u64::from),
+                    Field::Line => Some(entry.line() as u64),
+                    Field::LineNumber => Some(entry.line_number()),
+                    _ => unreachable!("{:?} isn't a number", field),
+                };
+                value.is_some_and(|value| operator.compare(value, number))
+            }
+        }
+    }
+
+    // The query as an SQL condition that is never NULL, so that `NOT` doesn't drop
+    // entries missing a field
+    fn sql(&self, params: &mut Vec<Value>) -> String {
+        // Values are integers and written inline, queries can match many strings
+        let any_of = |column: &str, values: Vec<i64>| {
+            if values.is_empty() {
+                return "0".to_string();
+            }
+            let values: Vec<String> = values.iter().map(i64::to_string).collect();
+            format!("ifnull({} IN ({}), 0)", column, values.join(", "))
+        };
+        let symbols = |symbols: &HashSet<Symbol>| {
+            symbols
+                .iter()
+                .map(|symbol| symbol_value(*symbol) as i64)
+                .collect()
+        };
+
+        match *self {
+            BoundPredicate::And(ref left, ref right) => {
+                format!("({} AND {})", left.sql(params), right.sql(params))
+            }
+            BoundPredicate::Or(ref left, ref right) => {
+                format!("({} OR {})", left.sql(params), right.sql(params))
+            }
+            BoundPredicate::Not(ref predicate) => format!("NOT {}", predicate.sql(params)),
+            BoundPredicate::Symbols(field, ref values) => {
+                let column = match field {
+                    Field::Category => "category",
+                    Field::Thread => "thread",
+                    Field::Object => "object",
+                    Field::Function => "function",
+                    Field::File => "file",
+                    Field::Unit => "unit",
+                    _ => unreachable!("{:?} isn't interned", field),
+                };
+                any_of(column, symbols(values))
+            }
+            BoundPredicate::Message(regex) => {
+                params.push(Value::Text(regex.as_str().to_string()));
+                "message REGEXP ?".to_string()
+            }
+            BoundPredicate::Sources(ref sources) => any_of(
+                "source",
+                sources.iter().map(|&source| source as i64).collect(),
+            ),
+            BoundPredicate::Levels(ref levels) => any_of(
+                "level",
+                levels
+                    .iter()
+                    .map(|&level| level_value(level) as i64)
+                    .collect(),
+            ),
+            BoundPredicate::Number(field, operator, number) => {
+                let column = match field {
+                    Field::Pid => "pid",
+                    Field::JournalPid => "journal_pid",
+                    Field::Line => "line",
+                    Field::LineNumber => "line_number",
+                    _ => unreachable!("{:?} isn't a number", field),
+                };
+                let operator = match operator {
+                    Operator::Match => "=",
+                    Operator::Less => "<",
+                    Operator::LessOrEqual => "<=",
+                    Operator::Greater => ">",
+                    Operator::GreaterOrEqual => ">=",
+                    Operator::Regex => unreachable!("regexes only apply to text"),
+                };
+                params.push(Value::Integer(i64::try_from(number).unwrap_or(i64::MAX)));
+                format!("ifnull({} {} ?, 0)", column, operator)
+            }
+        }
+    }
+}
+
+fn compile_regex(field: &str, pattern: Option<&str>) -> Result<Option<Regex>, ApiError> {
+    let pattern = match pattern {
+        Some(pattern) => pattern,
+        None => return Ok(None),
+    };
+    Regex::new(pattern).map(Some).map_err(|e| {
+        let msg = format!("Invalid {} regex {:?}: {}", field, pattern, e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: msg,
+        }
+    })
+}
+
+// A filter bound to a session. For each filtered value, `None` means it isn't
+// set and `Some(None)` that no entry of the session can match.
+pub struct BoundFilter<'a> {
+    plan: &'a FilterPlan,
+    session: &'a Session,
+    source: Option<Option<usize>>,
+    level: Option<Option<DebugLevel>>,
+    // Every string matching one of the categories, ignoring surrounding whitespace
+    categories: Option<Vec<Symbol>>,
+    thread: Option<Option<Symbol>>,
+    object: Option<Option<Symbol>>,
+    unit: Option<Option<Symbol>>,
+    // Every string matching the function regex, so it's only matched once per function
+    functions: Option<HashSet<Symbol>>,
+    query: Option<BoundPredicate<'a>>,
+    thresholds: Option<BoundThresholds>,
+}
+
+impl<'a> BoundFilter<'a> {
+    // Check whether an entry of the session matches the filter
+    pub fn matches(&self, entry: &impl EntryFields) -> bool {
+        let filter = &self.plan.filter;
+
+        // Filter by time range if specified
+        let ts = entry.ts();
+        if self.plan.min_ts.is_some_and(|min_ts| ts < min_ts)
+            || self.plan.max_ts.is_some_and(|max_ts| ts > max_ts)
+        {
+            return false;
+        }
+
+        // Filter by source file if specified
+        if let Some(source_index) = self.source {
+            if source_index != Some(entry.source()) {
+                return false;
+            }
+        }
+
+        // Filter by journal fields if specified, entries of other logs don't have them
+        if let Some(unit) = self.unit {
+            if unit.is_none() || entry.unit() != unit {
+                return false;
+            }
+        }
+        if filter.journal_pid.is_some() && entry.journal_pid() != filter.journal_pid {
+            return false;
+        }
+
+        // Filter by line numbers if specified
+        let line_number = entry.line_number();
+        if filter
+            .line_from
+            .is_some_and(|line_from| line_number < line_from)
+            || filter.line_to.is_some_and(|line_to| line_number > line_to)
+        {
+            return false;
+        }
+
+        // Filter by level if specified
+        if let Some(level) = self.level {
+            if level != Some(entry.level()) {
+                return false;
+            }
+        }
+
+        // Filter by categories if specified
+        if let Some(ref categories) = self.categories {
+            if !categories.contains(&entry.category()) {
+                return false;
+            }
+        }
+
+        // Filter by PID if specified
+        if let Some(pid) = filter.pid {
+            if entry.pid() != pid {
+                return false;
+            }
+        }
+
+        // Filter by thread if specified
+        if let Some(thread) = self.thread {
+            if thread != Some(entry.thread()) {
+                return false;
+            }
+        }
+
+        // Filter by object if specified
+        if let Some(object) = self.object {
+            if object.is_none() || object != entry.object() {
+                return false;
+            }
+        }
+
+        // Filter by function using regex if specified
+        if let Some(ref functions) = self.functions {
+            if !functions.contains(&entry.function()) {
+                return false;
+            }
+        }
+
+        // Filter by message using regex if specified
+        if let Some(ref regex) = self.plan.message {
+            if !regex.is_match(&entry.message()) {
+                return false;
+            }
+        }
+
+        // Filter by query if specified
+        if let Some(ref query) = self.query {
+            if !query.matches(entry) {
+                return false;
+            }
+        }
+
+        // Filter by GST_DEBUG thresholds if specified
+        if let Some(ref thresholds) = self.thresholds {
+            if !thresholds.matches(entry) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Positions of the matching entries, when they follow each other and are found
+    // without looking at the entries: every entry, or those of a time range of a
+    // mapped session
+    fn range(&self) -> Option<Range<usize>> {
+        if self.plan.is_empty() {
+            return Some(0..self.session.entries.len());
+        }
+        match self.session.entries {
+            Entries::Mapped(ref entries) if self.plan.is_time_range() => {
+                Some(entries.time_range(self.plan.min_ts, self.plan.max_ts))
+            }
+            _ => None,
+        }
+    }
+
+    // Number of matching entries, along with those from the `offset`th one on and
+    // at most `limit` of them. Entries of SQLite sessions are counted and read by
+    // a query each.
+    pub fn page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(usize, Vec<Cow<'a, SessionEntry>>), ApiError> {
+        let session = self.session;
+        let (total, positions) = match (self.range(), &session.entries) {
+            (Some(range), entries) => {
+                let start = range.start.saturating_add(offset).min(range.end);
+                let end = start.saturating_add(limit).min(range.end);
+                if let Entries::Sqlite(entries) = entries {
+                    let page = entries
+                        .get_range(start..end)
+                        .map_err(|e| self.query_error(e))?;
+                    return Ok((range.len(), page.into_iter().map(Cow::Owned).collect()));
+                }
+                (range.len(), (start..end).collect())
+            }
+            (None, Entries::Sqlite(entries)) => {
+                let (condition, params) = self.sql_condition();
+                let total = entries
+                    .count(&condition, params.clone())
+                    .map_err(|e| self.query_error(e))?;
+                let page = entries
+                    .select_page(&condition, params, offset, limit)
+                    .map_err(|e| self.query_error(e))?;
+                return Ok((total, page.into_iter().map(Cow::Owned).collect()));
+            }
+            // The matches are counted as they are found, only the positions of the page are kept
+            (None, _) => {
+                let mut total = 0;
+                let mut positions = Vec::new();
+                for position in self.matching_positions()? {
+                    if total >= offset && positions.len() < limit {
+                        positions.push(position);
+                    }
+                    total += 1;
+                }
+                (total, positions)
+            }
+        };
+
+        let entries = positions
+            .into_iter()
+            .map(|position| session.entry(position).map_err(|e| self.query_error(e)))
+            .collect::<Result<_, _>>()?;
+        Ok((total, entries))
+    }
+
+    // Call `f` with the timestamp of each matching entry in nanoseconds, in order
+    pub fn for_each_ts(&self, mut f: impl FnMut(u64)) -> Result<(), ApiError> {
+        let entries = &self.session.entries;
+        if let Entries::Sqlite(sqlite) = entries {
+            let (condition, params) = self.sql_condition();
+            return sqlite
+                .for_each_ts(&condition, params, f)
+                .map_err(|e| self.query_error(e));
+        }
+        for position in self.matching_positions()? {
+            f(entries.ts(position).map_err(|e| self.query_error(e))?);
+        }
+        Ok(())
+    }
+
+    // Call `f` with the fields of each matching entry, in order
+    pub fn for_each_match(&self, mut f: impl FnMut(&dyn EntryFields)) -> Result<(), ApiError> {
+        let session = self.session;
+        match session.entries {
+            Entries::Sqlite(ref entries) => {
+                let (condition, params) = self.sql_condition();
+                entries
+                    .for_each_entry(&condition, params, |entry| f(&entry))
+                    .map_err(|e| self.query_error(e))
+            }
+            // Only the fields that are looked at are read
+            Entries::Mapped(ref entries) => {
+                for position in self.matching_positions()? {
+                    f(&entries.fields(position));
+                }
+                Ok(())
+            }
+            Entries::Memory(ref entries) => {
+                for position in self.matching_positions()? {
+                    f(&entries[position]);
+                }
+                Ok(())
+            }
+            Entries::Writing(_) => Err(self.query_error(writing_error())),
+        }
+    }
+
+    // Positions of the matching entries of sessions that aren't in SQLite, in order.
+    // Entries are checked as the positions are iterated.
+    fn matching_positions(&self) -> Result<Box<dyn Iterator<Item = usize> + '_>, ApiError> {
+        if let Some(range) = self.range() {
+            return Ok(Box::new(range));
+        }
+        match self.session.entries {
+            // Only the fields the filter looks at are read
+            Entries::Mapped(ref entries) => Ok(Box::new(
+                self.candidate_positions()
+                    .filter(move |&position| self.matches(&entries.fields(position))),
+            )),
+            Entries::Memory(ref entries) => Ok(Box::new(
+                self.candidate_positions()
+                    .filter(move |&position| self.matches(&entries[position])),
+            )),
+            Entries::Writing(_) => Err(self.query_error(writing_error())),
+            // The whole filter runs as a query, using the indexes of the database
+            Entries::Sqlite(_) => unreachable!("entries of SQLite sessions are selected in SQL"),
+        }
+    }
+
+    // Error of a failed read of the entries of the session
+    pub fn query_error(&self, e: io::Error) -> ApiError {
+        let msg = format!(
+            "Failed to query session {}: {}",
+            self.plan.filter.session_id, e
+        );
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    }
+
+    // Positions of the entries that may match the filter. The indexes of mapped sessions
+    // narrow them down, the entries still have to be checked against the whole filter.
+    fn candidate_positions(&self) -> Box<dyn Iterator<Item = usize> + '_> {
+        let entries = match self.session.entries {
+            Entries::Mapped(ref entries) => entries,
+            _ => return Box::new(0..self.session.entries.len()),
+        };
+        let range = entries.time_range(self.plan.min_ts, self.plan.max_ts);
+
+        // Values to look up in the index of each filtered field
+        let mut lookups: Vec<(IndexedField, Vec<u32>)> = Vec::new();
+        if let Some(source) = self.source {
+            let values = source.map(|source| source as u32).into_iter().collect();
+            lookups.push((IndexedField::Source, values));
+        }
+        if let Some(level) = self.level {
+            lookups.push((
+                IndexedField::Level,
+                level.map(level_value).into_iter().collect(),
+            ));
+        }
+        if let Some(ref categories) = self.categories {
+            let values = categories.iter().copied().map(symbol_value).collect();
+            lookups.push((IndexedField::Category, values));
+        }
+        if let Some(thread) = self.thread {
+            lookups.push((
+                IndexedField::Thread,
+                thread.map(symbol_value).into_iter().collect(),
+            ));
+        }
+        if let Some(object) = self.object {
+            lookups.push((
+                IndexedField::Object,
+                object.map(symbol_value).into_iter().collect(),
+            ));
+        }
+        if let Some(pid) = self.plan.filter.pid {
+            lookups.push((IndexedField::Pid, vec![pid]));
+        }
+        if let Some(unit) = self.unit {
+            lookups.push((
+                IndexedField::Unit,
+                unit.map(symbol_value).into_iter().collect(),
+            ));
+        }
+
+        // Positions found in every index, they are sorted so those of the time range
+        // follow each other
+        let mut positions: Option<Box<dyn Iterator<Item = usize> + '_>> = None;
+        for (field, values) in lookups {
+            let found = entries.positions(field, values);
+            positions = Some(match positions {
+                Some(positions) => Box::new(intersect_sorted(positions, found)),
+                None => Box::new(found),
+            });
+        }
+        match positions {
+            Some(positions) => Box::new(
+                positions
+                    .skip_while(move |&position| position < range.start)
+                    .take_while(move |&position| position < range.end),
+            ),
+            None => Box::new(range),
+        }
+    }
+
+    // The filter as an SQL condition on the entries of SQLite sessions, with the
+    // values of its `?` parameters
+    pub fn sql_condition(&self) -> (String, Vec<Value>) {
+        let filter = &self.plan.filter;
+        let mut conditions: Vec<String> = Vec::new();
+        let mut params: Vec<Value> = Vec::new();
+        // Equality with one of several values, nothing matches when there are none.
+        // Values are integers and written inline, sets such as the functions matching
+        // a regex can have more values than a statement can have parameters.
+        let mut any_of = |column: &str, values: Vec<i64>| {
+            if values.is_empty() {
+                conditions.push("0".to_string());
+            } else {
+                let values: Vec<String> = values.iter().map(i64::to_string).collect();
+                conditions.push(format!("{} IN ({})", column, values.join(", ")));
+            }
+        };
+        let symbol = |symbol: Symbol| symbol_value(symbol) as i64;
+
+        if let Some(source) = self.source {
+            any_of(
+                "source",
+                source.map(|source| source as i64).into_iter().collect(),
+            );
+        }
+        if let Some(level) = self.level {
+            any_of(
+                "level",
+                level
+                    .map(|level| level_value(level) as i64)
+                    .into_iter()
+                    .collect(),
+            );
+        }
+        if let Some(ref categories) = self.categories {
+            any_of("category", categories.iter().copied().map(symbol).collect());
+        }
+        if let Some(thread) = self.thread {
+            any_of("thread", thread.map(symbol).into_iter().collect());
+        }
+        if let Some(object) = self.object {
+            any_of("object", object.map(symbol).into_iter().collect());
+        }
+        if let Some(pid) = filter.pid {
+            any_of("pid", vec![pid as i64]);
+        }
+        if let Some(unit) = self.unit {
+            any_of("unit", unit.map(symbol).into_iter().collect());
+        }
+        if let Some(pid) = filter.journal_pid {
+            any_of("journal_pid", vec![pid as i64]);
+        }
+        if let Some(ref functions) = self.functions {
+            any_of("function", functions.iter().copied().map(symbol).collect());
+        }
+
+        let mut bound = |condition: &str, value: Option<u64>| {
+            if let Some(value) = value {
+                conditions.push(condition.to_string());
+                params.push(Value::Integer(i64::try_from(value).unwrap_or(i64::MAX)));
+            }
+        };
+        bound("ts >= ?", self.plan.min_ts);
+        bound("ts <= ?", self.plan.max_ts);
+        bound("line_number >= ?", filter.line_from);
+        bound("line_number <= ?", filter.line_to);
+
+        if let Some(ref message_regex) = filter.message_regex {
+            // Literals are looked up in the trigrams of the messages first, the
+            // lookup ignores case so the regex still decides
+            if message_regex.chars().count() >= 3 && regex::escape(message_regex) == *message_regex
+            {
+                conditions
+                    .push("id IN (SELECT rowid FROM messages WHERE messages MATCH ?)".to_string());
+                params.push(Value::Text(format!(
+                    "\"{}\"",
+                    message_regex.replace('"', "\"\"")
+                )));
+            }
+            conditions.push("message REGEXP ?".to_string());
+            params.push(Value::Text(message_regex.clone()));
+        }
+
+        if let Some(ref query) = self.query {
+            conditions.push(query.sql(&mut params));
+        }
+        if let Some(ref thresholds) = self.thresholds {
+            conditions.push(thresholds.sql());
+        }
+
+        if conditions.is_empty() {
+            return ("1".to_string(), params);
+        }
+        (conditions.join(" AND "), params)
+    }
+}
+
+// Values present in both sorted iterators
+fn intersect_sorted(
+    a: impl Iterator<Item = usize>,
+    b: impl Iterator<Item = usize>,
+) -> impl Iterator<Item = usize> {
+    a.merge_join_by(b, usize::cmp)
+        .filter_map(|either| match either {
+            EitherOrBoth::Both(position, _) => Some(position),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SessionEntry;
+
+    fn filter(fields: serde_json::Value) -> LogFilter {
+        let mut filter = fields;
+        filter["session_id"] = "session".into();
+        serde_json::from_value(filter).unwrap()
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        for fields in [
+            serde_json::json!({ "message_regex": "(unclosed" }),
+            serde_json::json!({ "function_regex": "[a-" }),
+        ] {
+            let error = FilterPlan::compile(&filter(fields)).unwrap_err();
+            assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn limits_timestamps_to_the_time_range() {
+        let mut session = Session::new();
+        // 0.5ms apart, from 1ms on
+        let entries = (0..8)
+            .map(|i| {
+                let ts = 1_000_000 + i * 500_000;
+                SessionEntry::for_test(&mut session.strings, ts, "a", DebugLevel::Info, "")
+            })
+            .collect();
+        session.insert_entries(entries).unwrap();
+
+        let timestamps = |fields| {
+            let plan = FilterPlan::compile(&filter(fields)).unwrap();
+            let mut timestamps = Vec::new();
+            plan.bind(&session)
+                .for_each_ts(|ts| timestamps.push(ts / 500_000))
+                .unwrap();
+            timestamps
+        };
+        assert_eq!(
+            timestamps(serde_json::json!({ "min_timestamp": 2, "max_timestamp": 3 })),
+            [4, 5, 6, 7]
+        );
+        assert_eq!(
+            timestamps(serde_json::json!({
+                "min_timestamp": 1500,
+                "max_timestamp": 2000,
+                "use_microseconds": true,
+            })),
+            [3, 4]
+        );
+        assert_eq!(
+            timestamps(serde_json::json!({ "min_timestamp": 5 })),
+            Vec::<u64>::new()
+        );
+    }
+}
@@ -0,0 +1,730 @@
+use std::collections::HashMap;
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, RawQuery, State};
+use axum::http::StatusCode;
+use axum::response::{Json, Response};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use uuid::Uuid;
+
+use crate::filter::FilterPlan;
+use crate::handlers::sessions::{ensure_loaded, persist_session};
+use crate::models::{
+    ApiError, AppState, LiveSubscriber, LogFilter, SerializableEntry, Session, SessionEntry,
+    SessionStatus, SessionStatusResponse,
+};
+use crate::parser::{IncrementalParser, ParseReport, ParsedEntry};
+
+// How long to wait for the file to grow once its end has been reached
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Number of bytes read from the file at once
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+// Entries queued for a client before it is considered too slow and disconnected
+const SUBSCRIBER_QUEUE_SIZE: usize = 4096;
+
+// Source name of entries received through `ingest_stream`
+const STREAM_SOURCE_NAME: &str = "stream";
+
+// Request body for tailing a file on the server
+#[derive(Debug, Deserialize)]
+pub struct TailRequest {
+    // Path of the log file, relative to the configured tail directory
+    pub path: String,
+}
+
+// Handler for following a log file on the server as it is written
+pub async fn start_tail(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<TailRequest>,
+) -> Result<Json<HashMap<String, String>>, ApiError> {
+    let path = resolve_tail_path(&state, &request.path)?;
+    let session_id = Uuid::new_v4().to_string();
+
+    log::info!("Tailing {} for session {}", path.display(), session_id);
+
+    // The session exists right away and grows as the file does
+    let mut session = new_live_session(&state, &session_id)?;
+    session.add_source(request.path, None, ParseReport::default());
+    state
+        .parsed_logs
+        .write()
+        .unwrap()
+        .insert(session_id.clone(), session);
+    state.set_status(&session_id, SessionStatus::Live { bytes_read: 0 });
+
+    let handle = tokio::spawn(tail_file(path, session_id.clone(), state.clone()));
+    let task_session_id = session_id.clone();
+    let task_state = state.clone();
+    tokio::spawn(async move {
+        let error = match handle.await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => format!("Tail task aborted: {}", e),
+        };
+        log::error!("Stopped tailing for session {}: {}", task_session_id, error);
+        task_state.set_status(&task_session_id, SessionStatus::Failed { error });
+        task_state
+            .live_subscribers
+            .lock()
+            .unwrap()
+            .remove(&task_session_id);
+    });
+
+    Ok(Json(HashMap::from([(
+        "session_id".to_string(),
+        session_id,
+    )])))
+}
+
+// Create an empty session kept like those of uploads, whose entries can be read
+// while they are added
+fn new_live_session(state: &AppState, session_id: &str) -> Result<Session, ApiError> {
+    let created = state.new_session().and_then(|mut session| {
+        session.finish_entries()?;
+        Ok(session)
+    });
+    created.map_err(|e| {
+        let msg = format!("Failed to create session {}: {}", session_id, e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    })
+}
+
+// Resolve a requested path, making sure it doesn't escape the tail directory
+fn resolve_tail_path(state: &AppState, path: &str) -> Result<PathBuf, ApiError> {
+    let tail_dir = state.config.tail_dir.as_ref().ok_or_else(|| {
+        let msg = "Tailing files is disabled, set TAIL_DIR to enable it".to_string();
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::FORBIDDEN,
+            message: msg,
+        }
+    })?;
+
+    let tail_dir = tail_dir.canonicalize().map_err(|e| {
+        let msg = format!("Invalid tail directory {}: {}", tail_dir.display(), e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    })?;
+
+    // Canonicalizing resolves `..` and symlinks before checking the prefix
+    let resolved = tail_dir.join(path).canonicalize().map_err(|e| {
+        let msg = format!("Failed to open {}: {}", path, e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: msg,
+        }
+    })?;
+
+    if !resolved.starts_with(&tail_dir) {
+        let msg = format!("{} is outside of the tail directory", path);
+        log::error!("{}", msg);
+        return Err(ApiError {
+            status: StatusCode::FORBIDDEN,
+            message: msg,
+        });
+    }
+
+    if !resolved.is_file() {
+        let msg = format!("{} is not a file", path);
+        log::error!("{}", msg);
+        return Err(ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: msg,
+        });
+    }
+
+    Ok(resolved)
+}
+
+// Parse new lines of the file as they are written, until the session is removed
+async fn tail_file(path: PathBuf, session_id: String, state: Arc<AppState>) -> Result<()> {
+    let mut file = tokio::fs::File::open(&path).await?;
+    let mut parser = IncrementalParser::new(state.config.fallback_encoding);
+    let mut buf = vec![0; READ_CHUNK_SIZE];
+    let mut position: u64 = 0;
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n > 0 {
+            position += n as u64;
+            let entries = parser.push(&buf[..n]);
+            if !publish_entries(&state, &session_id, 0, entries, &mut parser, n as u64).await? {
+                break;
+            }
+            state.set_status(
+                &session_id,
+                SessionStatus::Live {
+                    bytes_read: position,
+                },
+            );
+            continue;
+        }
+
+        // Reached the end of the file, the last entry is published once the file
+        // stays idle for a while, unless the writer is in the middle of a message
+        let entries = parser.idle().into_iter().collect();
+        if !publish_entries(&state, &session_id, 0, entries, &mut parser, 0).await? {
+            break;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        // Start over if the file was truncated, e.g. by a new run of the pipeline
+        let len = tokio::fs::metadata(&path).await?.len();
+        if len < position {
+            log::info!(
+                "{} was truncated, reading it again from the start",
+                path.display()
+            );
+            file.seek(SeekFrom::Start(0)).await?;
+            position = 0;
+            parser.reset();
+        }
+    }
+
+    log::info!(
+        "Session {} was removed, stopped tailing {}",
+        session_id,
+        path.display()
+    );
+    state.live_subscribers.lock().unwrap().remove(&session_id);
+
+    Ok(())
+}
+
+// Add new entries of a source to a session and send them to its subscribers,
+// along with what the parser found out since the last call and the number of
+// bytes received meanwhile. Entries are added on a blocking thread with the
+// session taken out of the sessions, so that other sessions remain usable meanwhile.
+// Returns false if the session doesn't exist anymore.
+pub async fn publish_entries(
+    state: &Arc<AppState>,
+    session_id: &str,
+    source: usize,
+    entries: Vec<ParsedEntry>,
+    parser: &mut IncrementalParser,
+    new_bytes: u64,
+) -> io::Result<bool> {
+    let format = parser.format();
+    let report = parser.take_report();
+    if entries.is_empty() {
+        let mut logs = state.parsed_logs.write().unwrap();
+        let session = match logs.get_mut(session_id) {
+            Some(session) => session,
+            None => return Ok(false),
+        };
+        update_source(session, source, format, report, new_bytes);
+        return Ok(true);
+    }
+
+    let task_state = state.clone();
+    let task_session_id = session_id.to_string();
+    let live_entries = tokio::task::spawn_blocking(move || {
+        insert_live_entries(&task_state, &task_session_id, |session| {
+            update_source(session, source, format, report, new_bytes);
+            entries
+                .into_iter()
+                .map(|entry| SessionEntry::new(entry, source, &mut session.strings))
+                .collect()
+        })
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    match live_entries {
+        Some(entries) => {
+            if !entries.is_empty() {
+                notify_subscribers(state, session_id, &entries);
+            }
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Record what was found out about a source since entries were last added
+fn update_source(
+    session: &mut Session,
+    source: usize,
+    format: Option<&'static str>,
+    report: ParseReport,
+    new_bytes: u64,
+) {
+    session.metadata_mut().size_bytes += new_bytes;
+    if let Some(format) = format {
+        session.sources[source].format = Some(format);
+    }
+    if !report.is_empty() {
+        session.record_rejected_lines(source, report);
+    }
+}
+
+// Insert the entries made by `entries` from the session, which is taken out of the
+// sessions meanwhile like for an append. Returns a copy of the entries for the
+// subscribers of the session, if any, or None if the session doesn't exist anymore.
+fn insert_live_entries(
+    state: &AppState,
+    session_id: &str,
+    entries: impl FnOnce(&mut Session) -> Vec<SessionEntry>,
+) -> io::Result<Option<Vec<SessionEntry>>> {
+    // Held so that the session isn't loaded again, stored or removed while it's out
+    let stored_version = state.stored_version(session_id);
+    let _stored_version = stored_version.lock().unwrap();
+    let status = state.sessions.read().unwrap().get(session_id).cloned();
+    let mut session = match state.parsed_logs.write().unwrap().remove(session_id) {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+
+    let entries = entries(&mut session);
+    let has_subscribers = state
+        .live_subscribers
+        .lock()
+        .unwrap()
+        .contains_key(session_id);
+    let live_entries = if has_subscribers {
+        entries.clone()
+    } else {
+        Vec::new()
+    };
+    // A failed insert leaves the session as it was, it's put back either way
+    let result = session.insert_entries(entries);
+
+    let mut logs = state.parsed_logs.write().unwrap();
+    let sessions = state.sessions.read().unwrap();
+    if logs.contains_key(session_id) || sessions.get(session_id) != status.as_ref() {
+        return Ok(None);
+    }
+    logs.insert(session_id.to_string(), session);
+    drop(sessions);
+    drop(logs);
+
+    result?;
+    Ok(Some(live_entries))
+}
+
+// Send the entries matching each subscriber's filter, dropping subscribers that went away
+fn notify_subscribers(state: &AppState, session_id: &str, entries: &[SessionEntry]) {
+    let subscribers: Vec<(Arc<FilterPlan>, mpsc::Sender<SerializableEntry>)> =
+        match state.live_subscribers.lock().unwrap().get(session_id) {
+            Some(subscribers) => subscribers
+                .iter()
+                .map(|subscriber| (subscriber.plan.clone(), subscriber.sender.clone()))
+                .collect(),
+            None => return,
+        };
+
+    // Entries are matched while other requests can still read sessions
+    let batches: Vec<Vec<SerializableEntry>> = {
+        let logs = state.parsed_logs.read().unwrap();
+        let session = match logs.get(session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        subscribers
+            .iter()
+            .map(|(plan, _)| {
+                let filter = plan.bind(session);
+                entries
+                    .iter()
+                    .filter(|&entry| filter.matches(entry))
+                    .map(|entry| SerializableEntry::new(entry, session))
+                    .collect()
+            })
+            .collect()
+    };
+
+    let mut gone = Vec::new();
+    for ((_, sender), batch) in subscribers.iter().zip(batches) {
+        for entry in batch {
+            match sender.try_send(entry) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    log::warn!(
+                        "Live client of session {} can't keep up, disconnecting it",
+                        session_id
+                    );
+                    gone.push(sender);
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    gone.push(sender);
+                    break;
+                }
+            }
+        }
+    }
+    if gone.is_empty() {
+        return;
+    }
+
+    let mut subscribers = state.live_subscribers.lock().unwrap();
+    if let Some(session_subscribers) = subscribers.get_mut(session_id) {
+        session_subscribers.retain(|subscriber| {
+            !gone
+                .iter()
+                .any(|sender| sender.same_channel(&subscriber.sender))
+        });
+        if session_subscribers.is_empty() {
+            subscribers.remove(session_id);
+        }
+    }
+}
+
+// Handler for creating an empty session that is then fed through `ingest_stream`
+pub async fn create_ingest_session(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<HashMap<String, String>>, ApiError> {
+    let session_id = Uuid::new_v4().to_string();
+    let session = new_live_session(&state, &session_id)?;
+    log::info!("Created session {} waiting for streamed logs", session_id);
+
+    state
+        .parsed_logs
+        .write()
+        .unwrap()
+        .insert(session_id.clone(), session);
+    state.set_status(&session_id, SessionStatus::Queued);
+
+    Ok(Json(HashMap::from([(
+        "session_id".to_string(),
+        session_id,
+    )])))
+}
+
+// Handler for streaming a log into a session, e.g. the output of a running pipeline.
+// Lines are parsed as they arrive so the session can be queried while it grows.
+pub async fn ingest_stream(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+    body: Body,
+) -> Result<Json<SessionStatusResponse>, ApiError> {
+    ensure_loaded(&state, &session_id).await?;
+
+    // Claim the session, a stream can only be ingested into a session that isn't growing already
+    let has_entries = state.parsed_logs.read().unwrap().contains_key(&session_id);
+    {
+        let mut sessions = state.sessions.write().unwrap();
+        match (sessions.get(&session_id), has_entries) {
+            (Some(SessionStatus::Queued), true) | (Some(SessionStatus::Ready { .. }), true) => {
+                sessions.insert(session_id.clone(), SessionStatus::Live { bytes_read: 0 });
+            }
+            (Some(status), _) => {
+                let msg = format!(
+                    "Session {} can't receive a stream in its current state: {:?}",
+                    session_id, status
+                );
+                log::error!("{}", msg);
+                return Err(ApiError {
+                    status: StatusCode::CONFLICT,
+                    message: msg,
+                });
+            }
+            (None, _) => {
+                let msg = format!("Session not found: {}", session_id);
+                log::error!("{}", msg);
+                return Err(ApiError {
+                    status: StatusCode::NOT_FOUND,
+                    message: msg,
+                });
+            }
+        }
+    }
+
+    let claim = StreamClaim {
+        state: state.clone(),
+        session_id: session_id.clone(),
+        released: false,
+    };
+    log::info!("Receiving streamed log for session {}", session_id);
+
+    let source = match state.parsed_logs.write().unwrap().get_mut(&session_id) {
+        Some(session) => {
+            session.add_source(STREAM_SOURCE_NAME.to_string(), None, ParseReport::default())
+        }
+        None => 0,
+    };
+
+    let mut parser = IncrementalParser::new(state.config.fallback_encoding);
+    let mut bytes_read: u64 = 0;
+    let mut stream = body.into_data_stream();
+    let mut stream_error = None;
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                stream_error = Some(ApiError {
+                    status: StatusCode::BAD_REQUEST,
+                    message: format!(
+                        "Failed to read streamed log after {} bytes: {}",
+                        bytes_read, e
+                    ),
+                });
+                break;
+            }
+        };
+
+        // The body isn't limited like uploads are, the limit is checked as it's received
+        let max_size = state.config.max_upload_size;
+        if bytes_read + chunk.len() as u64 > max_size {
+            stream_error = Some(ApiError {
+                status: StatusCode::PAYLOAD_TOO_LARGE,
+                message: format!("Stream exceeds the maximum size of {} bytes", max_size),
+            });
+            break;
+        }
+
+        bytes_read += chunk.len() as u64;
+        let entries = parser.push(&chunk);
+        let published = publish_entries(
+            &state,
+            &session_id,
+            source,
+            entries,
+            &mut parser,
+            chunk.len() as u64,
+        )
+        .await
+        .map_err(|e| publish_error(&state, &session_id, e))?;
+        if !published {
+            log::info!("Session {} was removed while streaming", session_id);
+            return Err(ApiError {
+                status: StatusCode::GONE,
+                message: format!("Session {} was removed", session_id),
+            });
+        }
+        state.set_status(&session_id, SessionStatus::Live { bytes_read });
+    }
+
+    // Whatever was received is kept, even if the client went away in the middle
+    let entries = parser.finish();
+    publish_entries(&state, &session_id, source, entries, &mut parser, 0)
+        .await
+        .map_err(|e| publish_error(&state, &session_id, e))?;
+    let (entries, metadata, sources) = match state.parsed_logs.read().unwrap().get(&session_id) {
+        Some(session) => (
+            session.entries.len(),
+            Some(session.metadata.clone()),
+            session.source_summaries(),
+        ),
+        None => {
+            log::info!("Session {} was removed while streaming", session_id);
+            return Err(ApiError {
+                status: StatusCode::GONE,
+                message: format!("Session {} was removed", session_id),
+            });
+        }
+    };
+    let status = SessionStatus::Ready { entries };
+    state.set_status(&session_id, status.clone());
+    claim.release();
+
+    let task_state = state.clone();
+    let task_session_id = session_id.clone();
+    if let Err(e) =
+        tokio::task::spawn_blocking(move || persist_session(&task_state, &task_session_id)).await
+    {
+        log::error!("Failed to store session {}: {}", session_id, e);
+    }
+
+    if let Some(e) = stream_error {
+        log::error!("{}", e.message);
+        return Err(e);
+    }
+
+    log::info!(
+        "Stream for session {} ended after {} bytes, session has {} entries",
+        session_id,
+        bytes_read,
+        entries
+    );
+
+    Ok(Json(SessionStatusResponse {
+        session_id,
+        status,
+        metadata,
+        sources,
+    }))
+}
+
+// Claim of a session by `ingest_stream`. If the stream ends without the session being
+// made ready or failed, e.g. when the client goes away and the handler is dropped,
+// the session is made ready with the entries received so far once they are added.
+struct StreamClaim {
+    state: Arc<AppState>,
+    session_id: String,
+    released: bool,
+}
+
+impl StreamClaim {
+    // The stream ended normally, another one may claim the session right away
+    fn release(mut self) {
+        self.released = true;
+    }
+}
+
+impl Drop for StreamClaim {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        let state = self.state.clone();
+        let session_id = std::mem::take(&mut self.session_id);
+        tokio::task::spawn_blocking(move || {
+            let stored_version = state.stored_version(&session_id);
+            let stored_version = stored_version.lock().unwrap();
+            let entries = state
+                .parsed_logs
+                .read()
+                .unwrap()
+                .get(&session_id)
+                .map(|session| session.entries.len());
+            let mut sessions = state.sessions.write().unwrap();
+            let status = match sessions.get_mut(&session_id) {
+                Some(status @ SessionStatus::Live { .. }) => status,
+                _ => return,
+            };
+            *status = match entries {
+                Some(entries) => SessionStatus::Ready { entries },
+                None => SessionStatus::Failed {
+                    error: "Stream ended unexpectedly".to_string(),
+                },
+            };
+            drop(sessions);
+            drop(stored_version);
+
+            log::info!("Stream for session {} was interrupted", session_id);
+            persist_session(&state, &session_id);
+        });
+    }
+}
+
+// Fail a streamed session whose entries couldn't be added
+fn publish_error(state: &AppState, session_id: &str, e: io::Error) -> ApiError {
+    let msg = format!("Failed to add entries to session {}: {}", session_id, e);
+    log::error!("{}", msg);
+    state.set_status(session_id, SessionStatus::Failed { error: msg.clone() });
+    ApiError {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: msg,
+    }
+}
+
+// Handler for receiving new entries of a session over a WebSocket
+pub async fn live_entries(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+    raw_query: RawQuery,
+    query_result: Result<Query<LogFilter>, axum::extract::rejection::QueryRejection>,
+) -> Result<Response, ApiError> {
+    let filter = match query_result {
+        Ok(Query(mut filter)) => {
+            // Manually extract the categories from the raw query string
+            if let Some(query_str) = raw_query.0.as_ref() {
+                let pairs = url::form_urlencoded::parse(query_str.as_bytes());
+                for (key, value) in pairs {
+                    if key == "categories" {
+                        filter.categories.push(value.to_string());
+                    }
+                }
+            }
+            filter
+        }
+        Err(err) => {
+            log::error!("Failed to deserialize live query parameters: {:?}", err);
+            return Err(ApiError {
+                status: StatusCode::BAD_REQUEST,
+                message: format!("Invalid live query parameters: {}", err),
+            });
+        }
+    };
+
+    // Compile the filter once for all the entries sent to this client
+    let plan = FilterPlan::compile(&filter)?;
+
+    // Waits for the session to be put back if entries are being added to it
+    ensure_loaded(&state, &filter.session_id).await?;
+    if !state
+        .parsed_logs
+        .read()
+        .unwrap()
+        .contains_key(&filter.session_id)
+    {
+        let msg = format!("Session not found: {}", filter.session_id);
+        log::error!("{}", msg);
+        return Err(ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: msg,
+        });
+    }
+
+    log::info!("New live client for session {}", filter.session_id);
+
+    let (sender, receiver) = mpsc::channel(SUBSCRIBER_QUEUE_SIZE);
+    state
+        .live_subscribers
+        .lock()
+        .unwrap()
+        .entry(filter.session_id.clone())
+        .or_default()
+        .push(LiveSubscriber {
+            plan: Arc::new(plan),
+            sender,
+        });
+
+    Ok(ws.on_upgrade(move |socket| stream_entries(socket, receiver)))
+}
+
+// Forward entries to the client until either side goes away
+async fn stream_entries(mut socket: WebSocket, mut receiver: mpsc::Receiver<SerializableEntry>) {
+    loop {
+        tokio::select! {
+            entry = receiver.recv() => {
+                // The channel is closed when the session stops growing or the client is too slow
+                let entry = match entry {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                let json = match serde_json::to_string(&entry) {
+                    Ok(json) => json,
+                    Err(e) => {
+                        log::error!("Failed to serialize live entry: {}", e);
+                        continue;
+                    }
+                };
+                if socket.send(Message::Text(json)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // Clients have nothing to say besides closing the connection
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+
+    log::debug!("Live client disconnected");
+    if let Err(e) = socket.close().await {
+        log::debug!("Error closing live connection: {}", e);
+    }
+}
@@ -1,4 +1,6 @@
+pub mod live;
 pub mod options;
 pub mod query;
+pub mod sessions;
 pub mod timeline;
 pub mod upload;
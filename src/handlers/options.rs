@@ -1,90 +1,182 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Instant;
 
 use anyhow::Result;
-use axum::extract::{Query, State};
+use axum::extract::{Query, RawQuery, State};
 use axum::http::StatusCode;
 use axum::response::Json;
 
-use crate::models::{ApiError, AppState, FilterOptionsResponse};
+use crate::filter::FilterPlan;
+use crate::handlers::sessions::{ensure_loaded, read_session};
+use crate::models::{
+    ApiError, AppState, Entries, EntryFields, FilterOptionsResponse, LogFilter, Symbol, LEVELS,
+};
+use crate::storage::IndexedField;
 
-// Handler for getting available filter options
+// Handler for getting available filter options.
+// Options only come from the entries matching the filter parameters, if any.
 pub async fn get_filter_options(
     State(state): State<Arc<AppState>>,
-    Query(filter): Query<HashMap<String, String>>,
+    raw_query: RawQuery,
+    query_result: Result<Query<LogFilter>, axum::extract::rejection::QueryRejection>,
 ) -> Result<Json<FilterOptionsResponse>, ApiError> {
-    let session_id = filter.get("session_id").ok_or_else(|| {
-        let msg = "Missing session_id parameter".to_string();
-        log::error!("{}", msg);
-        ApiError {
-            status: StatusCode::BAD_REQUEST,
-            message: msg,
+    let filter = match query_result {
+        Ok(Query(mut filter)) => {
+            // Manually extract the categories from the raw query string
+            if let Some(query_str) = raw_query.0.as_ref() {
+                let pairs = url::form_urlencoded::parse(query_str.as_bytes());
+                for (key, value) in pairs {
+                    if key == "categories" {
+                        filter.categories.push(value.to_string());
+                    }
+                }
+            }
+            filter
         }
-    })?;
+        Err(err) => {
+            log::error!("Failed to deserialize filter options parameters: {:?}", err);
+            return Err(ApiError {
+                status: StatusCode::BAD_REQUEST,
+                message: format!("Invalid filter options parameters: {}", err),
+            });
+        }
+    };
+    let session_id = &filter.session_id;
 
     log::info!("Fetching filter options for session: {}", session_id);
 
+    // Compile the filter once, invalid regexes are rejected before touching the session
+    let plan = FilterPlan::compile(&filter)?;
+
     // Get the parsed logs for the session
-    let logs = state.parsed_logs.read().unwrap();
+    ensure_loaded(&state, session_id).await?;
 
-    // Check if we have logs for this session
-    let session_exists = logs.contains_key(session_id);
-    log::debug!("Session exists in state: {}", session_exists);
+    let task_session_id = session_id.clone();
+    let response = read_session(&state, session_id, move |session| {
+        let session_id = &task_session_id;
+        let entries = &session.entries;
+        log::debug!("Found session with {} entries", entries.len());
 
-    // List all sessions for debugging
-    log::debug!("Available sessions: {:?}", logs.keys().collect::<Vec<_>>());
+        // Check if we have entries
+        if entries.is_empty() {
+            let msg = format!("No log entries found for session: {}. The log file may be empty or in an incorrect format.", session_id);
+            log::error!("{}", msg);
+            return Err(ApiError {
+                status: StatusCode::NOT_FOUND,
+                message: msg,
+            });
+        }
 
-    let entries = logs.get(session_id).ok_or_else(|| {
-        let msg = format!("Session not found: {}. This may occur if the log file is still being processed or if parsing failed.", session_id);
-        log::error!("{}", msg);
-        ApiError {
-            status: StatusCode::NOT_FOUND,
-            message: msg,
+        // Extract unique values for each filter field
+        let start_time = Instant::now();
+        let mut categories = HashSet::new();
+        let mut levels = HashSet::new();
+        let mut pids = HashSet::new();
+        let mut threads = HashSet::new();
+        let mut objects = HashSet::new();
+        let mut units = HashSet::new();
+
+        let filter = plan.bind(session);
+        match entries {
+            // The indexes of mapped sessions already list the distinct values
+            Entries::Mapped(mapped) if plan.is_empty() => {
+                let symbols = |field| {
+                    mapped
+                        .values(field)
+                        .map(|value| Symbol::from_index(value as usize))
+                };
+                categories.extend(symbols(IndexedField::Category));
+                levels.extend(
+                    mapped
+                        .values(IndexedField::Level)
+                        .map(|value| LEVELS[value as usize]),
+                );
+                pids.extend(mapped.values(IndexedField::Pid));
+                threads.extend(symbols(IndexedField::Thread));
+                objects.extend(symbols(IndexedField::Object));
+                units.extend(symbols(IndexedField::Unit));
+            }
+            // Distinct values are queried from the database
+            Entries::Sqlite(sqlite) => {
+                let (condition, params) = filter.sql_condition();
+                let values = |field| {
+                    sqlite
+                        .values(field, &condition, params.clone())
+                        .map_err(|e| filter.query_error(e))
+                };
+                let symbols = |field| -> Result<Vec<Symbol>, ApiError> {
+                    Ok(values(field)?
+                        .into_iter()
+                        .map(|value| Symbol::from_index(value as usize))
+                        .collect())
+                };
+                categories.extend(symbols(IndexedField::Category)?);
+                levels.extend(
+                    values(IndexedField::Level)?
+                        .into_iter()
+                        .map(|value| LEVELS[value as usize]),
+                );
+                pids.extend(values(IndexedField::Pid)?);
+                threads.extend(symbols(IndexedField::Thread)?);
+                objects.extend(symbols(IndexedField::Object)?);
+                units.extend(symbols(IndexedField::Unit)?);
+            }
+            _ => {
+                let add = |entry: &dyn EntryFields| {
+                    // Collect symbols, their strings are only looked up once per distinct value
+                    categories.insert(entry.category());
+                    levels.insert(entry.level());
+                    pids.insert(entry.pid());
+                    threads.insert(entry.thread());
+                    if let Some(object) = entry.object() {
+                        objects.insert(object);
+                    }
+                    if let Some(unit) = entry.unit() {
+                        units.insert(unit);
+                    }
+                };
+                // Only the collected fields of mapped entries are read
+                filter.for_each_match(add)?;
+            }
         }
-    })?;
 
-    log::debug!("Found session with {} entries", entries.len());
+        let elapsed = start_time.elapsed();
+        log::debug!("Extracted filter options in {:.2?}: {} categories, {} levels, {} PIDs, {} threads, {} objects",
+            elapsed, categories.len(), levels.len(), pids.len(), threads.len(), objects.len());
+
+        let resolve = |symbols: HashSet<Symbol>| -> Vec<String> {
+            symbols
+                .into_iter()
+                .map(|symbol| session.strings.resolve(symbol).to_string())
+                .collect()
+        };
+        let response = FilterOptionsResponse {
+            categories: resolve(categories),
+            levels: levels
+                .into_iter()
+                .map(|level| format!("{:?}", level))
+                .collect(),
+            pids: pids.into_iter().collect(),
+            threads: resolve(threads),
+            objects: resolve(objects),
+            sources: session
+                .sources
+                .iter()
+                .map(|source| source.name.clone())
+                .collect(),
+            units: resolve(units),
+        };
+        Ok(response)
+    })
+    .await?;
 
-    // Check if we have entries
-    if entries.is_empty() {
-        let msg = format!("No log entries found for session: {}. The log file may be empty or in an incorrect format.", session_id);
+    response.map(Json).ok_or_else(|| {
+        let msg = format!("Session not found: {}. This may occur if the log file is still being processed or if parsing failed.", session_id);
         log::error!("{}", msg);
-        return Err(ApiError {
+        ApiError {
             status: StatusCode::NOT_FOUND,
             message: msg,
-        });
-    }
-
-    // Extract unique values for each filter field
-    let start_time = Instant::now();
-    let mut categories = HashSet::new();
-    let mut levels = HashSet::new();
-    let mut pids = HashSet::new();
-    let mut threads = HashSet::new();
-    let mut objects = HashSet::new();
-
-    for entry in entries {
-        categories.insert(entry.category.clone());
-        levels.insert(format!("{:?}", entry.level));
-        pids.insert(entry.pid);
-        threads.insert(entry.thread.clone());
-        if let Some(ref object) = entry.object {
-            objects.insert(object.clone());
         }
-    }
-
-    let elapsed = start_time.elapsed();
-    log::debug!("Extracted filter options in {:.2?}: {} categories, {} levels, {} PIDs, {} threads, {} objects",
-        elapsed, categories.len(), levels.len(), pids.len(), threads.len(), objects.len());
-
-    let response = FilterOptionsResponse {
-        categories: categories.into_iter().collect(),
-        levels: levels.into_iter().collect(),
-        pids: pids.into_iter().collect(),
-        threads: threads.into_iter().collect(),
-        objects: objects.into_iter().collect(),
-    };
-
-    Ok(Json(response))
+    })
 }
@@ -5,22 +5,11 @@ use anyhow::Result;
 use axum::extract::{Query, RawQuery, State};
 use axum::http::StatusCode;
 use axum::response::Json;
-use regex::Regex;
 
+use crate::filter::FilterPlan;
+use crate::handlers::sessions::{ensure_loaded, read_session};
 use crate::models::{ApiError, AppState, LogFilter, SerializableEntry};
 
-// Helper function to convert ClockTime to milliseconds
-fn to_milliseconds(clock_time: &gstreamer::ClockTime) -> u64 {
-    // ClockTime is in nanoseconds, convert to milliseconds
-    clock_time.nseconds() / 1_000_000
-}
-
-// Helper function to convert ClockTime to microseconds
-fn to_microseconds(clock_time: &gstreamer::ClockTime) -> u64 {
-    // ClockTime is in nanoseconds, convert to microseconds
-    clock_time.nseconds() / 1_000
-}
-
 // Handler for getting log entries with filtering and pagination
 pub async fn get_logs(
     State(state): State<Arc<AppState>>,
@@ -70,214 +59,65 @@ pub async fn get_logs(
         log::info!("No categories filter applied");
     }
 
-    // Get the parsed logs for the session
-    let logs = state.parsed_logs.read().unwrap();
-    let entries = logs.get(&filter.session_id).ok_or_else(|| {
-        let msg = format!("Session not found: {}", filter.session_id);
-        log::error!("{}", msg);
-        ApiError {
-            status: StatusCode::NOT_FOUND,
-            message: msg,
-        }
-    })?;
+    // Compile the filter once, invalid regexes are rejected before touching the session
+    let plan = FilterPlan::compile(&filter)?;
 
-    log::debug!("Found session with {} entries", entries.len());
+    // Get the parsed logs for the session
+    ensure_loaded(&state, &filter.session_id).await?;
 
     // Use the explicit flag for microsecond precision
-    let use_microseconds = filter.use_microseconds;
-
-    if use_microseconds {
+    if filter.use_microseconds {
         log::debug!("Using microsecond precision for timestamp filtering (explicitly specified)");
     } else {
         log::debug!("Using millisecond precision for timestamp filtering");
     }
 
-    // Apply time range filters first if specified
-    let filtered_entries = if filter.min_timestamp.is_some() || filter.max_timestamp.is_some() {
-        entries
-            .iter()
-            .filter(|entry| {
-                // Get timestamp in the appropriate unit
-                let timestamp = if use_microseconds {
-                    to_microseconds(&entry.ts)
-                } else {
-                    to_milliseconds(&entry.ts)
-                };
-
-                // Log some sample timestamps for debugging
-                if filter.min_timestamp.is_some() {
-                    let min_ts = filter.min_timestamp.unwrap();
-                    log::debug!(
-                        "Comparing timestamp {} to min_timestamp {}",
-                        timestamp,
-                        min_ts
-                    );
-                }
-
-                // Check min timestamp
-                if let Some(min_ts) = filter.min_timestamp {
-                    if timestamp < min_ts {
-                        return false;
-                    }
-                }
-
-                // Check max timestamp
-                if let Some(max_ts) = filter.max_timestamp {
-                    if timestamp > max_ts {
-                        return false;
-                    }
-                }
-
-                true
-            })
-            .collect::<Vec<_>>()
-    } else {
-        entries.iter().collect::<Vec<_>>()
-    };
-
-    // Apply other filters
-    let start_time = Instant::now();
-    let filtered_entries = filtered_entries
-        .iter()
-        .filter(|entry| {
-            // Filter by level if specified
-            if let Some(ref level) = filter.level {
-                if format!("{:?}", entry.level) != *level {
-                    return false;
-                }
-            }
-
-            // Filter by categories if specified
-            if !filter.categories.is_empty() {
-                log::debug!(
-                    "Filtering by categories: {:?}, entry category: {}",
-                    filter.categories,
-                    entry.category
-                );
-                // For debugging purposes
-                let entry_bytes = entry.category.as_bytes();
-                log::debug!("Entry category as bytes: {:?}", entry_bytes);
-
-                let mut found = false;
-                for cat in &filter.categories {
-                    let cat_bytes = cat.as_bytes();
-                    log::debug!("Filter category as bytes: {:?}", cat_bytes);
-
-                    // Do various equality checks to help debug
-                    let string_eq = cat == &entry.category;
-                    let bytes_eq = cat_bytes == entry_bytes;
-                    let trim_eq = cat.trim() == entry.category.trim();
-
-                    log::debug!(
-                        "'{}' == '{}': string_eq={}, bytes_eq={}, trim_eq={}",
-                        cat,
-                        entry.category,
-                        string_eq,
-                        bytes_eq,
-                        trim_eq
-                    );
-
-                    if string_eq || bytes_eq || trim_eq {
-                        found = true;
-                        break;
-                    }
-                }
-
-                if !found {
-                    return false;
-                }
-            }
-
-            // Filter by message using regex if specified
-            if let Some(ref message_regex) = filter.message_regex {
-                if let Ok(regex) = Regex::new(message_regex) {
-                    if !regex.is_match(&entry.message) {
-                        return false;
-                    }
-                } else {
-                    // Log invalid regex but don't filter out entries
-                    log::error!("Invalid message regex: {}", message_regex);
-                }
-            }
-
-            // Filter by PID if specified
-            if let Some(pid) = filter.pid {
-                if entry.pid != pid {
-                    return false;
-                }
-            }
-
-            // Filter by thread if specified
-            if let Some(ref thread) = filter.thread {
-                if entry.thread != *thread {
-                    return false;
-                }
-            }
-
-            // Filter by object if specified
-            if let Some(ref object) = filter.object {
-                if let Some(ref entry_object) = entry.object {
-                    if entry_object != object {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            }
-
-            // Filter by function using regex if specified
-            if let Some(ref function_regex) = filter.function_regex {
-                if let Ok(regex) = Regex::new(function_regex) {
-                    if !regex.is_match(&entry.function) {
-                        return false;
-                    }
-                } else {
-                    // Log invalid regex but don't filter out entries
-                    log::error!("Invalid function regex: {}", function_regex);
-                }
-            }
-
-            true
-        })
-        .map(|entry| *entry) // Dereference to get &Entry instead of &&Entry
-        .collect::<Vec<_>>();
-
-    let filter_time = start_time.elapsed();
-    log::debug!(
-        "Filtered to {} entries in {:.2?}",
-        filtered_entries.len(),
-        filter_time
-    );
-
     // Apply pagination
     let page = filter.page.max(1);
     let per_page = filter.per_page.min(1000);
-    let total = filtered_entries.len();
-    let total_pages = (total + per_page - 1) / per_page;
-
-    let start = (page - 1) * per_page;
-    let end = (start + per_page).min(total);
-
-    log::debug!(
-        "Pagination: page {}/{}, showing entries {}-{} of {}",
-        page,
-        total_pages,
-        start + 1,
-        end,
-        total
-    );
-
-    let paginated_entries = filtered_entries
-        .into_iter()
-        .skip(start)
-        .take(end - start)
-        .map(SerializableEntry::from)
-        .collect();
+    let offset = (page - 1).saturating_mul(per_page);
+
+    let response = read_session(&state, &filter.session_id, move |session| {
+        log::debug!("Found session with {} entries", session.entries.len());
+
+        // Apply filters, only the entries of the page are read. Pages past the last one are empty.
+        let start_time = Instant::now();
+        let bound = plan.bind(session);
+        let (total, entries) = bound.page(offset, per_page)?;
+        let total_pages = total.div_ceil(per_page);
+
+        let filter_time = start_time.elapsed();
+        log::debug!("Filtered to {} entries in {:.2?}", total, filter_time);
+
+        log::debug!(
+            "Pagination: page {}/{}, showing entries {}-{} of {}",
+            page,
+            total_pages,
+            offset.min(total) + 1,
+            offset.min(total) + entries.len(),
+            total
+        );
+
+        let paginated_entries = entries
+            .iter()
+            .map(|entry| SerializableEntry::new(entry, session))
+            .collect();
+
+        Ok(crate::models::LogResponse {
+            entries: paginated_entries,
+            total,
+            page,
+            total_pages,
+        })
+    })
+    .await?;
 
-    Ok(Json(crate::models::LogResponse {
-        entries: paginated_entries,
-        total,
-        page,
-        total_pages,
-    }))
+    response.map(Json).ok_or_else(|| {
+        let msg = format!("Session not found: {}", filter.session_id);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: msg,
+        }
+    })
 }
@@ -0,0 +1,500 @@
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::Json;
+
+use crate::models::{
+    writing_error, ApiError, AppState, RejectedLinesResponse, Session, SessionInfo,
+    SessionMetadata, SessionStatus, SessionStatusResponse, SessionUpdate, SourceRejectedLines,
+};
+
+// Load the entries of a stored session into memory if they aren't already, or wait
+// for an append to put them back. Sessions that aren't stored are left to the caller
+// to report as not found.
+pub async fn ensure_loaded(state: &Arc<AppState>, session_id: &str) -> Result<(), ApiError> {
+    let loaded = state.parsed_logs.read().unwrap().contains_key(session_id);
+    if loaded {
+        state.touch(session_id);
+        return Ok(());
+    }
+
+    let task_state = state.clone();
+    let task_session_id = session_id.to_string();
+    let loaded = tokio::task::spawn_blocking(move || -> Result<Option<usize>> {
+        // Held until the session is in memory, so no copy is written meanwhile
+        let stored_version = task_state.stored_version(&task_session_id);
+        let mut stored_version = stored_version.lock().unwrap();
+        // Another request may have loaded it in the meantime
+        if task_state
+            .parsed_logs
+            .read()
+            .unwrap()
+            .contains_key(&task_session_id)
+        {
+            return Ok(None);
+        }
+
+        let store = match task_state.store {
+            Some(ref store) => store,
+            None => return Ok(None),
+        };
+        let session = match store.load(&task_session_id, task_state.new_session()?)? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        *stored_version = Some(session.version());
+        let entries = session.entries.len();
+        task_state
+            .parsed_logs
+            .write()
+            .unwrap()
+            .insert(task_session_id, session);
+        Ok(Some(entries))
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|result| result)
+    .map_err(|e| {
+        let msg = format!("Failed to load session {}: {}", session_id, e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    })?;
+
+    if let Some(entries) = loaded {
+        log::info!(
+            "Loaded stored session {} with {} entries",
+            session_id,
+            entries
+        );
+        let mut sessions = state.sessions.write().unwrap();
+        if let Some(status @ SessionStatus::Unloaded { .. }) = sessions.get_mut(session_id) {
+            *status = SessionStatus::Ready { entries };
+        }
+        drop(sessions);
+        state.touch(session_id);
+    }
+
+    Ok(())
+}
+
+// Read a loaded session with `read` on a blocking thread, through a copy sharing its
+// entries. Mapped and SQLite sessions are read from disk, the sessions aren't kept
+// locked meanwhile. Returns None if the session isn't loaded.
+pub async fn read_session<T: Send + 'static>(
+    state: &AppState,
+    session_id: &str,
+    read: impl FnOnce(&Session) -> Result<T, ApiError> + Send + 'static,
+) -> Result<Option<T>, ApiError> {
+    let snapshot = match state.parsed_logs.read().unwrap().get(session_id) {
+        Some(session) => session.snapshot(),
+        None => return Ok(None),
+    };
+    let query_error = |e: &dyn std::fmt::Display| {
+        let msg = format!("Failed to query session {}: {}", session_id, e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    };
+    let session = snapshot.ok_or_else(|| query_error(&writing_error()))?;
+
+    tokio::task::spawn_blocking(move || read(&session))
+        .await
+        .map_err(|e| query_error(&e))?
+        .map(Some)
+}
+
+// Write a session to the store, if sessions are stored. Failures are only logged,
+// the session remains usable from memory. Returns whether the session is stored.
+pub fn persist_session(state: &AppState, session_id: &str) -> bool {
+    let store = match state.store {
+        Some(ref store) => store,
+        None => return false,
+    };
+
+    // Held while writing, so an older copy never replaces a newer one
+    let stored_version = state.stored_version(session_id);
+    let mut stored_version = stored_version.lock().unwrap();
+    // The copy shares the entries, the sessions aren't locked while it's written
+    let snapshot = match state.parsed_logs.read().unwrap().get(session_id) {
+        Some(session) => session.snapshot(),
+        None => return false,
+    };
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None => return false,
+    };
+    if *stored_version == Some(snapshot.version()) {
+        return true;
+    }
+
+    match store.save(session_id, &snapshot) {
+        Ok(()) => {
+            log::debug!("Stored session {}", session_id);
+            *stored_version = Some(snapshot.version());
+            true
+        }
+        Err(e) => {
+            log::error!("Failed to store session {}: {}", session_id, e);
+            false
+        }
+    }
+}
+
+// Handler for getting the processing status of a session
+pub async fn get_session_status(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+) -> Result<Json<SessionStatusResponse>, ApiError> {
+    log::debug!("Fetching status for session: {}", session_id);
+
+    let status = state
+        .sessions
+        .read()
+        .unwrap()
+        .get(&session_id)
+        .cloned()
+        .ok_or_else(|| {
+            let msg = format!("Session not found: {}", session_id);
+            log::error!("{}", msg);
+            ApiError {
+                status: StatusCode::NOT_FOUND,
+                message: msg,
+            }
+        })?;
+
+    // Polling the progress of a stored session doesn't load it, its header is enough
+    let loaded = state
+        .parsed_logs
+        .read()
+        .unwrap()
+        .get(&session_id)
+        .map(|session| (session.metadata.clone(), session.source_summaries()));
+    let (metadata, sources) = match loaded {
+        Some((metadata, sources)) => (Some(metadata), sources),
+        None if state.store.is_some() => {
+            let task_state = state.clone();
+            let task_session_id = session_id.clone();
+            let stored = tokio::task::spawn_blocking(move || {
+                let store = task_state.store.as_ref().unwrap();
+                store.summary(&task_session_id)
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result);
+            match stored {
+                Ok(Some((metadata, sources))) => (Some(metadata), sources),
+                Ok(None) => (None, Vec::new()),
+                Err(e) => {
+                    log::warn!("Failed to read stored session {}: {}", session_id, e);
+                    (None, Vec::new())
+                }
+            }
+        }
+        None => (None, Vec::new()),
+    };
+
+    Ok(Json(SessionStatusResponse {
+        session_id,
+        status,
+        metadata,
+        sources,
+    }))
+}
+
+// Handler for getting the lines of a session that couldn't be parsed
+pub async fn get_rejected_lines(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+) -> Result<Json<RejectedLinesResponse>, ApiError> {
+    log::debug!("Fetching rejected lines for session: {}", session_id);
+
+    ensure_loaded(&state, &session_id).await?;
+
+    let logs = state.parsed_logs.read().unwrap();
+    let session = logs.get(&session_id).ok_or_else(|| {
+        let msg = format!("Session not found: {}", session_id);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: msg,
+        }
+    })?;
+
+    let sources: Vec<SourceRejectedLines> = session
+        .sources
+        .iter()
+        .map(|source| SourceRejectedLines {
+            source: source.name.clone(),
+            report: source.parse_report.clone(),
+        })
+        .collect();
+    let rejected_lines = sources
+        .iter()
+        .map(|source| source.report.rejected_lines)
+        .sum();
+
+    Ok(Json(RejectedLinesResponse {
+        session_id,
+        rejected_lines,
+        sources,
+    }))
+}
+
+// Handler for listing all sessions, newest first
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<SessionInfo>>, ApiError> {
+    let statuses: Vec<(String, SessionStatus)> = state
+        .sessions
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(session_id, status)| (session_id.clone(), status.clone()))
+        .collect();
+
+    // Sessions in memory are summarized right away, the others from their stored header
+    let mut infos = Vec::new();
+    let mut not_loaded = Vec::new();
+    {
+        let logs = state.parsed_logs.read().unwrap();
+        for (session_id, status) in statuses {
+            match logs.get(&session_id) {
+                Some(session) => infos.push(session.info(&session_id, status)),
+                None => not_loaded.push((session_id, status)),
+            }
+        }
+    }
+
+    let task_state = state.clone();
+    let stored = tokio::task::spawn_blocking(move || {
+        not_loaded
+            .into_iter()
+            .map(|(session_id, status)| {
+                let stored = task_state.store.as_ref().and_then(|store| {
+                    match store.info(&session_id, status.clone()) {
+                        Ok(info) => info,
+                        Err(e) => {
+                            log::warn!("Failed to read stored session {}: {}", session_id, e);
+                            None
+                        }
+                    }
+                });
+                // Sessions still being parsed have no entries yet
+                stored.unwrap_or_else(|| {
+                    SessionInfo::new(
+                        &session_id,
+                        status,
+                        &SessionMetadata::default(),
+                        Vec::new(),
+                        0,
+                        None,
+                    )
+                })
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|e| {
+        let msg = format!("Failed to list sessions: {}", e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    })?;
+    infos.extend(stored);
+
+    infos.sort_by_key(|info| Reverse(info.created_at));
+    Ok(Json(infos))
+}
+
+// Status of a session that can be modified, sessions being parsed are only
+// inserted once done and can't be modified meanwhile
+fn modifiable_status(state: &AppState, session_id: &str) -> Result<SessionStatus, ApiError> {
+    let loaded = state.parsed_logs.read().unwrap().contains_key(session_id);
+    match state.sessions.read().unwrap().get(session_id) {
+        Some(SessionStatus::Queued) | Some(SessionStatus::Parsing { .. }) if !loaded => {
+            let msg = format!("Session {} is still being parsed", session_id);
+            log::error!("{}", msg);
+            Err(ApiError {
+                status: StatusCode::CONFLICT,
+                message: msg,
+            })
+        }
+        Some(status) => Ok(status.clone()),
+        None => {
+            let msg = format!("Session not found: {}", session_id);
+            log::error!("{}", msg);
+            Err(ApiError {
+                status: StatusCode::NOT_FOUND,
+                message: msg,
+            })
+        }
+    }
+}
+
+// Handler for setting the name and tags of a session
+pub async fn update_session(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+    Json(update): Json<SessionUpdate>,
+) -> Result<Json<SessionInfo>, ApiError> {
+    let status = modifiable_status(&state, &session_id)?;
+
+    let task_state = state.clone();
+    let task_session_id = session_id.clone();
+    let info = tokio::task::spawn_blocking(move || {
+        apply_update(&task_state, &task_session_id, status, update)
+    })
+    .await
+    .map_err(|e| {
+        let msg = format!("Failed to update session {}: {}", session_id, e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    })??;
+    log::info!(
+        "Updated session {}: name {:?}, tags {:?}",
+        session_id,
+        info.name,
+        info.tags
+    );
+
+    Ok(Json(info))
+}
+
+// Set the name and tags of a session in memory and in the store. Only the
+// metadata of the stored copy is written, unless the copy is out of date.
+fn apply_update(
+    state: &AppState,
+    session_id: &str,
+    status: SessionStatus,
+    update: SessionUpdate,
+) -> Result<SessionInfo, ApiError> {
+    let store_error = |e: anyhow::Error| {
+        let msg = format!("Failed to update stored session {}: {}", session_id, e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    };
+
+    // Held so that the session isn't loaded or stored meanwhile
+    let stored_version = state.stored_version(session_id);
+    let mut stored_version = stored_version.lock().unwrap();
+
+    let mut logs = state.parsed_logs.write().unwrap();
+    if let Some(session) = logs.get_mut(session_id) {
+        let up_to_date = *stored_version == Some(session.version());
+        update_metadata(session.metadata_mut(), update);
+        let info = session.info(session_id, status);
+        let (metadata, version) = (session.metadata.clone(), session.version());
+        drop(logs);
+
+        let store = match state.store {
+            Some(ref store) => store,
+            None => return Ok(info),
+        };
+        if !up_to_date {
+            drop(stored_version);
+            persist_session(state, session_id);
+            return Ok(info);
+        }
+        // The session remains usable from memory, it's stored again with its next change
+        match store.save_metadata(session_id, &metadata) {
+            Ok(()) => *stored_version = Some(version),
+            Err(e) => log::error!("Failed to store session {}: {}", session_id, e),
+        }
+        return Ok(info);
+    }
+    drop(logs);
+
+    // Unloaded sessions are only in the store
+    let not_found = || {
+        let msg = format!("Session not found: {}", session_id);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: msg,
+        }
+    };
+    let store = state.store.as_ref().ok_or_else(not_found)?;
+    let (mut metadata, _) = store
+        .summary(session_id)
+        .map_err(store_error)?
+        .ok_or_else(not_found)?;
+    update_metadata(&mut metadata, update);
+    store
+        .save_metadata(session_id, &metadata)
+        .map_err(store_error)?;
+    store
+        .info(session_id, status)
+        .map_err(store_error)?
+        .ok_or_else(not_found)
+}
+
+fn update_metadata(metadata: &mut SessionMetadata, update: SessionUpdate) {
+    if let Some(name) = update.name {
+        let name = name.trim();
+        metadata.name = (!name.is_empty()).then(|| name.to_string());
+    }
+    if let Some(tags) = update.tags {
+        let mut tags: Vec<String> = tags
+            .iter()
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        metadata.tags = tags;
+    }
+}
+
+// Handler for deleting a session, along with its stored copy.
+// Live sessions stop following their file or stream.
+pub async fn delete_session(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    modifiable_status(&state, &session_id)?;
+
+    let task_state = state.clone();
+    let task_session_id = session_id.clone();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        // Held so that the session isn't stored again meanwhile
+        let stored_version = task_state.stored_version(&task_session_id);
+        let _stored_version = stored_version.lock().unwrap();
+        let session = task_state.remove_session(&task_session_id);
+        if let Some(ref store) = task_state.store {
+            store.remove(&task_session_id)?;
+        }
+        // Large sessions take a while to free
+        drop(session);
+        Ok(())
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|result| result)
+    .map_err(|e| {
+        let msg = format!("Failed to remove stored session {}: {}", session_id, e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    })?;
+
+    log::info!("Deleted session {}", session_id);
+    Ok(StatusCode::NO_CONTENT)
+}
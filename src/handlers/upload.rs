@@ -1,166 +1,627 @@
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::Write;
-use std::path::Path;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{self, PathBuf};
 use std::sync::Arc;
 use std::time::Instant;
 
 use anyhow::Result;
-use axum::extract::{Multipart, State};
+use axum::extract::multipart::Field;
+use axum::extract::{Multipart, Path, State};
 use axum::http::StatusCode;
 use axum::response::Json;
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
-use crate::models::{ApiError, AppState};
+use crate::config::Config;
+use crate::handlers::sessions::{ensure_loaded, persist_session};
+use crate::models::{ApiError, AppState, Session, SessionStatus, SkippedFile};
 use crate::parser;
-use crate::parser::Entry;
+use crate::parser::{LogFormat, ParseReport, ParsedEntry};
+
+// A file received through a multipart upload, waiting to be parsed
+pub struct UploadedFile {
+    // Original file name, used as the source of its entries
+    pub name: String,
+    // Location of the temporary copy
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+// How the entries of a parse task end up in the app state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    // The files make up a new session
+    Create,
+    // The entries are added to an existing session
+    Append,
+}
 
 // Handler for log file uploads
 pub async fn upload_log(
     State(state): State<Arc<AppState>>,
-    mut multipart: Multipart,
+    multipart: Multipart,
 ) -> Result<Json<HashMap<String, String>>, ApiError> {
     // Generate a unique session ID for this upload
     let session_id = Uuid::new_v4().to_string();
-    let temp_path = state.temp_dir.path().join(&session_id);
 
     log::info!("Starting upload for session: {}", session_id);
 
-    // Extract and save the uploaded file
-    while let Some(field) = multipart.next_field().await.map_err(|e| {
-        let msg = format!("Failed to read multipart form: {}", e);
-        log::error!("{}", msg);
-        ApiError {
-            status: StatusCode::BAD_REQUEST,
-            message: msg,
+    let files = receive_files(&state, multipart).await?;
+
+    // Parse log files in a blocking task to avoid blocking the async runtime
+    log::info!(
+        "Received {} file(s) for session {}",
+        files.len(),
+        session_id
+    );
+    state.set_status(&session_id, SessionStatus::Queued);
+    spawn_parse_task(files, session_id.clone(), state.clone(), ParseMode::Create);
+
+    Ok(Json(HashMap::from([(
+        "session_id".to_string(),
+        session_id,
+    )])))
+}
+
+// Handler for adding log files to an existing session
+pub async fn append_log(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+    multipart: Multipart,
+) -> Result<Json<HashMap<String, String>>, ApiError> {
+    log::info!("Starting append for session: {}", session_id);
+
+    // Stored sessions must be in memory for the new entries to be merged in
+    ensure_loaded(&state, &session_id).await?;
+
+    // Only sessions that are done parsing can receive more entries
+    match state.sessions.read().unwrap().get(&session_id) {
+        Some(SessionStatus::Ready { .. }) => {}
+        Some(status) => {
+            let msg = format!(
+                "Session {} can't be appended to in its current state: {:?}",
+                session_id, status
+            );
+            log::error!("{}", msg);
+            return Err(ApiError {
+                status: StatusCode::CONFLICT,
+                message: msg,
+            });
+        }
+        None => {
+            let msg = format!("Session not found: {}", session_id);
+            log::error!("{}", msg);
+            return Err(ApiError {
+                status: StatusCode::NOT_FOUND,
+                message: msg,
+            });
         }
-    })? {
+    }
+
+    let files = receive_files(&state, multipart).await?;
+
+    log::info!(
+        "Received {} file(s) to append to session {}",
+        files.len(),
+        session_id
+    );
+    state.set_status(&session_id, SessionStatus::Queued);
+    spawn_parse_task(files, session_id.clone(), state.clone(), ParseMode::Append);
+
+    Ok(Json(HashMap::from([(
+        "session_id".to_string(),
+        session_id,
+    )])))
+}
+
+// Extract and save every file of a multipart upload to the temporary directory
+async fn receive_files(
+    state: &AppState,
+    mut multipart: Multipart,
+) -> Result<Vec<UploadedFile>, ApiError> {
+    // Temporary files are named after the upload, several uploads may target the same session
+    let upload_id = Uuid::new_v4().to_string();
+    let mut files: Vec<UploadedFile> = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                remove_files(&files);
+                let msg = format!("Failed to read multipart form: {}", e);
+                log::error!("{}", msg);
+                return Err(ApiError {
+                    status: StatusCode::BAD_REQUEST,
+                    message: msg,
+                });
+            }
+        };
+
         let field_name = field.name().unwrap_or("unnamed").to_string();
         let content_type = field
             .content_type()
             .unwrap_or("application/octet-stream")
             .to_string();
+        let file_name = field
+            .file_name()
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| format!("{}-{}", field_name, files.len() + 1));
 
         log::debug!(
-            "Processing field '{}' with type '{}'",
+            "Processing field '{}' ({}) with type '{}'",
             field_name,
+            file_name,
             content_type
         );
 
-        // Get file data
-        let data = field.bytes().await.map_err(|e| {
-            let msg = format!("Failed to read field data: {}", e);
-            log::error!("{}", msg);
-            ApiError {
-                status: StatusCode::BAD_REQUEST,
-                message: msg,
+        // Stream the file data to a temporary file
+        let temp_path = state
+            .temp_dir
+            .path()
+            .join(format!("{}-{}", upload_id, files.len()));
+        let received: u64 = files.iter().map(|file| file.size).sum();
+        let size = match save_field(field, &temp_path, state.config.max_upload_size, received).await
+        {
+            Ok(size) => size,
+            Err(e) => {
+                remove_files(&files);
+                return Err(e);
             }
-        })?;
+        };
 
-        log::debug!("Received file data of size: {} bytes", data.len());
+        log::debug!("Received file data of size: {} bytes", size);
+        log::debug!("File written to temporary path: {}", temp_path.display());
 
-        // Create and write to temporary file
-        let mut file = File::create(&temp_path).map_err(|e| {
-            let msg = format!("Failed to create temporary file: {}", e);
-            log::error!("{}", msg);
-            ApiError {
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-                message: msg,
+        files.push(UploadedFile {
+            name: file_name,
+            path: temp_path,
+            size,
+        });
+    }
+
+    // Without a file there is nothing to parse and the session would never become ready
+    if files.is_empty() {
+        let msg = "Upload did not contain a file".to_string();
+        log::error!("{}", msg);
+        return Err(ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: msg,
+        });
+    }
+
+    Ok(files)
+}
+
+// Remove the temporary copies of uploaded files
+fn remove_files(files: &[UploadedFile]) {
+    for file in files {
+        if let Err(e) = fs::remove_file(&file.path) {
+            log::error!("Error removing temporary file: {}", e);
+        } else {
+            log::debug!("Removed temporary file: {}", file.path.display());
+        }
+    }
+}
+
+// Write a multipart field to disk chunk by chunk, rejecting it once the whole upload
+// (`received` bytes of previous fields plus this one) exceeds `max_size` bytes
+async fn save_field(
+    mut field: Field<'_>,
+    path: &path::Path,
+    max_size: u64,
+    received: u64,
+) -> Result<u64, ApiError> {
+    let mut file = tokio::fs::File::create(path).await.map_err(|e| {
+        let msg = format!("Failed to create temporary file: {}", e);
+        log::error!("{}", msg);
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: msg,
+        }
+    })?;
+
+    let mut written: u64 = 0;
+    let result = loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break Ok(written),
+            Err(e) => {
+                break Err(ApiError {
+                    status: StatusCode::BAD_REQUEST,
+                    message: format!("Failed to read field data: {}", e),
+                })
             }
-        })?;
+        };
 
-        file.write_all(&data).map_err(|e| {
-            let msg = format!("Failed to write data to temporary file: {}", e);
-            log::error!("{}", msg);
-            ApiError {
+        written += chunk.len() as u64;
+        if received + written > max_size {
+            break Err(ApiError {
+                status: StatusCode::PAYLOAD_TOO_LARGE,
+                message: format!("Upload exceeds the maximum size of {} bytes", max_size),
+            });
+        }
+
+        if let Err(e) = file.write_all(&chunk).await {
+            break Err(ApiError {
                 status: StatusCode::INTERNAL_SERVER_ERROR,
-                message: msg,
-            }
-        })?;
+                message: format!("Failed to write data to temporary file: {}", e),
+            });
+        }
+    };
 
-        log::debug!("File written to temporary path: {}", temp_path.display());
+    let result = match result {
+        Ok(written) => file.flush().await.map(|_| written).map_err(|e| ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: format!("Failed to write data to temporary file: {}", e),
+        }),
+        Err(e) => Err(e),
+    };
+
+    // Don't leave partial uploads behind
+    if let Err(ref e) = result {
+        log::error!("{}", e.message);
+        drop(file);
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            log::error!("Error removing partial upload: {}", e);
+        }
+    }
 
-        // Parse log file in a blocking task to avoid blocking the async runtime
-        let session_id_clone = session_id.clone();
-        let temp_path_clone = temp_path.clone();
-        let state_clone = state.clone();
+    result
+}
+
+// Run the parser on a blocking thread and record the outcome in the session status
+fn spawn_parse_task(
+    files: Vec<UploadedFile>,
+    session_id: String,
+    state: Arc<AppState>,
+    mode: ParseMode,
+) {
+    let task_session_id = session_id.clone();
+    let task_state = state.clone();
+    let handle = tokio::task::spawn_blocking(move || {
+        let result = parse_log_files(&files, &task_session_id, &task_state)
+            .and_then(|session| store_session(&task_state, &task_session_id, session, mode));
+        remove_files(&files);
+        if result.is_ok() {
+            persist_session(&task_state, &task_session_id);
+        }
+        result
+    });
 
-        tokio::task::spawn_blocking(move || {
-            let result = parse_log_file(temp_path_clone, session_id_clone, state_clone);
-            if let Err(e) = &result {
+    tokio::spawn(async move {
+        let status = match handle.await {
+            Ok(Ok(entries)) => SessionStatus::Ready { entries },
+            Ok(Err(e)) if mode == ParseMode::Append => {
+                log::error!("Error appending to session {}: {}", session_id, e);
+                // Entries already in the session are untouched, it remains usable
+                let mut logs = state.parsed_logs.write().unwrap();
+                match logs.get_mut(&session_id) {
+                    Some(session) => {
+                        session.metadata_mut().append_errors.push(e.to_string());
+                        SessionStatus::Ready {
+                            entries: session.entries.len(),
+                        }
+                    }
+                    None => {
+                        log::info!("Session {} was deleted while appending", session_id);
+                        return;
+                    }
+                }
+            }
+            Ok(Err(e)) => {
                 log::error!("Error parsing log file: {}", e);
+                SessionStatus::Failed {
+                    error: e.to_string(),
+                }
             }
-            result
-        });
+            Err(e) => {
+                log::error!("Parser task for session {} aborted: {}", session_id, e);
+                SessionStatus::Failed {
+                    error: format!("Parser task aborted: {}", e),
+                }
+            }
+        };
+        state.set_status(&session_id, status);
+    });
+}
+
+// Number of bytes read between two progress updates
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+// Reader wrapper that reports how many bytes have been consumed so far
+struct ProgressReader<R, F> {
+    inner: R,
+    bytes_read: u64,
+    last_report: u64,
+    on_progress: F,
+}
+
+impl<R: Read, F: FnMut(u64)> ProgressReader<R, F> {
+    fn new(inner: R, on_progress: F) -> Self {
+        ProgressReader {
+            inner,
+            bytes_read: 0,
+            last_report: 0,
+            on_progress,
+        }
+    }
+}
 
-        // For simplicity, we only process the first field
-        break;
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n as u64;
+        if n == 0 || self.bytes_read - self.last_report >= PROGRESS_INTERVAL {
+            self.last_report = self.bytes_read;
+            (self.on_progress)(self.bytes_read);
+        }
+        Ok(n)
     }
+}
 
-    Ok(Json(HashMap::from([(
-        "session_id".to_string(),
+// Archives need to be seekable, progress keeps counting the bytes actually read
+impl<R: Seek, F> Seek for ProgressReader<R, F> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+// Parse every uploaded file into a session with their entries merged
+pub fn parse_log_files(
+    files: &[UploadedFile],
+    session_id: &str,
+    state: &AppState,
+) -> Result<Session, anyhow::Error> {
+    let total_bytes: u64 = files.iter().map(|file| file.size).sum();
+    let mut session = state.new_session()?;
+    session.metadata.size_bytes = total_bytes;
+    let mut done_bytes = 0;
+
+    state.set_status(
         session_id,
-    )])))
+        SessionStatus::Parsing {
+            bytes_read: 0,
+            total_bytes,
+        },
+    );
+
+    for file in files {
+        parse_log_file(
+            file,
+            &mut session,
+            session_id,
+            &state.config,
+            |bytes_read| {
+                state.set_status(
+                    session_id,
+                    SessionStatus::Parsing {
+                        // Archive headers may be read more than once
+                        bytes_read: done_bytes + bytes_read.min(file.size),
+                        total_bytes,
+                    },
+                );
+            },
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", file.name, e))?;
+        done_bytes += file.size;
+    }
+
+    session.finish_entries()?;
+    Ok(session)
+}
+
+// Store the parsed entries in the app state, returning the number of entries in the session
+fn store_session(
+    state: &AppState,
+    session_id: &str,
+    session: Session,
+    mode: ParseMode,
+) -> Result<usize, anyhow::Error> {
+    let count = match mode {
+        ParseMode::Create => {
+            let count = session.entries.len();
+            let mut logs = state.parsed_logs.write().unwrap();
+            logs.insert(session_id.to_string(), session);
+            log::debug!("Current sessions in state: {}", logs.len());
+            count
+        }
+        ParseMode::Append => append_session(state, session_id, session)?,
+    };
+    log::debug!("Stored parsed entries in state for session: {}", session_id);
+
+    Ok(count)
 }
 
-// Parse the log file and store the entries in the app state
+// Add the entries of a parsed session to an existing one, returning the number of
+// entries of the existing session. It's taken out of the sessions while the entries
+// are added, so that other sessions remain usable meanwhile.
+fn append_session(
+    state: &AppState,
+    session_id: &str,
+    session: Session,
+) -> Result<usize, anyhow::Error> {
+    // Held so that the session isn't loaded again, stored or removed while it's out
+    let stored_version = state.stored_version(session_id);
+    let _stored_version = stored_version.lock().unwrap();
+    let status = state.sessions.read().unwrap().get(session_id).cloned();
+    let mut existing = state
+        .parsed_logs
+        .write()
+        .unwrap()
+        .remove(session_id)
+        .ok_or_else(|| anyhow::anyhow!("Session {} no longer exists", session_id))?;
+
+    // A failed append leaves the session as it was, it's put back either way
+    let result = existing.append(session);
+    let count = existing.entries.len();
+
+    let mut logs = state.parsed_logs.write().unwrap();
+    let sessions = state.sessions.read().unwrap();
+    let unchanged = !logs.contains_key(session_id) && sessions.get(session_id) == status.as_ref();
+    if !unchanged {
+        return Err(anyhow::anyhow!(
+            "Session {} changed while appending",
+            session_id
+        ));
+    }
+    logs.insert(session_id.to_string(), existing);
+    drop(sessions);
+    drop(logs);
+
+    result?;
+    Ok(count)
+}
+
+// Number of bytes looked at to detect the format of a log
+const SNIFF_SIZE: u64 = 64 * 1024;
+
+// Parse a single uploaded file into the session, reporting progress in bytes read from it.
+// Archives add one source per member that looks like a GStreamer log.
 pub fn parse_log_file(
-    path: impl AsRef<Path>,
-    session_id: String,
-    state: Arc<AppState>,
+    file: &UploadedFile,
+    session: &mut Session,
+    session_id: &str,
+    config: &Config,
+    on_progress: impl FnMut(u64),
 ) -> Result<(), anyhow::Error> {
-    log::info!("Parsing log file for session {}", session_id);
+    log::info!(
+        "Parsing log file {} for session {}",
+        file.path.display(),
+        session_id
+    );
     let start_time = Instant::now();
 
     // Open the file and parse it
-    let file = File::open(&path)?;
-    let file_size = fs::metadata(&path)?.len();
-    log::debug!("Opened file with size: {} bytes", file_size);
+    let mut reader = ProgressReader::new(File::open(&file.path)?, on_progress);
+    log::debug!("Opened file with size: {} bytes", file.size);
+
+    match parser::detect_archive(&mut reader)? {
+        Some(kind) => {
+            log::info!("{} is a {:?} archive, parsing its members", file.name, kind);
+            parser::for_each_member(kind, reader, |name, member| {
+                let name = format!("{}/{}", file.name, name);
+
+                // Members may be compressed on their own
+                let mut member = parser::decompress(member)?;
+                let mut sample = Vec::new();
+                member.by_ref().take(SNIFF_SIZE).read_to_end(&mut sample)?;
 
-    let entries: Vec<Entry> = parser::parse(file).collect();
-    let elapsed = start_time.elapsed();
+                let format = match parser::detect_format(&sample) {
+                    Some(format) => format,
+                    None => {
+                        log::info!("Skipping archive member {}: not a GStreamer log", name);
+                        session.metadata.skipped_files.push(SkippedFile {
+                            name,
+                            reason: "Not a GStreamer debug log".to_string(),
+                        });
+                        return Ok(());
+                    }
+                };
+
+                parse_entries(
+                    Cursor::new(sample).chain(member),
+                    session,
+                    name,
+                    format,
+                    config,
+                )?;
+                Ok(())
+            })?;
+        }
+        None => {
+            // Compressed logs are decoded while parsing, they never hit the disk uncompressed
+            let mut reader = parser::decompress(reader)?;
+            let mut sample = Vec::new();
+            reader.by_ref().take(SNIFF_SIZE).read_to_end(&mut sample)?;
+
+            // Uploaded files are parsed even in an unknown format, so their lines get reported
+            let format = parser::detect_format(&sample).unwrap_or_else(|| {
+                log::warn!("Couldn't detect the format of {}", file.name);
+                parser::DEFAULT_FORMAT
+            });
+            log::info!("{} is a {} log", file.name, format.name());
+
+            parse_entries(
+                Cursor::new(sample).chain(reader),
+                session,
+                file.name.clone(),
+                format,
+                config,
+            )?;
+        }
+    }
 
     log::info!(
-        "Parsed {} entries for session {} in {:.2?}",
-        entries.len(),
+        "Parsed {} for session {} in {:.2?}",
+        file.name,
         session_id,
-        elapsed
+        start_time.elapsed()
     );
 
-    if entries.is_empty() {
-        log::warn!(
-            "No entries were parsed from the log file. This might indicate an incorrect format."
-        );
-    } else {
-        // Sample the first few entries to help with debugging
-        log::debug!("Sample entries (up to 3):");
-        for (i, entry) in entries.iter().take(3).enumerate() {
-            log::debug!(
-                "  Entry {}: {} | {}:{} | {} | {:?}",
-                i + 1,
-                entry.ts,
-                entry.file,
-                entry.line,
-                entry.category,
-                entry.level
-            );
+    Ok(())
+}
+
+// Parse all entries from a log stream into a new source of the session.
+// Entries are added in batches when the session is written to files.
+// Fails if the stream can't be read to its end, e.g. for a corrupt compressed file.
+fn parse_entries(
+    reader: impl Read,
+    session: &mut Session,
+    name: String,
+    format: &'static dyn LogFormat,
+    config: &Config,
+) -> std::io::Result<()> {
+    let source = session.add_source(name.clone(), Some(format.name()), ParseReport::default());
+    let batch_size = session.entries.batch_size();
+    let mut parser = parser::parse(reader, format, config.fallback_encoding);
+    let mut count = 0;
+    loop {
+        let entries: Vec<ParsedEntry> = parser.by_ref().take(batch_size).collect();
+        if entries.is_empty() {
+            break;
+        }
+        if count == 0 {
+            // Sample the first few entries to help with debugging
+            log::debug!("Sample entries (up to 3):");
+            for (i, entry) in entries.iter().map(|entry| &entry.entry).take(3).enumerate() {
+                log::debug!(
+                    "  Entry {}: {} | {}:{} | {} | {:?}",
+                    i + 1,
+                    entry.ts,
+                    entry.file,
+                    entry.line,
+                    entry.category,
+                    entry.level
+                );
+            }
         }
+        count += entries.len();
+        session.add_entries(source, entries)?;
     }
+    let report = parser.into_report()?;
+    log::info!("Parsed {} entries from {}", count, name);
 
-    // Store the parsed entries
-    {
-        let mut logs = state.parsed_logs.write().unwrap();
-        logs.insert(session_id.clone(), entries);
-        log::debug!("Stored parsed entries in state for session: {}", session_id);
-        log::debug!("Current sessions in state: {}", logs.len());
+    if report.rejected_lines > 0 {
+        log::warn!(
+            "{} lines of {} couldn't be parsed and were skipped",
+            report.rejected_lines,
+            name
+        );
     }
-
-    // Clean up the temporary file
-    if let Err(e) = fs::remove_file(&path) {
-        log::error!("Error removing temporary file: {}", e);
-    } else {
-        log::debug!("Removed temporary file: {}", path.as_ref().display());
+    if report.lossy_lines > 0 {
+        log::warn!(
+            "{} lines of {} weren't valid UTF-8 and were decoded lossily",
+            report.lossy_lines,
+            name
+        );
+    }
+    if count == 0 {
+        log::warn!(
+            "No entries were parsed from the log file. This might indicate an incorrect format."
+        );
     }
 
+    session.record_rejected_lines(source, report);
     Ok(())
 }
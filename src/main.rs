@@ -1,24 +1,36 @@
+mod config;
+mod eviction;
+mod filter;
 mod handlers;
 mod models;
 mod parser;
+mod storage;
 
 use std::collections::HashMap;
 use std::env;
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 
 use anyhow::Result;
-use axum::routing::{get, post};
 use axum::extract::DefaultBodyLimit;
+use axum::routing::{get, patch, post};
 use axum::Router;
 use tempfile::TempDir;
 use tower_http::{cors::CorsLayer, services::ServeDir};
 
+use config::{Config, SessionBackend};
+
+use handlers::live::{create_ingest_session, ingest_stream, live_entries, start_tail};
 use handlers::options::get_filter_options;
 use handlers::query::get_logs;
+use handlers::sessions::{
+    delete_session, get_rejected_lines, get_session_status, list_sessions, update_session,
+};
 use handlers::timeline::get_timeline;
-use handlers::upload::upload_log;
-use models::AppState;
+use handlers::upload::{append_log, upload_log};
+use models::{AppState, SessionStatus};
+use storage::SessionStore;
 
 fn get_storage_dir() -> Result<TempDir> {
     // Check if running in Cloudron environment
@@ -51,21 +63,122 @@ async fn main() -> Result<()> {
     // Get storage directory
     let temp_dir = get_storage_dir().expect("Failed to create storage directory");
 
+    let config = Config::from_env();
+    log::info!("Maximum upload size: {} bytes", config.max_upload_size);
+    if let Some(encoding) = config.fallback_encoding {
+        log::info!(
+            "Lines that aren't valid UTF-8 are decoded as {}",
+            encoding.name()
+        );
+    }
+    match config.tail_dir {
+        Some(ref tail_dir) => log::info!("Files under {} can be tailed", tail_dir.display()),
+        None => log::info!("Tailing files is disabled"),
+    }
+
+    let store = match config.session_backend {
+        // Sessions only survive restarts when the database is in a persistent location
+        SessionBackend::Sqlite => {
+            let path = config
+                .sqlite_path
+                .clone()
+                .or_else(|| config.data_dir.as_ref().map(|dir| dir.join("sessions.db")))
+                .unwrap_or_else(|| temp_dir.path().join("sessions.db"));
+            Some(SessionStore::open_sqlite(&path).expect("Failed to open session database"))
+        }
+        SessionBackend::Memory | SessionBackend::Mapped => {
+            config.data_dir.as_ref().map(|data_dir| {
+                SessionStore::open(data_dir.clone())
+                    .expect("Failed to create session storage directory")
+            })
+        }
+    };
+    // Stored sessions are listed right away, their entries are loaded when first accessed
+    let mut sessions = HashMap::new();
+    match store {
+        Some(ref store) => {
+            let stored = store.list().expect("Failed to list stored sessions");
+            log::info!(
+                "Storing sessions in {}, {} sessions found",
+                store.path().display(),
+                stored.len()
+            );
+            for (session_id, entries) in stored {
+                sessions.insert(session_id, SessionStatus::Unloaded { entries });
+            }
+        }
+        None => log::info!("Sessions are kept in memory only, set DATA_DIR to store them"),
+    }
+
+    // Files of mapped sessions are only needed while the server runs
+    let mapped_dir = match config.session_backend {
+        SessionBackend::Memory | SessionBackend::Sqlite => None,
+        SessionBackend::Mapped => {
+            let parent = config.mapped_dir.clone().unwrap_or_else(env::temp_dir);
+            std::fs::create_dir_all(&parent).expect("Failed to create mapped sessions directory");
+            let dir = tempfile::Builder::new()
+                .prefix("mapped-")
+                .tempdir_in(&parent)
+                .expect("Failed to create mapped sessions directory");
+            log::info!(
+                "Keeping session entries in files under {}",
+                dir.path().display()
+            );
+            Some(dir)
+        }
+    };
+
+    // Stored sessions count as used at startup, for eviction
+    let last_access = sessions
+        .keys()
+        .map(|session_id| (session_id.clone(), Instant::now()))
+        .collect();
+
     // Create the shared application state
     let state = Arc::new(AppState {
+        config,
         parsed_logs: RwLock::new(HashMap::new()),
+        sessions: RwLock::new(sessions),
+        live_subscribers: Mutex::new(HashMap::new()),
         temp_dir,
+        store,
+        stored_versions: Mutex::new(HashMap::new()),
+        last_access: Mutex::new(last_access),
+        mapped_dir,
     });
 
+    if state.config.session_ttl.is_some() || state.config.memory_budget.is_some() {
+        tokio::spawn(eviction::run(state.clone()));
+    }
+
     // Build our application with routes
+    // Uploads are streamed to disk and their size is checked by the upload handler,
+    // other requests keep the default body limit
     let app = Router::new()
-        .route("/api/upload", post(upload_log))
+        .route(
+            "/api/upload",
+            post(upload_log).layer(DefaultBodyLimit::disable()),
+        )
         .route("/api/logs", get(get_logs))
         .route("/api/timeline", get(get_timeline))
         .route("/api/filter-options", get(get_filter_options))
+        .route("/api/sessions", get(list_sessions))
+        .route(
+            "/api/sessions/:id",
+            patch(update_session).delete(delete_session),
+        )
+        .route("/api/sessions/:id/status", get(get_session_status))
+        .route(
+            "/api/sessions/:id/append",
+            post(append_log).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/api/sessions/:id/rejected-lines", get(get_rejected_lines))
+        .route("/api/tail", post(start_tail))
+        .route("/api/live", get(live_entries))
+        .route("/api/ingest", post(create_ingest_session))
+        .route("/api/sessions/:id/ingest", post(ingest_stream))
         .nest_service("/", ServeDir::new("frontend/dist"))
         .layer(CorsLayer::permissive())
-        .layer(DefaultBodyLimit::max(500 * 1024 * 1024)) // Set max body limit to 500MB
         .with_state(state);
 
     // Run our application with hyper
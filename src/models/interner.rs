@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// ID of a string interned in a session, the same string always gets the same ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    // Position of the string in the interner
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    // Symbol of the string at a position, for symbols read back from files
+    pub fn from_index(index: usize) -> Self {
+        Symbol(index as u32)
+    }
+}
+
+// Strings shared by the entries of a session.
+// Threads, categories, files, functions and objects take a few hundred distinct
+// values over millions of entries, so entries only store their symbols.
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+    symbols: HashMap<Arc<str>, Symbol>,
+    strings: Vec<Arc<str>>,
+    // Estimate of the memory used by the strings and the lookup table, in bytes
+    memory_size: usize,
+}
+
+impl Interner {
+    // Symbol of a string, interning it if it's new
+    pub fn intern(&mut self, string: &str) -> Symbol {
+        if let Some(&symbol) = self.symbols.get(string) {
+            return symbol;
+        }
+
+        let symbol = Symbol(self.strings.len() as u32);
+        let string: Arc<str> = Arc::from(string);
+        self.memory_size += string.len()
+            + std::mem::size_of::<Arc<str>>() * 2
+            + std::mem::size_of::<Symbol>()
+            // Reference counts of the shared allocation
+            + std::mem::size_of::<usize>() * 2;
+        self.symbols.insert(string.clone(), symbol);
+        self.strings.push(string);
+        symbol
+    }
+
+    // Symbol of a string, if an entry uses it
+    pub fn get(&self, string: &str) -> Option<Symbol> {
+        self.symbols.get(string).copied()
+    }
+
+    pub fn resolve(&self, symbol: Symbol) -> &str {
+        &self.strings[symbol.index()]
+    }
+
+    // All interned strings along with their symbol
+    pub fn iter(&self) -> impl Iterator<Item = (Symbol, &str)> {
+        self.strings
+            .iter()
+            .enumerate()
+            .map(|(index, string)| (Symbol(index as u32), string.as_ref()))
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+}
@@ -0,0 +1,219 @@
+use std::ops::Index;
+use std::sync::Arc;
+
+use super::SessionEntry;
+
+// Entries of a session kept in memory, ordered by timestamp.
+// They are split in chunks shared with the copies of the session being stored, so
+// adding entries only copies the chunks from the first one the new entries go into,
+// usually none of them for a growing log.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryEntries {
+    chunks: Vec<Chunk>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Chunk {
+    entries: Arc<Vec<SessionEntry>>,
+    // Position of the first entry of the chunk
+    start: usize,
+    // Latest timestamp of the entries of the chunk
+    max_ts: u64,
+}
+
+impl MemoryEntries {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Add entries, they go after those with the same timestamp
+    pub fn insert(&mut self, mut entries: Vec<SessionEntry>) {
+        // Lines logged by several threads aren't strictly ordered, the sort is stable
+        // so those with the same timestamp stay in the order they were written
+        entries.sort_by_key(|entry| entry.ts);
+        let first_ts = match entries.first() {
+            Some(entry) => entry.ts,
+            None => return,
+        };
+        // Chunks whose entries all come before the new ones stay as they are
+        let first_chunk = self
+            .chunks
+            .iter()
+            .position(|chunk| chunk.max_ts > first_ts)
+            .unwrap_or(self.chunks.len());
+        let following: Vec<SessionEntry> = self
+            .chunks
+            .drain(first_chunk..)
+            .flat_map(|chunk| Arc::unwrap_or_clone(chunk.entries))
+            .collect();
+        self.len -= following.len();
+        self.push(merge_by_timestamp(following, entries));
+    }
+
+    // Add entries that go after all others, to the last chunk if it isn't shared
+    fn push(&mut self, entries: Vec<SessionEntry>) {
+        let max_ts = entries.iter().map(|entry| entry.ts).max().unwrap_or(0);
+        let len = self.len;
+        self.len += entries.len();
+        if let Some(chunk) = self.chunks.last_mut() {
+            if let Some(last) = Arc::get_mut(&mut chunk.entries) {
+                last.extend(entries);
+                chunk.max_ts = chunk.max_ts.max(max_ts);
+                return;
+            }
+        }
+        self.chunks.push(Chunk {
+            entries: Arc::new(entries),
+            start: len,
+            max_ts,
+        });
+    }
+
+    // All entries, ordered by timestamp
+    pub fn into_entries(self) -> impl Iterator<Item = SessionEntry> {
+        self.chunks
+            .into_iter()
+            .flat_map(|chunk| Arc::unwrap_or_clone(chunk.entries))
+    }
+}
+
+impl Index<usize> for MemoryEntries {
+    type Output = SessionEntry;
+
+    fn index(&self, position: usize) -> &SessionEntry {
+        let chunk = &self.chunks[self.chunks.partition_point(|chunk| chunk.start <= position) - 1];
+        &chunk.entries[position - chunk.start]
+    }
+}
+
+// Merge two lists of entries sorted by timestamp.
+// Entries of `a` go before those of `b` with the same timestamp.
+fn merge_by_timestamp(a: Vec<SessionEntry>, b: Vec<SessionEntry>) -> Vec<SessionEntry> {
+    if a.is_empty() {
+        return b;
+    }
+    if b.is_empty() {
+        return a;
+    }
+    // Entries of a growing log usually come after everything already stored
+    if a[a.len() - 1].ts <= b[0].ts {
+        let mut a = a;
+        a.extend(b);
+        return a;
+    }
+
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let mut a = a.into_iter().peekable();
+    let mut b = b.into_iter().peekable();
+    loop {
+        let take_a = match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) => x.ts <= y.ts,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+        };
+        let next = if take_a { a.next() } else { b.next() };
+        merged.extend(next);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Interner;
+    use gstreamer::DebugLevel;
+
+    fn entries(strings: &mut Interner, timestamps: &[u64], message: &str) -> Vec<SessionEntry> {
+        timestamps
+            .iter()
+            .map(|&ts| SessionEntry::for_test(strings, ts, "test", DebugLevel::Info, message))
+            .collect()
+    }
+
+    fn contents(entries: &MemoryEntries) -> Vec<(u64, String)> {
+        (0..entries.len())
+            .map(|position| {
+                let entry = &entries[position];
+                (entry.ts, entry.message.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn appends_without_copying_shared_chunks() {
+        let mut strings = Interner::default();
+        let mut memory = MemoryEntries::default();
+        memory.insert(entries(&mut strings, &[1, 2], "a"));
+        memory.insert(entries(&mut strings, &[3], "b"));
+        assert_eq!(memory.chunks.len(), 1);
+
+        let snapshot = memory.clone();
+        memory.insert(entries(&mut strings, &[4, 5], "c"));
+        assert_eq!(memory.chunks.len(), 2);
+        assert!(Arc::ptr_eq(
+            &memory.chunks[0].entries,
+            &snapshot.chunks[0].entries
+        ));
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(
+            contents(&memory),
+            [(1, "a"), (2, "a"), (3, "b"), (4, "c"), (5, "c")]
+                .map(|(ts, message)| (ts, message.to_string()))
+        );
+    }
+
+    #[test]
+    fn merges_from_the_first_chunk_entries_go_into() {
+        let mut strings = Interner::default();
+        let mut memory = MemoryEntries::default();
+        memory.insert(entries(&mut strings, &[1, 3], "a"));
+        let first = memory.clone();
+        memory.insert(entries(&mut strings, &[5, 7], "b"));
+        let second = memory.clone();
+
+        // Goes between the entries of the second chunk, the first one is kept
+        memory.insert(entries(&mut strings, &[5, 6], "c"));
+        assert!(Arc::ptr_eq(
+            &memory.chunks[0].entries,
+            &first.chunks[0].entries
+        ));
+        assert_eq!(
+            contents(&memory),
+            [(1, "a"), (3, "a"), (5, "b"), (5, "c"), (6, "c"), (7, "b")]
+                .map(|(ts, message)| (ts, message.to_string()))
+        );
+        assert_eq!(contents(&second).len(), 4);
+
+        // Goes before everything
+        memory.insert(entries(&mut strings, &[0], "d"));
+        assert_eq!(memory.len(), 7);
+        assert_eq!(memory[0].message.as_ref(), "d");
+        assert_eq!(memory[6].message.as_ref(), "b");
+        let all: Vec<u64> = memory.into_entries().map(|entry| entry.ts).collect();
+        assert_eq!(all, [0, 1, 3, 5, 5, 6, 7]);
+    }
+
+    #[test]
+    fn sorts_entries_that_are_out_of_order() {
+        let mut strings = Interner::default();
+        let mut memory = MemoryEntries::default();
+        memory.insert(entries(&mut strings, &[4, 2, 6], "a"));
+        memory.insert(entries(&mut strings, &[5, 1], "b"));
+        memory.insert(entries(&mut strings, &[3, 2], "c"));
+        assert_eq!(
+            contents(&memory),
+            [
+                (1, "b"),
+                (2, "a"),
+                (2, "c"),
+                (3, "c"),
+                (4, "a"),
+                (5, "b"),
+                (6, "a")
+            ]
+            .map(|(ts, message)| (ts, message.to_string()))
+        );
+    }
+}
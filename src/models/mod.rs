@@ -1,19 +1,877 @@
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Json};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Display;
-use std::sync::RwLock;
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use gstreamer::{ClockTime, DebugLevel};
 use tempfile::TempDir;
+use tokio::sync::mpsc;
+
+use crate::config::Config;
+use crate::filter::FilterPlan;
+use crate::parser::{JournalFields, ParseReport, ParsedEntry};
+use crate::storage::{MappedEntries, MappedWriter, SessionStore, SqliteEntries};
+
+mod interner;
+mod memory;
+
+pub use interner::{Interner, Symbol};
+pub use memory::MemoryEntries;
 
-use crate::parser::Entry;
+// All levels, in increasing order of verbosity
+pub const LEVELS: [DebugLevel; 9] = [
+    DebugLevel::None,
+    DebugLevel::Error,
+    DebugLevel::Warning,
+    DebugLevel::Fixme,
+    DebugLevel::Info,
+    DebugLevel::Debug,
+    DebugLevel::Log,
+    DebugLevel::Trace,
+    DebugLevel::Memdump,
+];
+
+// Level with the given name, as levels are named in responses
+pub fn level_from_name(name: &str) -> Option<DebugLevel> {
+    LEVELS
+        .into_iter()
+        .find(|level| format!("{:?}", level) == name)
+}
 
 // Temporary storage for uploaded log files and parsed entries
 pub struct AppState {
+    // Runtime configuration
+    pub config: Config,
     // Map of session ID to parsed log entries
-    pub parsed_logs: RwLock<HashMap<String, Vec<Entry>>>,
+    pub parsed_logs: RwLock<HashMap<String, Session>>,
+    // Map of session ID to its processing status
+    pub sessions: RwLock<HashMap<String, SessionStatus>>,
+    // Map of session ID to the clients waiting for its new entries
+    pub live_subscribers: Mutex<HashMap<String, Vec<LiveSubscriber>>>,
     // Directory for temporary log file storage
     pub temp_dir: TempDir,
+    // Persistent storage of parsed sessions, sessions only live in memory when unset
+    pub store: Option<SessionStore>,
+    // Map of session ID to the version of the session in the store, if it's up to date.
+    // Each is locked while the stored copy is accessed, so that copies are written in order.
+    pub stored_versions: Mutex<HashMap<String, Arc<Mutex<Option<u64>>>>>,
+    // Map of session ID to the last time it was accessed or updated, for eviction
+    pub last_access: Mutex<HashMap<String, Instant>>,
+    // Directory of the entries of sessions, when they are kept in memory-mapped files
+    pub mapped_dir: Option<TempDir>,
+}
+
+impl AppState {
+    // Record the current processing status of a session
+    pub fn set_status(&self, session_id: &str, status: SessionStatus) {
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.insert(session_id.to_string(), status);
+        drop(sessions);
+        self.touch(session_id);
+    }
+
+    // Record that a session was just used
+    pub fn touch(&self, session_id: &str) {
+        self.last_access
+            .lock()
+            .unwrap()
+            .insert(session_id.to_string(), Instant::now());
+    }
+
+    // Create an empty session whose entries are kept as configured
+    pub fn new_session(&self) -> io::Result<Session> {
+        let mut session = Session::new();
+        if let Some(ref mapped_dir) = self.mapped_dir {
+            // Sessions parsed for an append are merged into another one, they get their own files
+            let dir = mapped_dir.path().join(uuid::Uuid::new_v4().to_string());
+            session.entries = Entries::Writing(MappedWriter::create(dir)?);
+        }
+        // Entries of SQLite sessions are written to the database that stores them
+        if let Some(SessionStore::Sqlite(ref db)) = self.store {
+            session.entries = Entries::Sqlite(SqliteEntries::create(db.clone()));
+        }
+        Ok(session)
+    }
+
+    // Forget everything about a session that is kept in memory. The session is
+    // returned so that its entries are freed once the sessions are unlocked.
+    pub fn remove_session(&self, session_id: &str) -> Option<Session> {
+        let session = self.parsed_logs.write().unwrap().remove(session_id);
+        self.sessions.write().unwrap().remove(session_id);
+        self.live_subscribers.lock().unwrap().remove(session_id);
+        self.last_access.lock().unwrap().remove(session_id);
+        self.stored_versions.lock().unwrap().remove(session_id);
+        session
+    }
+
+    // Lock on the stored copy of a session, holding the version of the session it was written from
+    pub fn stored_version(&self, session_id: &str) -> Arc<Mutex<Option<u64>>> {
+        self.stored_versions
+            .lock()
+            .unwrap()
+            .entry(session_id.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+// A client receiving the entries of a live session as they are parsed
+pub struct LiveSubscriber {
+    // Shared with the tasks sending new entries, while they don't hold any lock
+    pub plan: Arc<FilterPlan>,
+    // Entries matching the filter are sent through this channel
+    pub sender: mpsc::Sender<SerializableEntry>,
+}
+
+// Parsed log entries of a session, possibly coming from several files
+#[derive(Debug, Default)]
+pub struct Session {
+    // Entries of all files, ordered by timestamp
+    pub entries: Entries,
+    // Files the entries were parsed from
+    pub sources: Vec<Source>,
+    // Information about how the session was built, reported to clients
+    pub metadata: SessionMetadata,
+    // Strings referenced by the entries
+    pub strings: Interner,
+    // Estimate of the memory used by the entries, in bytes, not counting their strings
+    entries_size: usize,
+    // Changes with every modification of the session
+    version: u64,
+}
+
+// Versions of sessions increase across all sessions, so a session loaded again
+// never gets the version of an older copy
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+// Details about the files of a session that are not part of the entries
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionMetadata {
+    // Name and tags set by users to find the session again
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    // Creation time of the session, in seconds since the Unix epoch
+    pub created_at: u64,
+    // Bytes of logs uploaded or streamed into the session
+    pub size_bytes: u64,
+    // Archive members that were not parsed because they aren't GStreamer logs
+    pub skipped_files: Vec<SkippedFile>,
+    // Errors of appends that failed, leaving the session unchanged
+    pub append_errors: Vec<String>,
+    // Lines of all sources that weren't valid UTF-8 and were decoded lossily
+    pub lossy_lines: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SkippedFile {
+    pub name: String,
+    pub reason: String,
+}
+
+// A file of a session
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub name: String,
+    // Format of the file, unknown until enough of a stream has been received
+    pub format: Option<&'static str>,
+    // Lines of the file that couldn't be parsed
+    pub parse_report: ParseReport,
+}
+
+impl Source {
+    // Name and format of the file
+    pub fn summary(&self) -> SourceSummary {
+        SourceSummary {
+            name: self.name.clone(),
+            format: self.format,
+        }
+    }
+}
+
+// Entries of a session, in memory, in memory-mapped files or in an SQLite database
+#[derive(Debug)]
+pub enum Entries {
+    Memory(MemoryEntries),
+    // Written to files while the session is parsed, they can't be read yet
+    Writing(MappedWriter),
+    Mapped(MappedEntries),
+    Sqlite(SqliteEntries),
+}
+
+// Error of reading entries that are being written, they can't be read until they are finished
+pub fn writing_error() -> io::Error {
+    io::Error::other("Entries are read while being written")
+}
+
+impl Default for Entries {
+    fn default() -> Self {
+        Entries::Memory(MemoryEntries::default())
+    }
+}
+
+impl Entries {
+    pub fn len(&self) -> usize {
+        match self {
+            Entries::Memory(entries) => entries.len(),
+            Entries::Writing(writer) => writer.len(),
+            Entries::Mapped(entries) => entries.len(),
+            Entries::Sqlite(entries) => entries.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Timestamp of the entry at a position, in nanoseconds. Reading from the
+    // database, or entries that are being written, fails.
+    pub fn ts(&self, position: usize) -> io::Result<u64> {
+        match self {
+            Entries::Memory(entries) => Ok(entries[position].ts),
+            Entries::Writing(_) => Err(writing_error()),
+            Entries::Mapped(entries) => Ok(entries.ts(position)),
+            Entries::Sqlite(entries) => entries.ts(position),
+        }
+    }
+
+    // Whether entries are being added, they can't be read until they are finished
+    pub fn is_writing(&self) -> bool {
+        match self {
+            Entries::Writing(_) => true,
+            Entries::Sqlite(entries) => entries.is_writing(),
+            Entries::Memory(_) | Entries::Mapped(_) => false,
+        }
+    }
+
+    // Number of parsed entries to add at once. Entries written to files or to the
+    // database are added as they are parsed, so a log never has to fit in memory.
+    pub fn batch_size(&self) -> usize {
+        match self {
+            Entries::Writing(_) | Entries::Sqlite(_) => 64 * 1024,
+            _ => usize::MAX,
+        }
+    }
+}
+
+// A parsed entry along with the file it came from.
+// Repetitive fields are symbols of the strings of the session.
+#[derive(Debug, Clone)]
+pub struct SessionEntry {
+    // Timestamp in nanoseconds
+    pub ts: u64,
+    pub pid: u32,
+    pub thread: Symbol,
+    pub level: DebugLevel,
+    pub category: Symbol,
+    pub file: Symbol,
+    pub line: u32,
+    pub function: Symbol,
+    pub message: Box<str>,
+    pub object: Option<Symbol>,
+    // Index into `Session::sources`
+    pub source: usize,
+    // Journal fields, for entries of journal exports
+    pub journal: Option<Box<EntryJournal>>,
+    // Line number and byte offset of the entry in its source
+    pub line_number: u64,
+    pub offset: u64,
+}
+
+// Journal fields of an entry, the unit is a symbol of the strings of the session
+#[derive(Debug, Clone, Copy)]
+pub struct EntryJournal {
+    pub realtime_timestamp: u64,
+    pub pid: Option<u32>,
+    pub unit: Option<Symbol>,
+}
+
+impl EntryJournal {
+    pub fn new(journal: &JournalFields, strings: &mut Interner) -> Self {
+        EntryJournal {
+            realtime_timestamp: journal.realtime_timestamp,
+            pid: journal.pid,
+            unit: journal.unit.as_deref().map(|unit| strings.intern(unit)),
+        }
+    }
+
+    // The fields with the unit resolved from the strings of the session
+    pub fn resolve(&self, strings: &Interner) -> JournalFields {
+        JournalFields {
+            realtime_timestamp: self.realtime_timestamp,
+            pid: self.pid,
+            unit: self.unit.map(|unit| strings.resolve(unit).to_string()),
+        }
+    }
+}
+
+impl SessionEntry {
+    // Estimate of the memory used by the entry, in bytes
+    fn memory_size(&self) -> usize {
+        let journal = self
+            .journal
+            .as_ref()
+            .map_or(0, |_| std::mem::size_of::<EntryJournal>());
+        std::mem::size_of::<SessionEntry>() + self.message.len() + journal
+    }
+
+    pub fn new(entry: ParsedEntry, source: usize, strings: &mut Interner) -> Self {
+        let ParsedEntry {
+            entry,
+            journal,
+            line_number,
+            offset,
+        } = entry;
+        SessionEntry {
+            ts: entry.ts.nseconds(),
+            pid: entry.pid,
+            thread: strings.intern(&entry.thread),
+            level: entry.level,
+            category: strings.intern(&entry.category),
+            file: strings.intern(&entry.file),
+            line: entry.line,
+            function: strings.intern(&entry.function),
+            message: entry.message.into_boxed_str(),
+            object: entry.object.as_deref().map(|object| strings.intern(object)),
+            source,
+            journal: journal.map(|journal| Box::new(EntryJournal::new(&journal, strings))),
+            line_number,
+            offset,
+        }
+    }
+}
+
+// Entry with the given fields, its other fields empty, for the tests of the modules reading entries
+#[cfg(test)]
+impl SessionEntry {
+    pub fn for_test(
+        strings: &mut Interner,
+        ts: u64,
+        category: &str,
+        level: DebugLevel,
+        message: &str,
+    ) -> Self {
+        let empty = strings.intern("");
+        SessionEntry {
+            ts,
+            pid: 0,
+            thread: empty,
+            level,
+            category: strings.intern(category),
+            file: empty,
+            line: 0,
+            function: empty,
+            message: message.into(),
+            object: None,
+            source: 0,
+            journal: None,
+            line_number: 0,
+            offset: 0,
+        }
+    }
+}
+
+// Fields of an entry that filters look at. Entries of mapped sessions are read
+// field by field from their columns, without reading whole entries.
+pub trait EntryFields {
+    fn ts(&self) -> u64;
+    fn pid(&self) -> u32;
+    fn thread(&self) -> Symbol;
+    fn level(&self) -> DebugLevel;
+    fn category(&self) -> Symbol;
+    fn file(&self) -> Symbol;
+    fn line(&self) -> u32;
+    fn function(&self) -> Symbol;
+    fn message(&self) -> Cow<'_, str>;
+    fn object(&self) -> Option<Symbol>;
+    fn source(&self) -> usize;
+    // Journal fields, unset for entries of other logs
+    fn unit(&self) -> Option<Symbol>;
+    fn journal_pid(&self) -> Option<u32>;
+    fn line_number(&self) -> u64;
+}
+
+impl EntryFields for SessionEntry {
+    fn ts(&self) -> u64 {
+        self.ts
+    }
+
+    fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    fn thread(&self) -> Symbol {
+        self.thread
+    }
+
+    fn level(&self) -> DebugLevel {
+        self.level
+    }
+
+    fn category(&self) -> Symbol {
+        self.category
+    }
+
+    fn file(&self) -> Symbol {
+        self.file
+    }
+
+    fn line(&self) -> u32 {
+        self.line
+    }
+
+    fn function(&self) -> Symbol {
+        self.function
+    }
+
+    fn message(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.message)
+    }
+
+    fn object(&self) -> Option<Symbol> {
+        self.object
+    }
+
+    fn source(&self) -> usize {
+        self.source
+    }
+
+    fn unit(&self) -> Option<Symbol> {
+        self.journal.as_ref()?.unit
+    }
+
+    fn journal_pid(&self) -> Option<u32> {
+        self.journal.as_ref()?.pid
+    }
+
+    fn line_number(&self) -> u64 {
+        self.line_number
+    }
+}
+
+impl Session {
+    // Create an empty session, recording the current time as its creation time
+    pub fn new() -> Self {
+        let mut session = Session::default();
+        session.changed();
+        session.metadata.created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        session
+    }
+
+    // Add a file to the session, its entries are then added with `add_entries`.
+    // Returns the index of the new source.
+    pub fn add_source(
+        &mut self,
+        name: String,
+        format: Option<&'static str>,
+        parse_report: ParseReport,
+    ) -> usize {
+        let source = self.sources.len();
+        let name = self.unique_source_name(name);
+        self.metadata.lossy_lines += parse_report.lossy_lines;
+        self.sources.push(Source {
+            name,
+            format,
+            parse_report,
+        });
+        source
+    }
+
+    // Add entries parsed from an existing source
+    pub fn add_entries(&mut self, source: usize, entries: Vec<ParsedEntry>) -> io::Result<()> {
+        let entries = entries
+            .into_iter()
+            .map(|entry| SessionEntry::new(entry, source, &mut self.strings))
+            .collect();
+        self.insert_entries(entries)
+    }
+
+    // Insert entries of existing sources, keeping the session ordered by timestamp.
+    // Their symbols must come from the strings of this session.
+    pub fn insert_entries(&mut self, entries: Vec<SessionEntry>) -> io::Result<()> {
+        self.changed();
+        match self.entries {
+            Entries::Memory(ref mut existing) => {
+                self.entries_size += entries.iter().map(SessionEntry::memory_size).sum::<usize>();
+                existing.insert(entries);
+            }
+            // Sorted once all entries are written, errors are reported then
+            Entries::Writing(ref mut writer) => {
+                for entry in &entries {
+                    writer.push(entry);
+                }
+            }
+            // Ordered once all entries have been added, errors are reported then
+            Entries::Sqlite(ref mut sqlite) if sqlite.is_writing() => {
+                sqlite.insert(&entries);
+            }
+            // Rows are added to the files or to the database, then merged into the
+            // order of the existing ones
+            Entries::Mapped(_) | Entries::Sqlite(_) => {
+                return self.add_to_finished(|session| session.insert_entries(entries));
+            }
+        }
+        Ok(())
+    }
+
+    // Add entries with `add` to entries that are finished, which are reopened and then
+    // finished again. If that fails, the entries are restored as they were.
+    fn add_to_finished(&mut self, add: impl FnOnce(&mut Self) -> io::Result<()>) -> io::Result<()> {
+        let finished = match self.entries {
+            Entries::Mapped(ref entries) => Some(Entries::Mapped(entries.clone())),
+            Entries::Sqlite(ref entries) => entries.snapshot().map(Entries::Sqlite),
+            Entries::Memory(_) | Entries::Writing(_) => None,
+        };
+        let result = self
+            .reopen_entries()
+            .and_then(|()| add(self))
+            .and_then(|()| self.finish_entries());
+        if let (Err(_), Some(finished)) = (&result, finished) {
+            // Rows already added to the database would be ordered by the next finish
+            if let Entries::Sqlite(ref entries) = finished {
+                if let Err(e) = entries.discard_added() {
+                    log::error!("Failed to remove entries of a failed append: {}", e);
+                }
+            }
+            self.entries = finished;
+        }
+        result
+    }
+
+    // Make the entries written to files or to the database readable, once all
+    // entries have been added
+    pub fn finish_entries(&mut self) -> io::Result<()> {
+        if let Entries::Sqlite(ref mut entries) = self.entries {
+            entries.finish()?;
+        }
+        if let Entries::Writing(_) = self.entries {
+            let writer = match std::mem::take(&mut self.entries) {
+                Entries::Writing(writer) => writer,
+                _ => unreachable!(),
+            };
+            self.entries = Entries::Mapped(writer.finish()?);
+        }
+        Ok(())
+    }
+
+    // Make finished entries writable again, mapped entries reopen their files
+    fn reopen_entries(&mut self) -> io::Result<()> {
+        if let Entries::Sqlite(ref mut entries) = self.entries {
+            entries.reopen();
+        }
+        if let Entries::Mapped(_) = self.entries {
+            let entries = match std::mem::take(&mut self.entries) {
+                Entries::Mapped(entries) => entries,
+                _ => unreachable!(),
+            };
+            self.entries = Entries::Writing(entries.reopen()?);
+        }
+        Ok(())
+    }
+
+    // Entry at a position, entries of mapped sessions are read from their files
+    pub fn entry(&self, position: usize) -> io::Result<Cow<'_, SessionEntry>> {
+        match self.entries {
+            Entries::Memory(ref entries) => Ok(Cow::Borrowed(&entries[position])),
+            Entries::Writing(_) => Err(writing_error()),
+            Entries::Mapped(ref entries) => Ok(Cow::Owned(entries.get(position))),
+            Entries::Sqlite(ref entries) => entries.get(position).map(Cow::Owned),
+        }
+    }
+
+    // Entries at a range of positions, those of SQLite sessions are read at once
+    fn entries_in(&self, positions: Range<usize>) -> io::Result<Vec<SessionEntry>> {
+        match self.entries {
+            Entries::Sqlite(ref entries) => entries.get_range(positions),
+            _ => positions
+                .map(|position| self.entry(position).map(Cow::into_owned))
+                .collect(),
+        }
+    }
+
+    // All entries, ordered by timestamp
+    pub fn iter_entries(&self) -> impl Iterator<Item = io::Result<Cow<'_, SessionEntry>>> {
+        (0..self.entries.len()).map(|position| self.entry(position))
+    }
+
+    // Estimate of the memory used by the entries and their strings, in bytes
+    pub fn memory_size(&self) -> usize {
+        self.entries_size + self.strings.memory_size()
+    }
+
+    // Add the entries of another session, keeping the session ordered by timestamp.
+    // The session is left as it was if the entries can't be added.
+    pub fn append(&mut self, mut other: Session) -> io::Result<()> {
+        let offset = self.sources.len();
+
+        // Symbols of the other session are translated to those of this one
+        let symbols: Vec<Symbol> = other
+            .strings
+            .iter()
+            .map(|(_, string)| self.strings.intern(string))
+            .collect();
+        let translate = |symbol: Symbol| symbols[symbol.index()];
+        let translate_entry = |entry: SessionEntry| SessionEntry {
+            thread: translate(entry.thread),
+            category: translate(entry.category),
+            file: translate(entry.file),
+            function: translate(entry.function),
+            object: entry.object.map(translate),
+            source: entry.source + offset,
+            journal: entry.journal.map(|journal| {
+                Box::new(EntryJournal {
+                    unit: journal.unit.map(translate),
+                    ..*journal
+                })
+            }),
+            ..entry
+        };
+
+        self.add_to_finished(|session| match std::mem::take(&mut other.entries) {
+            Entries::Memory(entries) => {
+                session.insert_entries(entries.into_entries().map(translate_entry).collect())
+            }
+            entries => {
+                // Entries read from files are added in batches, they don't have to fit in memory
+                other.entries = entries;
+                let mut start = 0;
+                while start < other.entries.len() {
+                    let end = start
+                        .saturating_add(session.entries.batch_size())
+                        .min(other.entries.len());
+                    let batch = other.entries_in(start..end)?;
+                    session.insert_entries(batch.into_iter().map(translate_entry).collect())?;
+                    start = end;
+                }
+                Ok(())
+            }
+        })?;
+
+        self.changed();
+        for source in other.sources.drain(..) {
+            let name = self.unique_source_name(source.name);
+            self.sources.push(Source {
+                name,
+                format: source.format,
+                parse_report: source.parse_report,
+            });
+        }
+        self.metadata
+            .skipped_files
+            .append(&mut other.metadata.skipped_files);
+        self.metadata.lossy_lines += other.metadata.lossy_lines;
+        self.metadata.size_bytes += other.metadata.size_bytes;
+        Ok(())
+    }
+
+    // Source names identify files in filters, so they must not collide
+    fn unique_source_name(&self, name: String) -> String {
+        if self.source_index(&name).is_none() {
+            return name;
+        }
+        (2..)
+            .map(|n| format!("{} ({})", name, n))
+            .find(|candidate| self.source_index(candidate).is_none())
+            .unwrap()
+    }
+
+    // Index of the source with the given name
+    pub fn source_index(&self, name: &str) -> Option<usize> {
+        self.sources.iter().position(|source| source.name == name)
+    }
+
+    // Timestamps of the first and last entries
+    pub fn time_span(&self) -> io::Result<Option<(ClockTime, ClockTime)>> {
+        if self.entries.is_empty() || self.entries.is_writing() {
+            return Ok(None);
+        }
+        Ok(Some((
+            ClockTime::from_nseconds(self.entries.ts(0)?),
+            ClockTime::from_nseconds(self.entries.ts(self.entries.len() - 1)?),
+        )))
+    }
+
+    // Summary of the session for the session list, without its time span if it can't be read
+    pub fn info(&self, session_id: &str, status: SessionStatus) -> SessionInfo {
+        let time_span = self.time_span().unwrap_or_else(|e| {
+            log::warn!(
+                "Failed to read the time span of session {}: {}",
+                session_id,
+                e
+            );
+            None
+        });
+        SessionInfo::new(
+            session_id,
+            status,
+            &self.metadata,
+            self.sources
+                .iter()
+                .map(|source| source.name.clone())
+                .collect(),
+            self.entries.len(),
+            time_span,
+        )
+    }
+
+    // Name and format of each source
+    pub fn source_summaries(&self) -> Vec<SourceSummary> {
+        self.sources.iter().map(Source::summary).collect()
+    }
+
+    // Metadata to modify, which makes a new version of the session
+    pub fn metadata_mut(&mut self) -> &mut SessionMetadata {
+        self.changed();
+        &mut self.metadata
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn changed(&mut self) {
+        self.version = NEXT_VERSION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Copy of the session sharing its entries, so it can be stored without keeping
+    // the sessions locked. Entries that are still being written can't be stored.
+    pub fn snapshot(&self) -> Option<Session> {
+        let entries = match self.entries {
+            Entries::Memory(ref entries) => Entries::Memory(entries.clone()),
+            Entries::Writing(_) => return None,
+            Entries::Mapped(ref entries) => Entries::Mapped(entries.clone()),
+            Entries::Sqlite(ref entries) => Entries::Sqlite(entries.snapshot()?),
+        };
+        Some(Session {
+            entries,
+            sources: self.sources.clone(),
+            metadata: self.metadata.clone(),
+            strings: self.strings.clone(),
+            entries_size: self.entries_size,
+            version: self.version,
+        })
+    }
+
+    // Record lines of a source that couldn't be parsed
+    pub fn record_rejected_lines(&mut self, source: usize, report: ParseReport) {
+        self.changed();
+        self.metadata.lossy_lines += report.lossy_lines;
+        self.sources[source].parse_report.merge(report);
+    }
+}
+
+// Lifecycle of an uploaded session, from upload until its entries can be queried
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum SessionStatus {
+    // Waiting for a parser task to pick up the uploaded files, or for streamed data
+    Queued,
+    // Parser is running, progress is reported in bytes of the uploaded file
+    Parsing { bytes_read: u64, total_bytes: u64 },
+    // Entries are stored and can be queried
+    Ready { entries: usize },
+    // Parsing failed, the session has no entries
+    Failed { error: String },
+    // Entries are parsed as they are written to a file, the session keeps growing
+    Live { bytes_read: u64 },
+    // Entries are stored on disk and loaded again when the session is accessed
+    Unloaded { entries: usize },
+    // Session was removed to free resources, its entries are gone
+    Evicted { reason: String },
+}
+
+// Response for the rejected lines endpoint
+#[derive(Debug, Serialize)]
+pub struct RejectedLinesResponse {
+    pub session_id: String,
+    // Total over all sources
+    pub rejected_lines: u64,
+    pub sources: Vec<SourceRejectedLines>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SourceRejectedLines {
+    pub source: String,
+    #[serde(flatten)]
+    pub report: ParseReport,
+}
+
+// Entry of the session list
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    // Names of the uploaded files
+    pub files: Vec<String>,
+    pub created_at: u64,
+    pub size_bytes: u64,
+    pub entry_count: usize,
+    // Timestamps of the first and last entries, formatted like those of entries
+    pub start_ts: Option<String>,
+    pub end_ts: Option<String>,
+    #[serde(flatten)]
+    pub status: SessionStatus,
+}
+
+impl SessionInfo {
+    pub fn new(
+        session_id: &str,
+        status: SessionStatus,
+        metadata: &SessionMetadata,
+        files: Vec<String>,
+        entry_count: usize,
+        time_span: Option<(ClockTime, ClockTime)>,
+    ) -> Self {
+        SessionInfo {
+            session_id: session_id.to_string(),
+            name: metadata.name.clone(),
+            tags: metadata.tags.clone(),
+            files,
+            created_at: metadata.created_at,
+            size_bytes: metadata.size_bytes,
+            entry_count,
+            start_ts: time_span.map(|(start, _)| format!("{}", start)),
+            end_ts: time_span.map(|(_, end)| format!("{}", end)),
+            status,
+        }
+    }
+}
+
+// Request body for updating the name and tags of a session
+#[derive(Debug, Deserialize)]
+pub struct SessionUpdate {
+    // An empty name removes it
+    pub name: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+// Response for the session status endpoint
+#[derive(Debug, Serialize)]
+pub struct SessionStatusResponse {
+    pub session_id: String,
+    #[serde(flatten)]
+    pub status: SessionStatus,
+    // Only available once the session has been parsed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<SessionMetadata>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SourceSummary {
+    pub name: String,
+    pub format: Option<&'static str>,
 }
 
 // Custom error type for API errors with better logging
@@ -57,6 +915,18 @@ pub struct LogFilter {
     pub thread: Option<String>,
     pub object: Option<String>,
     pub function_regex: Option<String>,
+    // Name of the uploaded file the entries come from
+    pub source: Option<String>,
+    // Systemd unit and journald PID, for journal exports
+    pub unit: Option<String>,
+    pub journal_pid: Option<u32>,
+    // Range of line numbers in the source file, inclusive
+    pub line_from: Option<u64>,
+    pub line_to: Option<u64>,
+    // Query combining conditions on any field, see `filter::language`
+    pub q: Option<String>,
+    // Category thresholds in the syntax of GST_DEBUG, see `filter::gst_debug`
+    pub gst_debug: Option<String>,
     #[serde(default = "default_page")]
     pub page: usize,
     #[serde(default = "default_per_page")]
@@ -94,6 +964,8 @@ pub struct FilterOptionsResponse {
     pub pids: Vec<u32>,
     pub threads: Vec<String>,
     pub objects: Vec<String>,
+    pub sources: Vec<String>,
+    pub units: Vec<String>,
 }
 
 // Make Entry serializable for JSON responses
@@ -109,21 +981,96 @@ pub struct SerializableEntry {
     pub function: String,
     pub message: String,
     pub object: Option<String>,
+    pub source: String,
+    // Position of the entry in its source file
+    pub line_number: u64,
+    pub offset: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub journal: Option<JournalFields>,
 }
 
-impl From<&Entry> for SerializableEntry {
-    fn from(entry: &Entry) -> Self {
+impl SerializableEntry {
+    pub fn new(entry: &SessionEntry, session: &Session) -> Self {
+        let strings = &session.strings;
         SerializableEntry {
-            ts: format!("{}", entry.ts),
+            ts: format!("{}", ClockTime::from_nseconds(entry.ts)),
             pid: entry.pid,
-            thread: entry.thread.clone(),
+            thread: strings.resolve(entry.thread).to_string(),
             level: format!("{:?}", entry.level),
-            category: entry.category.clone(),
-            file: entry.file.clone(),
+            category: strings.resolve(entry.category).to_string(),
+            file: strings.resolve(entry.file).to_string(),
             line: entry.line,
-            function: entry.function.clone(),
-            message: entry.message.clone(),
-            object: entry.object.clone(),
+            function: strings.resolve(entry.function).to_string(),
+            message: entry.message.to_string(),
+            object: entry
+                .object
+                .map(|object| strings.resolve(object).to_string()),
+            source: session.sources[entry.source].name.clone(),
+            line_number: entry.line_number,
+            offset: entry.offset,
+            journal: entry
+                .journal
+                .as_ref()
+                .map(|journal| journal.resolve(strings)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_with(strings: &[&str], entries: &[(u64, &str, &str)]) -> Session {
+        let mut session = Session::new();
+        // Interned first, so that the same strings have other symbols in each session
+        for string in strings {
+            session.strings.intern(string);
+        }
+        session.add_source("app.log".to_string(), None, ParseReport::default());
+        let entries = entries
+            .iter()
+            .map(|&(ts, category, unit)| {
+                let strings = &mut session.strings;
+                let mut entry = SessionEntry::for_test(strings, ts, category, DebugLevel::Info, "");
+                entry.journal = Some(Box::new(EntryJournal {
+                    realtime_timestamp: ts,
+                    pid: None,
+                    unit: Some(strings.intern(unit)),
+                }));
+                entry
+            })
+            .collect();
+        session.insert_entries(entries).unwrap();
+        session
+    }
+
+    #[test]
+    fn appends_sessions_with_their_own_strings() {
+        let mut session = session_with(
+            &["a", "x.service"],
+            &[(1, "a", "x.service"), (3, "a", "x.service")],
+        );
+        let other = session_with(&["y.service", "b"], &[(2, "b", "y.service")]);
+        session.append(other).unwrap();
+
+        let entries: Vec<SerializableEntry> = session
+            .iter_entries()
+            .map(|entry| SerializableEntry::new(&entry.unwrap(), &session))
+            .collect();
+        let fields: Vec<(&str, &str, Option<&str>)> = entries
+            .iter()
+            .map(|entry| {
+                let unit = entry.journal.as_ref().unwrap().unit.as_deref();
+                (entry.category.as_str(), entry.source.as_str(), unit)
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("a", "app.log", Some("x.service")),
+                ("b", "app.log (2)", Some("y.service")),
+                ("a", "app.log", Some("x.service")),
+            ]
+        );
+    }
+}
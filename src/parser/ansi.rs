@@ -0,0 +1,79 @@
+//! Removal of ANSI escape sequences from logs captured with `GST_DEBUG_COLOR_MODE=on`
+
+use std::borrow::Cow;
+
+const ESC: char = '\x1b';
+
+/// Check whether a line contains escape sequences
+pub fn has_escapes(line: &str) -> bool {
+    line.contains(ESC)
+}
+
+/// Remove ANSI escape sequences, lines without any are returned untouched
+pub fn strip_escapes(line: &str) -> Cow<'_, str> {
+    if !has_escapes(line) {
+        return Cow::Borrowed(line);
+    }
+
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != ESC {
+            stripped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // Control Sequence Introducer: parameter and intermediate bytes up to a final byte
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // Operating System Command: terminated by BEL or ST (ESC \)
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == ESC && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Any other escape is a single character
+            _ => {}
+        }
+    }
+
+    Cow::Owned(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_escape_sequences() {
+        assert_eq!(
+            strip_escapes("\x1b[32m0:00:00.1\x1b[00m \x1b[31;01mERROR\x1b[00m done"),
+            "0:00:00.1 ERROR done"
+        );
+        assert_eq!(
+            strip_escapes("\x1b]0;title\x07a\x1b]8;;link\x1b\\b\x1bc"),
+            "ab"
+        );
+    }
+
+    #[test]
+    fn borrows_lines_without_escapes() {
+        assert!(!has_escapes("plain line"));
+        assert!(matches!(
+            strip_escapes("plain line"),
+            Cow::Borrowed("plain line")
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+//! Enumeration of log files bundled in zip or tar archives
+//!
+//! Tar archives may themselves be compressed with any format supported by
+//! [`decompress`](super::decompress).
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::Result;
+
+use super::decompress;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const ZIP_EMPTY_MAGIC: &[u8] = b"PK\x05\x06";
+// Offset and value of the magic field of a ustar header
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// Archive formats supported for uploads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+}
+
+/// Detect whether a file is a zip or a (possibly compressed) tar archive
+///
+/// The reader is rewound to its start before returning.
+pub fn detect_archive<R: Read + Seek>(reader: &mut R) -> io::Result<Option<ArchiveKind>> {
+    let mut header = Vec::with_capacity(TAR_MAGIC_OFFSET + TAR_MAGIC.len());
+    reader.by_ref().take(4).read_to_end(&mut header)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    if header.starts_with(ZIP_MAGIC) || header.starts_with(ZIP_EMPTY_MAGIC) {
+        return Ok(Some(ArchiveKind::Zip));
+    }
+
+    // Tar archives are usually compressed, look at the decompressed header
+    header.clear();
+    decompress(reader.by_ref())?
+        .take((TAR_MAGIC_OFFSET + TAR_MAGIC.len()) as u64)
+        .read_to_end(&mut header)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    if header.len() > TAR_MAGIC_OFFSET && header[TAR_MAGIC_OFFSET..].starts_with(TAR_MAGIC) {
+        return Ok(Some(ArchiveKind::Tar));
+    }
+
+    Ok(None)
+}
+
+/// Call `f` with the path and content of every regular file in the archive
+pub fn for_each_member<R, F>(kind: ArchiveKind, reader: R, mut f: F) -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(&str, &mut dyn Read) -> Result<()>,
+{
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(reader)?;
+            for i in 0..archive.len() {
+                let mut member = archive.by_index(i)?;
+                if member.is_dir() {
+                    continue;
+                }
+                let name = member.name().to_string();
+                f(&name, &mut member)?;
+            }
+        }
+        ArchiveKind::Tar => {
+            let mut archive = tar::Archive::new(decompress(reader)?);
+            for member in archive.entries()? {
+                let mut member = member?;
+                if !member.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = member.path()?.to_string_lossy().into_owned();
+                f(&name, &mut member)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    fn members(kind: ArchiveKind, data: Vec<u8>) -> Vec<(String, String)> {
+        let mut members = Vec::new();
+        for_each_member(kind, Cursor::new(data), |name, content| {
+            let mut text = String::new();
+            content.read_to_string(&mut text)?;
+            members.push((name.to_string(), text));
+            Ok(())
+        })
+        .unwrap();
+        members
+    }
+
+    fn expected() -> Vec<(String, String)> {
+        vec![
+            ("a.log".to_string(), "first\n".to_string()),
+            ("logs/b.log".to_string(), "second\n".to_string()),
+        ]
+    }
+
+    #[test]
+    fn lists_zip_files() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("a.log", options).unwrap();
+        writer.write_all(b"first\n").unwrap();
+        writer.add_directory("logs/", options).unwrap();
+        writer.start_file("logs/b.log", options).unwrap();
+        writer.write_all(b"second\n").unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        let mut reader = Cursor::new(data);
+        assert_eq!(detect_archive(&mut reader).unwrap(), Some(ArchiveKind::Zip));
+        assert_eq!(reader.position(), 0);
+        assert_eq!(members(ArchiveKind::Zip, reader.into_inner()), expected());
+    }
+
+    #[test]
+    fn lists_compressed_tar_files() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, content) in expected() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&builder.into_inner().unwrap()).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let mut reader = Cursor::new(data);
+        assert_eq!(detect_archive(&mut reader).unwrap(), Some(ArchiveKind::Tar));
+        assert_eq!(reader.position(), 0);
+        assert_eq!(members(ArchiveKind::Tar, reader.into_inner()), expected());
+    }
+
+    #[test]
+    fn ignores_plain_logs() {
+        let mut reader = Cursor::new(b"0:00:00.1 1 0x1 INFO cat f.c:1:fn: message\n".to_vec());
+        assert_eq!(detect_archive(&mut reader).unwrap(), None);
+        assert_eq!(reader.position(), 0);
+    }
+}
@@ -0,0 +1,111 @@
+//! Transparent decompression of compressed log files
+//!
+//! The compression format is detected from the magic bytes at the start of the
+//! stream, so file extensions don't matter.
+
+use std::io::{self, BufRead, BufReader, Read};
+
+use flate2::bufread::MultiGzDecoder;
+use xz2::bufread::XzDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Compression formats supported for uploaded logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// Detect the compression format from the first bytes of a file
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if header.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Wrap a reader in the decoder matching its compression format
+///
+/// Data is decompressed on the fly while it is read, nothing is written to disk.
+pub fn decompress<'a, R: Read + 'a>(reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(reader);
+    let compression = Compression::detect(reader.fill_buf()?);
+    log::debug!("Detected compression: {:?}", compression);
+
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const LOG: &[u8] =
+        b"0:00:00.1 1 0x1 INFO cat f.c:1:fn: first\n0:00:00.2 1 0x1 INFO cat f.c:1:fn: second\n";
+
+    fn read_all(data: &[u8]) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        decompress(data)
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decompresses_detected_formats() {
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 1);
+        xz.write_all(LOG).unwrap();
+        let xz = xz.finish().unwrap();
+        let zstd = zstd::encode_all(LOG, 1).unwrap();
+
+        assert_eq!(Compression::detect(&gzip(LOG)), Compression::Gzip);
+        assert_eq!(Compression::detect(&zstd), Compression::Zstd);
+        assert_eq!(Compression::detect(&xz), Compression::Xz);
+        assert_eq!(Compression::detect(LOG), Compression::None);
+        for data in [gzip(LOG), zstd, xz, LOG.to_vec()] {
+            assert_eq!(read_all(&data), LOG);
+        }
+    }
+
+    #[test]
+    fn reads_every_gzip_member() {
+        let (first, second) = LOG.split_at(20);
+        let mut data = gzip(first);
+        data.extend(gzip(second));
+        assert_eq!(read_all(&data), LOG);
+    }
+
+    #[test]
+    fn reports_truncated_files() {
+        let data = gzip(LOG);
+        let mut decompressed = Vec::new();
+        let result = decompress(&data[..data.len() - 10])
+            .unwrap()
+            .read_to_end(&mut decompressed);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Formats GStreamer debug logs are stored in
+//!
+//! Each format parses the lines of a log into entries. The format of a log is
+//! detected from a sample of its first lines, see [`detect_format`].
+
+use gstreamer::{ClockTime, DebugLevel};
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use super::journald::JournaldFormat;
+use super::logcat::LogcatFormat;
+use super::{ansi, Entry, ParsedEntry};
+
+lazy_static! {
+    // 00000010: 00 00 00 01 67 42 c0 1e  ....gB..
+    static ref HEXDUMP_RE: Regex = Regex::new(r"^[0-9a-fA-F]{8}: [0-9a-fA-F]{2}( |$)").unwrap();
+    // video/x-raw(memory:GLMemory), format=(string)RGBA, width=(int)1920;
+    static ref STRUCTURE_RE: Regex =
+        Regex::new(r"^[A-Za-z][\w.+/-]*(\([\w:, ]*\))?, *[A-Za-z][\w.-]*=").unwrap();
+    // audio/x-raw;
+    static ref MEDIA_TYPE_RE: Regex =
+        Regex::new(r"^[a-z]+/[\w.+-]+(\([\w:, ]*\))?;?$").unwrap();
+    // a=rtpmap:96 H264/90000
+    static ref SDP_RE: Regex = Regex::new(r"^[a-z]=\S").unwrap();
+}
+
+/// A format of GStreamer debug logs
+pub trait LogFormat: Send + Sync {
+    /// Name of the format, as recorded in sessions
+    fn name(&self) -> &'static str;
+
+    /// Parse a line of the log, without its line terminator
+    fn parse_line(&self, line: &str) -> Option<ParsedEntry>;
+
+    /// Text to add to the message of the previous entry, for lines that aren't entries
+    /// on their own but may continue a multi-line message
+    fn continuation<'a>(&self, line: &'a str) -> Option<&'a str> {
+        Some(line)
+    }
+
+    /// Check whether a sample of complete lines from the start of a log is in this format
+    fn detect(&self, sample: &str) -> bool {
+        sample.lines().any(|line| self.parse_line(line).is_some())
+    }
+}
+
+/// Debug output of GStreamer as written to stderr or `GST_DEBUG_FILE`
+pub struct GStreamerFormat;
+
+impl LogFormat for GStreamerFormat {
+    fn name(&self) -> &'static str {
+        "gstreamer"
+    }
+
+    fn parse_line(&self, line: &str) -> Option<ParsedEntry> {
+        parse_debug_line(line).map(ParsedEntry::new)
+    }
+
+    // Only lines shaped like the rest of a dump continue a message, other output
+    // mixed with the log is rejected
+    fn continuation<'a>(&self, line: &'a str) -> Option<&'a str> {
+        let text = ansi::strip_escapes(line);
+        let continues = text.starts_with([' ', '\t'])
+            || HEXDUMP_RE.is_match(&text)
+            || STRUCTURE_RE.is_match(&text)
+            || MEDIA_TYPE_RE.is_match(&text)
+            || SDP_RE.is_match(&text);
+        continues.then_some(line)
+    }
+}
+
+// Known formats, in the order they are tried. Formats wrapping GStreamer debug
+// lines come first as their lines may also contain plain ones.
+static FORMATS: &[&dyn LogFormat] = &[&JournaldFormat, &LogcatFormat, &GStreamerFormat];
+
+/// Format used when none could be detected, e.g. for streams that haven't sent a full line yet
+pub static DEFAULT_FORMAT: &dyn LogFormat = &GStreamerFormat;
+
+/// Look up a format by its name
+pub fn find_format(name: &str) -> Option<&'static dyn LogFormat> {
+    FORMATS.iter().copied().find(|format| format.name() == name)
+}
+
+/// Detect the format of a log from a sample of its start
+pub fn detect_format(sample: &[u8]) -> Option<&'static dyn LogFormat> {
+    // Only consider complete lines, the sample may end in the middle of one
+    let sample = match sample.iter().rposition(|&b| b == b'\n') {
+        Some(end) => &sample[..end],
+        None => sample,
+    };
+    // Lines with invalid UTF-8 are parsed lossily, they can be detected the same way
+    let text = String::from_utf8_lossy(sample);
+
+    FORMATS.iter().copied().find(|format| format.detect(&text))
+}
+
+/// Parse a GStreamer debug line, possibly colored
+///
+/// Lines are split the way gst-log-parser does, but one at a time: its own
+/// parser reads a whole stream and initializes GStreamer each time it's created.
+pub fn parse_debug_line(line: &str) -> Option<Entry> {
+    // Logs captured from a terminal are colored with ANSI escape sequences
+    let line = ansi::strip_escapes(line);
+
+    // 0:00:00.007773544  8874 0x558951015c00 INFO  GST_INIT gst.c:510:init_pre:<obj> message
+    let mut fields = line.split(' ');
+    let ts = parse_time(fields.next()?)?;
+    let mut fields = fields.skip_while(|field| field.is_empty());
+    let pid = fields.next()?.parse().ok()?;
+    let mut fields = fields.skip_while(|field| field.is_empty());
+    let thread = fields.next()?.to_string();
+    let mut fields = fields.skip_while(|field| field.is_empty());
+    let level = parse_level(fields.next()?)?;
+    let mut fields = fields.skip_while(|field| field.is_empty());
+    let category = fields.next()?.to_string();
+    let mut fields = fields.skip_while(|field| field.is_empty());
+
+    let mut location = fields.next()?.splitn(4, ':');
+    let file = location.next()?.to_string();
+    let line_number = location.next()?.parse().ok()?;
+    let function = location.next()?.to_string();
+    let object = location.next()?;
+    let object = (!object.is_empty()).then(|| {
+        object
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_string()
+    });
+
+    Some(Entry {
+        ts,
+        pid,
+        thread,
+        level,
+        category,
+        file,
+        line: line_number,
+        function,
+        message: fields.join(" "),
+        object,
+    })
+}
+
+// Timestamp of a debug line, e.g. 0:00:01.007773544
+fn parse_time(ts: &str) -> Option<ClockTime> {
+    let mut fields = ts.splitn(3, ':');
+    let hours: u64 = fields.next()?.parse().ok()?;
+    let minutes: u64 = fields.next()?.parse().ok()?;
+    let (seconds, nanoseconds) = fields.next()?.split_once('.')?;
+    let seconds: u64 = seconds.parse().ok()?;
+    let nanoseconds: u64 = nanoseconds.parse().ok()?;
+    clock_time(hours, minutes, seconds, nanoseconds)
+}
+
+// Time of the given fields, lines holding numbers too large for a time aren't entries
+pub(super) fn clock_time(
+    hours: u64,
+    minutes: u64,
+    seconds: u64,
+    nanoseconds: u64,
+) -> Option<ClockTime> {
+    let nanoseconds = hours
+        .checked_mul(60)?
+        .checked_add(minutes)?
+        .checked_mul(60)?
+        .checked_add(seconds)?
+        .checked_mul(1_000_000_000)?
+        .checked_add(nanoseconds)?;
+    // The maximum value is reserved for GST_CLOCK_TIME_NONE
+    (nanoseconds != u64::MAX).then(|| ClockTime::from_nseconds(nanoseconds))
+}
+
+fn parse_level(level: &str) -> Option<DebugLevel> {
+    match level {
+        "ERROR" => Some(DebugLevel::Error),
+        "WARN" => Some(DebugLevel::Warning),
+        "FIXME" => Some(DebugLevel::Fixme),
+        "INFO" => Some(DebugLevel::Info),
+        "DEBUG" => Some(DebugLevel::Debug),
+        "LOG" => Some(DebugLevel::Log),
+        "TRACE" => Some(DebugLevel::Trace),
+        "MEMDUMP" => Some(DebugLevel::Memdump),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_debug_lines() {
+        let entry = parse_debug_line(
+            "0:00:01.007773544  8874 0x558951015c00 INFO  GST_INIT gst.c:510:init_pre:<pipeline0> Initializing  GStreamer",
+        )
+        .unwrap();
+        assert_eq!(entry.ts.nseconds(), 1_007_773_544);
+        assert_eq!(entry.pid, 8874);
+        assert_eq!(entry.thread, "0x558951015c00");
+        assert_eq!(entry.level, DebugLevel::Info);
+        assert_eq!(entry.category, "GST_INIT");
+        assert_eq!(entry.file, "gst.c");
+        assert_eq!(entry.line, 510);
+        assert_eq!(entry.function, "init_pre");
+        assert_eq!(entry.object.as_deref(), Some("pipeline0"));
+        assert_eq!(entry.message, "Initializing  GStreamer");
+    }
+
+    #[test]
+    fn parses_colored_lines_without_object() {
+        let entry = parse_debug_line(
+            "\x1b[32m0:00:00.000123000\x1b[00m \x1b[35m  42\x1b[00m 0x1 \x1b[31;01mERROR  \x1b[00m \x1b[00m          basesrc gstbasesrc.c:3132:gst_base_src_loop:\x1b[00m error",
+        )
+        .unwrap();
+        assert_eq!(entry.ts.nseconds(), 123_000);
+        assert_eq!(entry.pid, 42);
+        assert_eq!(entry.level, DebugLevel::Error);
+        assert_eq!(entry.category, "basesrc");
+        assert_eq!(entry.object, None);
+        assert_eq!(entry.message, "error");
+    }
+
+    #[test]
+    fn parses_every_level() {
+        for (name, level) in [
+            ("ERROR", DebugLevel::Error),
+            ("WARN", DebugLevel::Warning),
+            ("FIXME", DebugLevel::Fixme),
+            ("INFO", DebugLevel::Info),
+            ("DEBUG", DebugLevel::Debug),
+            ("LOG", DebugLevel::Log),
+            ("TRACE", DebugLevel::Trace),
+            ("MEMDUMP", DebugLevel::Memdump),
+        ] {
+            let line = format!("0:00:00.1 1 0x1 {} cat f.c:1:fn: message", name);
+            assert_eq!(parse_debug_line(&line).unwrap().level, level);
+        }
+    }
+
+    #[test]
+    fn rejects_other_lines() {
+        for line in [
+            "",
+            "Setting pipeline to PAUSED ...",
+            "0:00:01.0 8874 0x1 VERBOSE cat file.c:1:func: message",
+            "0:00:01.0 8874 0x1 INFO cat file.c:line:func: message",
+            "0:00:01.0 8874 0x1 INFO cat file.c",
+            "1:2 8874 0x1 INFO cat file.c:1:func: message",
+            "99999999999999:00:00.0 1 0x1 INFO c f.c:1:f: m",
+            "0:00:00.18446744073709551615 1 0x1 INFO c f.c:1:f: m",
+        ] {
+            assert!(parse_debug_line(line).is_none(), "{:?} was parsed", line);
+        }
+    }
+
+    #[test]
+    fn detects_formats_from_complete_lines() {
+        let detect = |sample: &str| detect_format(sample.as_bytes()).map(|format| format.name());
+        assert_eq!(
+            detect("Setting pipeline to PAUSED ...\n0:00:00.1 1 0x1 INFO cat f.c:1:fn: message\n"),
+            Some("gstreamer")
+        );
+        assert_eq!(
+            detect(
+                "01-15 10:23:45.123  1234  1250 D GStreamer+cat: 0:00:00.1 0x1 f.c:1:fn: message\n"
+            ),
+            Some("logcat")
+        );
+        assert_eq!(
+            detect("{\"__REALTIME_TIMESTAMP\":\"1\",\"MESSAGE\":\"0:00:00.1 1 0x1 INFO cat f.c:1:fn: message\"}\n"),
+            Some("journald")
+        );
+        // The last line may be cut in the middle
+        assert_eq!(
+            detect("Setting pipeline to PAUSED ...\n0:00:00.1 1 0x1 INFO cat f.c:1:fn: message"),
+            None
+        );
+        for format in FORMATS {
+            assert_eq!(find_format(format.name()).unwrap().name(), format.name());
+        }
+    }
+}
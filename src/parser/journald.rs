@@ -0,0 +1,152 @@
+//! Records of systemd journal exports (`journalctl -o json`)
+//!
+//! Each line of the export is a JSON object. The `MESSAGE` field holds the
+//! GStreamer debug line, a few journal fields are kept alongside the entry.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::format::{parse_debug_line, LogFormat};
+use super::{ansi, ParsedEntry};
+
+// Journal fields of the record an entry was logged in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalFields {
+    // Wall clock time the record was received, in microseconds since the epoch
+    pub realtime_timestamp: u64,
+    // PID of the process that logged the record, as seen by journald
+    pub pid: Option<u32>,
+    // Systemd unit of the process
+    pub unit: Option<String>,
+}
+
+// Export of the systemd journal, one JSON record per line
+pub struct JournaldFormat;
+
+impl LogFormat for JournaldFormat {
+    fn name(&self) -> &'static str {
+        "journald"
+    }
+
+    fn parse_line(&self, line: &str) -> Option<ParsedEntry> {
+        let (message, journal) = parse_record(line)?;
+
+        // Records hold the whole message, including the lines following the first one
+        let message = message.trim_end_matches(['\n', '\r']);
+        let (first_line, rest) = match message.split_once('\n') {
+            Some((first_line, rest)) => (first_line.trim_end_matches('\r'), Some(rest)),
+            None => (message, None),
+        };
+        let mut entry = parse_debug_line(first_line)?;
+        if let Some(rest) = rest {
+            entry.message.push('\n');
+            entry.message.push_str(&ansi::strip_escapes(rest));
+        }
+
+        Some(ParsedEntry {
+            journal: Some(Box::new(journal)),
+            ..ParsedEntry::new(entry)
+        })
+    }
+
+    // Each record is complete, lines that aren't records are never part of a message
+    fn continuation<'a>(&self, _line: &'a str) -> Option<&'a str> {
+        None
+    }
+}
+
+// Parse a line of a journal export into its message and journal fields.
+// Returns `None` if the line isn't a journal record with a message.
+fn parse_record(line: &str) -> Option<(String, JournalFields)> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let record: Map<String, Value> = serde_json::from_str(line).ok()?;
+
+    let message = field_text(record.get("MESSAGE")?)?;
+    let realtime_timestamp = field_text(record.get("__REALTIME_TIMESTAMP")?)?
+        .parse()
+        .ok()?;
+    let pid = record
+        .get("_PID")
+        .and_then(field_text)
+        .and_then(|pid| pid.parse().ok());
+    let unit = record
+        .get("_SYSTEMD_UNIT")
+        .or_else(|| record.get("_SYSTEMD_USER_UNIT"))
+        .and_then(field_text);
+
+    Some((
+        message,
+        JournalFields {
+            realtime_timestamp,
+            pid,
+            unit,
+        },
+    ))
+}
+
+// Text of a field. Journald exports values that aren't printable text, such as
+// messages with escape sequences, as arrays of bytes.
+fn field_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(items) => {
+            let bytes = items
+                .iter()
+                .map(|item| item.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                .collect::<Option<Vec<u8>>>()?;
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_records_with_journal_fields() {
+        let line = r#"{"__REALTIME_TIMESTAMP":"1705314225123456","_PID":"8874","_SYSTEMD_UNIT":"player.service","MESSAGE":"0:00:01.007773544  8874 0x1 INFO  GST_INIT gst.c:510:init_pre: Initializing\ncaps: video/x-raw\n"}"#;
+        let entry = JournaldFormat.parse_line(line).unwrap();
+        assert_eq!(entry.entry.category, "GST_INIT");
+        assert_eq!(entry.entry.message, "Initializing\ncaps: video/x-raw");
+        let journal = entry.journal.unwrap();
+        assert_eq!(journal.realtime_timestamp, 1_705_314_225_123_456);
+        assert_eq!(journal.pid, Some(8874));
+        assert_eq!(journal.unit.as_deref(), Some("player.service"));
+    }
+
+    #[test]
+    fn decodes_messages_exported_as_bytes() {
+        let message: Vec<String> = "0:00:00.1 1 0x1 WARN cat f.c:1:fn: \x1b[31mred"
+            .bytes()
+            .map(|byte| byte.to_string())
+            .collect();
+        let line = format!(
+            r#"{{"__REALTIME_TIMESTAMP":"1","_SYSTEMD_USER_UNIT":"app.service","MESSAGE":[{}]}}"#,
+            message.join(",")
+        );
+        let entry = JournaldFormat.parse_line(&line).unwrap();
+        assert_eq!(entry.entry.message, "red");
+        let journal = entry.journal.unwrap();
+        assert_eq!(journal.pid, None);
+        assert_eq!(journal.unit.as_deref(), Some("app.service"));
+    }
+
+    #[test]
+    fn rejects_other_records() {
+        for line in [
+            "0:00:00.1 1 0x1 WARN cat f.c:1:fn: message",
+            r#"{"__REALTIME_TIMESTAMP":"1","MESSAGE":"Started player.service"}"#,
+            r#"{"MESSAGE":"0:00:00.1 1 0x1 WARN cat f.c:1:fn: message"}"#,
+        ] {
+            assert!(
+                JournaldFormat.parse_line(line).is_none(),
+                "{:?} was parsed",
+                line
+            );
+        }
+    }
+}
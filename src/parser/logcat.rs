@@ -0,0 +1,197 @@
+//! GStreamer debug output logged through Android's logcat
+//!
+//! Both `logcat -v threadtime` and `logcat -v time` lines are supported. The
+//! payload is either a regular GStreamer debug line, or the shorter format used
+//! by GStreamer's logcat debug handler, which has no PID nor level: those are
+//! taken from the logcat header instead.
+
+use gstreamer::DebugLevel;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use super::format::{clock_time, parse_debug_line, LogFormat};
+use super::{Entry, ParsedEntry};
+
+// Tag prefix used by GStreamer's logcat debug handler, followed by the category name
+const GSTREAMER_TAG_PREFIX: &str = "GStreamer+";
+
+lazy_static! {
+    // 01-15 10:23:45.123  1234  1250 D GStreamer+v4l2: payload
+    static ref THREADTIME_RE: Regex = Regex::new(
+        r"^(?:\d{4}-)?\d{2}-\d{2}\s+\d{2}:\d{2}:\d{2}\.\d+\s+(\d+)\s+(\d+)\s+([VDIWEFA])\s+(.*?)\s*: (.*)$"
+    )
+    .unwrap();
+    // 01-15 10:23:45.123 D/GStreamer+v4l2( 1234): payload
+    static ref TIME_RE: Regex = Regex::new(
+        r"^(?:\d{4}-)?\d{2}-\d{2}\s+\d{2}:\d{2}:\d{2}\.\d+\s+([VDIWEFA])/(.*?)\(\s*(\d+)\): (.*)$"
+    )
+    .unwrap();
+    // 0:00:01.234567890 0xb4000071 gstv4l2src.c:123:gst_v4l2src_start:<v4l2src0> message
+    static ref PAYLOAD_RE: Regex = Regex::new(
+        r"^(\d+):(\d{2}):(\d{2})\.(\d+)\s+(0x[0-9a-fA-F]+)\s+([^:]+):(\d+):([^:]*):(<[^>]*>)? ?(.*)$"
+    )
+    .unwrap();
+}
+
+// Fields of the logcat header of a line
+struct Header<'a> {
+    pid: u32,
+    priority: &'a str,
+    tag: &'a str,
+    payload: &'a str,
+}
+
+fn parse_header(line: &str) -> Option<Header<'_>> {
+    if let Some(captures) = THREADTIME_RE.captures(line) {
+        return Some(Header {
+            pid: captures.get(1)?.as_str().parse().ok()?,
+            priority: captures.get(3)?.as_str(),
+            tag: captures.get(4)?.as_str(),
+            payload: captures.get(5)?.as_str(),
+        });
+    }
+
+    let captures = TIME_RE.captures(line)?;
+    Some(Header {
+        pid: captures.get(3)?.as_str().parse().ok()?,
+        priority: captures.get(1)?.as_str(),
+        tag: captures.get(2)?.as_str(),
+        payload: captures.get(4)?.as_str(),
+    })
+}
+
+// Map a logcat priority to the GStreamer level logged with it
+fn priority_to_level(priority: &str) -> DebugLevel {
+    match priority {
+        "E" | "F" | "A" => DebugLevel::Error,
+        "W" => DebugLevel::Warning,
+        "I" => DebugLevel::Info,
+        "D" => DebugLevel::Debug,
+        // GStreamer logs every level above DEBUG as verbose
+        _ => DebugLevel::Log,
+    }
+}
+
+/// Output of `adb logcat`, with GStreamer debug messages wrapped in logcat lines
+pub struct LogcatFormat;
+
+impl LogFormat for LogcatFormat {
+    fn name(&self) -> &'static str {
+        "logcat"
+    }
+
+    fn parse_line(&self, line: &str) -> Option<ParsedEntry> {
+        parse_line(line).map(ParsedEntry::new)
+    }
+
+    // Logcat splits multi-line messages into lines with their own header
+    fn continuation<'a>(&self, line: &'a str) -> Option<&'a str> {
+        let header = parse_header(line)?;
+        if header.tag.starts_with(GSTREAMER_TAG_PREFIX) {
+            Some(header.payload)
+        } else {
+            None
+        }
+    }
+}
+
+// Parse a logcat line wrapping a GStreamer debug message
+fn parse_line(line: &str) -> Option<Entry> {
+    let header = parse_header(line)?;
+
+    // Full GStreamer debug lines, e.g. from stderr redirected to logcat
+    if let Some(entry) = parse_debug_line(header.payload) {
+        return Some(entry);
+    }
+
+    let category = header.tag.strip_prefix(GSTREAMER_TAG_PREFIX)?;
+    let captures = PAYLOAD_RE.captures(header.payload)?;
+
+    let hours: u64 = captures.get(1)?.as_str().parse().ok()?;
+    let minutes: u64 = captures.get(2)?.as_str().parse().ok()?;
+    let seconds: u64 = captures.get(3)?.as_str().parse().ok()?;
+    // Fraction of a second, padded or cut to nanoseconds
+    let fraction = captures.get(4)?.as_str();
+    let nanos: u64 = format!("{:0<9.9}", fraction).parse().ok()?;
+    let ts = clock_time(hours, minutes, seconds, nanos)?;
+
+    let object = captures
+        .get(9)
+        .map(|object| {
+            object
+                .as_str()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+        })
+        .filter(|object| !object.is_empty())
+        .map(|object| object.to_string());
+
+    Some(Entry {
+        ts,
+        pid: header.pid,
+        thread: captures.get(5)?.as_str().to_string(),
+        level: priority_to_level(header.priority),
+        category: category.to_string(),
+        file: captures.get(6)?.as_str().to_string(),
+        line: captures.get(7)?.as_str().parse().ok()?,
+        function: captures.get(8)?.as_str().to_string(),
+        message: captures.get(10)?.as_str().to_string(),
+        object,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_debug_handler_payloads() {
+        for line in [
+            "01-15 10:23:45.123  1234  1250 W GStreamer+v4l2src: 0:00:01.5 0xb4000071 gstv4l2src.c:123:gst_v4l2src_start:<v4l2src0> starting",
+            "01-15 10:23:45.123 W/GStreamer+v4l2src( 1234): 0:00:01.5 0xb4000071 gstv4l2src.c:123:gst_v4l2src_start:<v4l2src0> starting",
+        ] {
+            let entry = parse_line(line).unwrap();
+            assert_eq!(entry.ts.nseconds(), 1_500_000_000);
+            assert_eq!(entry.pid, 1234);
+            assert_eq!(entry.thread, "0xb4000071");
+            assert_eq!(entry.level, DebugLevel::Warning);
+            assert_eq!(entry.category, "v4l2src");
+            assert_eq!(entry.file, "gstv4l2src.c");
+            assert_eq!(entry.line, 123);
+            assert_eq!(entry.function, "gst_v4l2src_start");
+            assert_eq!(entry.object.as_deref(), Some("v4l2src0"));
+            assert_eq!(entry.message, "starting");
+        }
+        // Timestamps too large for a clock time are garbage
+        assert!(parse_line(
+            "01-15 10:23:45.123  1234  1250 D GStreamer+caps: 99999999999999:00:00.0 0x1 f.c:1:f: m"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn parses_wrapped_debug_lines() {
+        let entry = parse_line(
+            "2024-01-15 10:23:45.123  1234  1250 I gst: 0:00:01.007773544  8874 0x1 INFO  GST_INIT gst.c:510:init_pre: Initializing",
+        )
+        .unwrap();
+        // The PID and level of the debug line win over those of the header
+        assert_eq!(entry.pid, 8874);
+        assert_eq!(entry.category, "GST_INIT");
+        assert_eq!(entry.message, "Initializing");
+    }
+
+    #[test]
+    fn continues_with_gstreamer_lines_only() {
+        let format = LogcatFormat;
+        assert_eq!(
+            format.continuation("01-15 10:23:45.123  1234  1250 D GStreamer+caps: width=640"),
+            Some("width=640")
+        );
+        assert_eq!(
+            format.continuation("01-15 10:23:45.123  1234  1250 D ActivityManager: started"),
+            None
+        );
+        assert!(parse_line("01-15 10:23:45.123  1234  1250 D ActivityManager: started").is_none());
+    }
+}
@@ -3,16 +3,305 @@
 //! This module uses the gst-log-parser crate by Guillaume Desmottes
 //! https://github.com/gdesmott/gst-log-parser/
 
+mod ansi;
+mod archive;
+mod compression;
+mod format;
+mod journald;
+mod logcat;
+mod report;
+
 // Re-export the Entry type and other useful types from the crate
 pub use gst_log_parser::Entry;
 
-use std::io::Read;
+pub use archive::{detect_archive, for_each_member};
+pub use compression::decompress;
+pub use format::{detect_format, find_format, LogFormat, DEFAULT_FORMAT};
+pub use journald::JournalFields;
+pub use report::ParseReport;
+
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, Read};
+
+use encoding_rs::Encoding;
+
+/// An entry along with what the log format recorded around it
+#[derive(Debug)]
+pub struct ParsedEntry {
+    pub entry: Entry,
+    // Only set for entries of journal exports, boxed as most logs don't have them
+    pub journal: Option<Box<JournalFields>>,
+    // Position of the line in the log, set by the parser
+    pub line_number: u64,
+    pub offset: u64,
+}
+
+impl ParsedEntry {
+    pub fn new(entry: Entry) -> Self {
+        ParsedEntry {
+            entry,
+            journal: None,
+            line_number: 0,
+            offset: 0,
+        }
+    }
+}
+
+/// Parse GStreamer log entries from a reader in the given format
+///
+/// Lines that can't be parsed are skipped and recorded in the parser's report.
+/// Lines that aren't valid UTF-8 are decoded with the fallback encoding if any,
+/// or with replacement characters. Entries end at the first error reading the log,
+/// such as a truncated compressed file, which is returned with the report.
+pub fn parse<R: Read>(
+    r: R,
+    format: &'static dyn LogFormat,
+    fallback_encoding: Option<&'static Encoding>,
+) -> Parser<R> {
+    Parser {
+        reader: BufReader::new(r),
+        line: Vec::new(),
+        lines: LineParser::new(Some(format), fallback_encoding),
+        error: None,
+    }
+}
+
+/// Iterator over the entries of a log, see [`parse`]
+pub struct Parser<R> {
+    reader: BufReader<R>,
+    // Buffer for the line being read
+    line: Vec<u8>,
+    lines: LineParser,
+    // Error that stopped reading the log
+    error: Option<io::Error>,
+}
+
+impl<R> Parser<R> {
+    /// Report of the lines rejected so far, or the error reading the log if any
+    pub fn into_report(self) -> io::Result<ParseReport> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.lines.report),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Parser<R> {
+    type Item = ParsedEntry;
+
+    fn next(&mut self) -> Option<ParsedEntry> {
+        if self.error.is_some() {
+            return None;
+        }
+        loop {
+            self.line.clear();
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => return self.lines.flush(),
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("Failed to read log: {}", e);
+                    self.error = Some(e);
+                    return None;
+                }
+            }
+            if let Some(entry) = self.lines.parse_line(&self.line) {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+// Maximum number of continuation lines attached to an entry, so that a log
+// followed by unrelated output doesn't end up in a single message
+const MAX_CONTINUATION_LINES: usize = 1000;
+
+// Times in a row a stream is found idle before its last entry is taken, the writer
+// may be in the middle of a multi-line message
+const IDLE_CHECKS_BEFORE_FLUSH: u32 = 4;
+
+// Parses lines one at a time, keeping track of line numbers and rejected lines.
+// An entry is only complete once the next one starts, as it may continue over
+// several lines.
+struct LineParser {
+    // Format of the log, unknown until enough of a stream has been received
+    format: Option<&'static dyn LogFormat>,
+    // Last parsed entry, waiting for its continuation lines
+    current: Option<ParsedEntry>,
+    continuation_lines: usize,
+    line_number: u64,
+    // Offset in bytes of the next line from the start of the log
+    offset: u64,
+    report: ParseReport,
+    // Whether escape sequences were found, the upstream parser doesn't handle them
+    colored: bool,
+    fallback_encoding: Option<&'static Encoding>,
+}
+
+impl LineParser {
+    fn new(
+        format: Option<&'static dyn LogFormat>,
+        fallback_encoding: Option<&'static Encoding>,
+    ) -> Self {
+        LineParser {
+            format,
+            fallback_encoding,
+            current: None,
+            continuation_lines: 0,
+            line_number: 0,
+            offset: 0,
+            report: ParseReport::default(),
+            colored: false,
+        }
+    }
+
+    // Parse a line, returning the previous entry if the line starts a new one
+    fn parse_line(&mut self, line: &[u8]) -> Option<ParsedEntry> {
+        self.line_number += 1;
+        let offset = self.offset;
+        self.offset += line.len() as u64;
+
+        // Messages may contain raw binary data or text in a legacy encoding
+        let line = match std::str::from_utf8(line) {
+            Ok(line) => Cow::Borrowed(line),
+            Err(_) => {
+                self.report.lossy_lines += 1;
+                match self.fallback_encoding {
+                    Some(encoding) => encoding.decode_without_bom_handling(line).0,
+                    None => String::from_utf8_lossy(line),
+                }
+            }
+        };
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line.trim().is_empty() {
+            return None;
+        }
+
+        if !self.colored && ansi::has_escapes(line) {
+            log::info!("Log contains ANSI escape sequences, stripping them");
+            self.colored = true;
+        }
+
+        let format = self.format.unwrap_or(DEFAULT_FORMAT);
+        if let Some(mut entry) = format.parse_line(line) {
+            entry.line_number = self.line_number;
+            entry.offset = offset;
+            self.continuation_lines = 0;
+            return self.current.replace(entry);
+        }
+
+        // Messages such as dumps of caps or SDPs span several lines
+        if let Some(ref mut current) = self.current {
+            if self.continuation_lines < MAX_CONTINUATION_LINES {
+                if let Some(text) = format.continuation(line) {
+                    let message = &mut current.entry.message;
+                    message.push('\n');
+                    message.push_str(&ansi::strip_escapes(text));
+                    self.continuation_lines += 1;
+                    return None;
+                }
+            }
+        }
+
+        self.report
+            .record(self.line_number, &ansi::strip_escapes(line));
+        None
+    }
+
+    // Take the last entry, once no more lines are expected for it
+    fn flush(&mut self) -> Option<ParsedEntry> {
+        self.current.take()
+    }
+}
+
+/// Parser for a log received in arbitrary chunks, such as a file that is still being written
+///
+/// The format of the log is detected from the first complete lines.
+pub struct IncrementalParser {
+    // Trailing bytes of the last chunk that don't form a complete line yet
+    pending: Vec<u8>,
+    lines: LineParser,
+    // Times the stream was found idle since data was last received
+    idle_checks: u32,
+}
+
+impl IncrementalParser {
+    pub fn new(fallback_encoding: Option<&'static Encoding>) -> Self {
+        IncrementalParser {
+            pending: Vec::new(),
+            lines: LineParser::new(None, fallback_encoding),
+            idle_checks: 0,
+        }
+    }
+
+    /// Parse the complete lines received so far, keeping a trailing partial line for later
+    pub fn push(&mut self, data: &[u8]) -> Vec<ParsedEntry> {
+        if !data.is_empty() {
+            self.idle_checks = 0;
+        }
+        self.pending.extend_from_slice(data);
+        let end = match self.pending.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => newline + 1,
+            None => return Vec::new(),
+        };
+        let lines: Vec<u8> = self.pending.drain(..end).collect();
+        self.detect_format(&lines);
+        lines
+            .split_inclusive(|&b| b == b'\n')
+            .filter_map(|line| self.lines.parse_line(line))
+            .collect()
+    }
+
+    /// Parse the remaining partial line once the stream has ended
+    pub fn finish(&mut self) -> Vec<ParsedEntry> {
+        let rest = std::mem::take(&mut self.pending);
+        let mut entries = Vec::new();
+        if !rest.is_empty() {
+            self.detect_format(&rest);
+            entries.extend(self.lines.parse_line(&rest));
+        }
+        entries.extend(self.lines.flush());
+        entries
+    }
+
+    /// Report that no data was received since the last check. The last entry is
+    /// otherwise held until the next one starts in case more lines of its message
+    /// follow, it's taken once the stream has been idle for several checks in a row.
+    pub fn idle(&mut self) -> Option<ParsedEntry> {
+        self.idle_checks += 1;
+        if self.idle_checks < IDLE_CHECKS_BEFORE_FLUSH {
+            return None;
+        }
+        self.lines.flush()
+    }
+
+    // Lines received before the format is known are parsed with the default one
+    fn detect_format(&mut self, lines: &[u8]) {
+        if self.lines.format.is_none() {
+            self.lines.format = detect_format(lines);
+            if let Some(format) = self.lines.format {
+                log::info!("Detected {} log format", format.name());
+            }
+        }
+    }
+
+    /// Name of the detected format of the log
+    pub fn format(&self) -> Option<&'static str> {
+        self.lines.format.map(|format| format.name())
+    }
+
+    /// Drop any partial line, e.g. when the file was truncated
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.idle_checks = 0;
+        self.lines.current = None;
+        self.lines.line_number = 0;
+        self.lines.offset = 0;
+    }
 
-/// Parse GStreamer log entries from a reader
-pub fn parse<R: Read>(r: R) -> impl Iterator<Item = Entry> {
-    // We don't initialize gstreamer here as it's done in main.rs
-    // and we don't want to initialize it multiple times
-    gst_log_parser::parse(r)
+    /// Take the lines rejected since the last call
+    pub fn take_report(&mut self) -> ParseReport {
+        std::mem::take(&mut self.lines.report)
+    }
 }
 
 // Extended functionality for Entry from gst-log-parser
@@ -31,3 +320,161 @@ impl EntryExt for Entry {
         Structure::from_str(&self.message).ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(seconds: u32, message: &str) -> String {
+        format!(
+            "0:00:{:02}.000000000  1 0x1 INFO  cat f.c:1:fn: {}\n",
+            seconds, message
+        )
+    }
+
+    fn messages(entries: &[ParsedEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.entry.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn parses_lines_split_across_chunks() {
+        let log = [line(1, "first"), line(2, "second"), line(3, "third")].concat();
+        let mut parser = IncrementalParser::new(None);
+        let second = log.find("0:00:02").unwrap();
+        let log = log.as_bytes();
+
+        assert!(parser.push(&log[..10]).is_empty());
+        // The entry is held until the next one starts, in case its message continues
+        assert!(parser.push(&log[10..second + 10]).is_empty());
+        assert_eq!(
+            messages(&parser.push(&log[second + 10..])),
+            ["first", "second"]
+        );
+        assert_eq!(parser.format(), Some(DEFAULT_FORMAT.name()));
+        assert_eq!(messages(&parser.finish()), ["third"]);
+    }
+
+    #[test]
+    fn keeps_the_last_entry_while_briefly_idle() {
+        let mut parser = IncrementalParser::new(None);
+        assert!(parser.push(line(1, "caps:").as_bytes()).is_empty());
+        // The writer may still be in the middle of the message at the end of the file
+        for _ in 1..IDLE_CHECKS_BEFORE_FLUSH {
+            assert!(parser.idle().is_none());
+        }
+        assert!(parser.push(b"  width=(int)640\n").is_empty());
+        for _ in 1..IDLE_CHECKS_BEFORE_FLUSH {
+            assert!(parser.idle().is_none());
+        }
+        let entry = parser.idle().unwrap();
+        assert_eq!(entry.entry.message, "caps:\n  width=(int)640");
+        assert_eq!(parser.take_report().rejected_lines, 0);
+        assert!(parser.idle().is_none());
+    }
+
+    #[test]
+    fn parses_the_last_line_without_newline() {
+        let mut parser = IncrementalParser::new(None);
+        assert!(parser
+            .push(line(1, "first").trim_end().as_bytes())
+            .is_empty());
+        assert_eq!(messages(&parser.finish()), ["first"]);
+    }
+
+    #[test]
+    fn drops_partial_lines_on_reset() {
+        let mut parser = IncrementalParser::new(None);
+        parser.push(line(1, "first").as_bytes());
+        parser.push(b"0:00:02.0");
+        parser.reset();
+        let entries = parser.push(line(3, "third").as_bytes());
+        assert!(entries.is_empty());
+        let entries = parser.finish();
+        assert_eq!(messages(&entries), ["third"]);
+        assert_eq!(entries[0].line_number, 1);
+    }
+
+    #[test]
+    fn keeps_line_numbers_and_offsets() {
+        let first = line(1, "first");
+        let log = [first.clone(), "\n".to_string(), line(2, "second")].concat();
+        let mut parser = parse(log.as_bytes(), DEFAULT_FORMAT, None);
+        let entries: Vec<ParsedEntry> = parser.by_ref().collect();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.line_number, entry.offset))
+                .collect::<Vec<_>>(),
+            [(1, 0), (3, first.len() as u64 + 1)]
+        );
+        assert!(parser.into_report().is_ok());
+    }
+
+    #[test]
+    fn attaches_continuation_lines() {
+        let log = [
+            "Setting pipeline to PAUSED ...\n",
+            &line(1, "caps:"),
+            "video/x-raw, format=(string)I420, width=(int)640\n",
+            "00000000: 00 00 00 01 67 42 c0 1e  ....gB..\n",
+            "Pipeline is PREROLLING ...\n",
+            &line(2, "second"),
+        ]
+        .concat();
+        let mut parser = parse(log.as_bytes(), DEFAULT_FORMAT, None);
+        let entries: Vec<ParsedEntry> = parser.by_ref().collect();
+        assert_eq!(
+            messages(&entries),
+            [
+                "caps:\nvideo/x-raw, format=(string)I420, width=(int)640\n00000000: 00 00 00 01 67 42 c0 1e  ....gB..",
+                "second"
+            ]
+        );
+        // Lines that don't look like part of a message, or come before any entry, are rejected
+        let report = parser.into_report().unwrap();
+        assert_eq!(
+            report
+                .samples
+                .iter()
+                .map(|sample| sample.line_number)
+                .collect::<Vec<_>>(),
+            [1, 5]
+        );
+    }
+
+    #[test]
+    fn limits_continuation_lines() {
+        let mut log = line(1, "dump:");
+        for _ in 0..MAX_CONTINUATION_LINES + 5 {
+            log.push_str("  field=(int)1\n");
+        }
+        let mut parser = parse(log.as_bytes(), DEFAULT_FORMAT, None);
+        let entries: Vec<ParsedEntry> = parser.by_ref().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].entry.message.lines().count(),
+            MAX_CONTINUATION_LINES + 1
+        );
+        assert_eq!(parser.into_report().unwrap().rejected_lines, 5);
+    }
+
+    #[test]
+    fn decodes_invalid_utf8_lines() {
+        let mut log = line(1, "caf").into_bytes();
+        log.insert(log.len() - 1, 0xe9);
+        log.extend(line(2, "utf-8 é").into_bytes());
+
+        let mut parser = parse(&log[..], DEFAULT_FORMAT, None);
+        let entries: Vec<ParsedEntry> = parser.by_ref().collect();
+        assert_eq!(messages(&entries), ["caf\u{fffd}", "utf-8 é"]);
+        assert_eq!(parser.into_report().unwrap().lossy_lines, 1);
+
+        let mut parser = parse(&log[..], DEFAULT_FORMAT, Some(encoding_rs::WINDOWS_1252));
+        let entries: Vec<ParsedEntry> = parser.by_ref().collect();
+        assert_eq!(messages(&entries), ["café", "utf-8 é"]);
+        assert_eq!(parser.into_report().unwrap().lossy_lines, 1);
+    }
+}
@@ -0,0 +1,76 @@
+//! Bookkeeping of the lines the parser couldn't make sense of
+
+use serde::{Deserialize, Serialize};
+
+// Maximum number of rejected lines kept as samples
+const MAX_SAMPLES: usize = 100;
+// Rejected lines are truncated to this many characters in samples
+const MAX_SAMPLE_LENGTH: usize = 512;
+
+/// A line that isn't a GStreamer debug entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedLine {
+    // 1-based line number in the source file
+    pub line_number: u64,
+    pub content: String,
+}
+
+/// Count and sample of the lines rejected while parsing a file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ParseReport {
+    pub rejected_lines: u64,
+    pub samples: Vec<RejectedLine>,
+    // Lines that weren't valid UTF-8, decoded with replacement characters or the fallback encoding
+    pub lossy_lines: u64,
+}
+
+impl ParseReport {
+    pub fn record(&mut self, line_number: u64, content: &str) {
+        self.rejected_lines += 1;
+        if self.samples.len() < MAX_SAMPLES {
+            self.samples.push(RejectedLine {
+                line_number,
+                content: content.chars().take(MAX_SAMPLE_LENGTH).collect(),
+            });
+        }
+    }
+
+    /// Whether there is anything to report
+    pub fn is_empty(&self) -> bool {
+        self.rejected_lines == 0 && self.lossy_lines == 0
+    }
+
+    /// Add the rejected lines of a later part of the same file
+    pub fn merge(&mut self, other: ParseReport) {
+        self.rejected_lines += other.rejected_lines;
+        self.lossy_lines += other.lossy_lines;
+        let free = MAX_SAMPLES.saturating_sub(self.samples.len());
+        self.samples.extend(other.samples.into_iter().take(free));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_a_limited_sample() {
+        let mut report = ParseReport::default();
+        assert!(report.is_empty());
+        for line_number in 1..=MAX_SAMPLES as u64 - 1 {
+            report.record(line_number, "rejected");
+        }
+        let mut later = ParseReport::default();
+        later.record(1000, &"x".repeat(MAX_SAMPLE_LENGTH * 2));
+        later.record(1001, "dropped");
+        later.lossy_lines = 1;
+
+        report.merge(later);
+        assert_eq!(report.rejected_lines, MAX_SAMPLES as u64 + 1);
+        assert_eq!(report.lossy_lines, 1);
+        assert_eq!(report.samples.len(), MAX_SAMPLES);
+        let last = report.samples.last().unwrap();
+        assert_eq!(last.line_number, 1000);
+        assert_eq!(last.content.len(), MAX_SAMPLE_LENGTH);
+    }
+}
@@ -0,0 +1,968 @@
+//! Entries of sessions kept in column files on disk and memory-mapped, so
+//! sessions can be larger than memory
+//!
+//! Entries are appended to one file per field as they are parsed, in the order
+//! they arrive. Once all entries are written, the rows are sorted by timestamp
+//! into an order file, and an index of the positions of each value is built for
+//! the fields that are filtered on by equality. Only the strings of the session
+//! stay in memory.
+//!
+//! Rows added to a session later are sorted on their own and merged into the
+//! order, from the first position one of them goes to. The positions from there
+//! on are appended to the indexes, which are rewritten once the appended
+//! positions outnumber the others. Until then, the files the entries read stay as
+//! they are, so that the entries are still whole if adding rows fails.
+
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use gstreamer::DebugLevel;
+use itertools::Itertools;
+use memmap2::{Mmap, MmapOptions};
+
+use crate::models::{EntryFields, EntryJournal, SessionEntry, Symbol, LEVELS};
+
+// Columns of 32-bit values unless noted otherwise, one little-endian value per row
+// 64-bit
+const TS: usize = 0;
+const PID: usize = 1;
+const THREAD: usize = 2;
+// 8-bit index into `LEVELS`
+const LEVEL: usize = 3;
+const CATEGORY: usize = 4;
+const FILE: usize = 5;
+const LINE: usize = 6;
+const FUNCTION: usize = 7;
+const OBJECT: usize = 8;
+const SOURCE: usize = 9;
+// 64-bit
+const LINE_NUMBER: usize = 10;
+// 64-bit
+const OFFSET: usize = 11;
+// 64-bit end of the message of the row in the messages file
+const MESSAGE_END: usize = 12;
+// 64-bit
+const JOURNAL_TIMESTAMP: usize = 13;
+const JOURNAL_PID: usize = 14;
+const JOURNAL_UNIT: usize = 15;
+
+// File name of each column
+const COLUMNS: [&str; 16] = [
+    "ts",
+    "pid",
+    "thread",
+    "level",
+    "category",
+    "file",
+    "line",
+    "function",
+    "object",
+    "source",
+    "line_number",
+    "offset",
+    "message_end",
+    "journal_timestamp",
+    "journal_pid",
+    "journal_unit",
+];
+
+// Messages of all rows, one after the other
+const MESSAGES: &str = "messages";
+// Rows in the order of their timestamp, entries are addressed by their position in it
+const ORDER: &str = "order";
+
+// Rows sorted in memory at once, more new rows are sorted in runs merged from files
+const RUN_ROWS: usize = 1 << 22;
+
+// Value of optional 32-bit fields that are unset
+const NONE_U32: u32 = u32::MAX;
+// Journal timestamp of entries that aren't journal records
+const NONE_U64: u64 = u64::MAX;
+
+// Fields with an index of the positions of each of their values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IndexedField {
+    Source,
+    Level,
+    Category,
+    Thread,
+    Pid,
+    Object,
+    Unit,
+}
+
+const INDEXED_FIELDS: [(IndexedField, usize); 7] = [
+    (IndexedField::Source, SOURCE),
+    (IndexedField::Level, LEVEL),
+    (IndexedField::Category, CATEGORY),
+    (IndexedField::Thread, THREAD),
+    (IndexedField::Pid, PID),
+    (IndexedField::Object, OBJECT),
+    (IndexedField::Unit, JOURNAL_UNIT),
+];
+
+// Directory of the files of a session, removed along with the session
+#[derive(Debug)]
+struct ScratchDir(PathBuf);
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            log::error!("Failed to remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+// Entries being written to the column files of a session
+#[derive(Debug)]
+pub struct MappedWriter {
+    dir: Arc<ScratchDir>,
+    rows: usize,
+    columns: Vec<BufWriter<File>>,
+    messages: BufWriter<File>,
+    messages_len: u64,
+    // Order and indexes of the rows of a previous finish
+    sorted: Option<Sorted>,
+    // First error while writing, reported once all entries are written
+    error: Option<io::Error>,
+}
+
+#[derive(Debug)]
+struct Sorted {
+    rows: usize,
+    order: Arc<Mmap>,
+    indexes: HashMap<IndexedField, Index>,
+}
+
+impl MappedWriter {
+    // Create the files of a new session in `dir`, which must not exist yet
+    pub fn create(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Self::open(Arc::new(ScratchDir(dir)), 0, 0, None)
+    }
+
+    fn open(
+        dir: Arc<ScratchDir>,
+        rows: usize,
+        messages_len: u64,
+        sorted: Option<Sorted>,
+    ) -> io::Result<Self> {
+        // Rows written by an append that failed are dropped. Truncating a mapped file
+        // is only sound because no mapping covers the dropped part: files are mapped
+        // up to the rows of a finish, and only the latest entries of a session are
+        // reopened, the copies still being read map these rows or fewer.
+        let append = |name: &str, len: u64| -> io::Result<BufWriter<File>> {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.0.join(name))?;
+            file.set_len(len)?;
+            Ok(BufWriter::new(file))
+        };
+        let columns = COLUMNS
+            .iter()
+            .enumerate()
+            .map(|(column, name)| append(name, (rows * width(column)) as u64))
+            .collect::<io::Result<_>>()?;
+        let messages = append(MESSAGES, messages_len)?;
+
+        Ok(MappedWriter {
+            dir,
+            rows,
+            columns,
+            messages,
+            messages_len,
+            sorted,
+            error: None,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    // Append an entry
+    pub fn push(&mut self, entry: &SessionEntry) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write_row(entry) {
+            self.error = Some(e);
+        }
+    }
+
+    fn write_row(&mut self, entry: &SessionEntry) -> io::Result<()> {
+        let journal = entry.journal.as_deref();
+        self.messages.write_all(entry.message.as_bytes())?;
+        self.messages_len += entry.message.len() as u64;
+
+        let columns = &mut self.columns;
+        columns[TS].write_all(&entry.ts.to_le_bytes())?;
+        columns[PID].write_all(&entry.pid.to_le_bytes())?;
+        columns[THREAD].write_all(&symbol_value(entry.thread).to_le_bytes())?;
+        columns[LEVEL].write_all(&[level_value(entry.level) as u8])?;
+        columns[CATEGORY].write_all(&symbol_value(entry.category).to_le_bytes())?;
+        columns[FILE].write_all(&symbol_value(entry.file).to_le_bytes())?;
+        columns[LINE].write_all(&entry.line.to_le_bytes())?;
+        columns[FUNCTION].write_all(&symbol_value(entry.function).to_le_bytes())?;
+        columns[OBJECT].write_all(&entry.object.map_or(NONE_U32, symbol_value).to_le_bytes())?;
+        columns[SOURCE].write_all(&(entry.source as u32).to_le_bytes())?;
+        columns[LINE_NUMBER].write_all(&entry.line_number.to_le_bytes())?;
+        columns[OFFSET].write_all(&entry.offset.to_le_bytes())?;
+        columns[MESSAGE_END].write_all(&self.messages_len.to_le_bytes())?;
+        columns[JOURNAL_TIMESTAMP].write_all(
+            &journal
+                .map_or(NONE_U64, |journal| journal.realtime_timestamp)
+                .to_le_bytes(),
+        )?;
+        columns[JOURNAL_PID].write_all(
+            &journal
+                .and_then(|journal| journal.pid)
+                .unwrap_or(NONE_U32)
+                .to_le_bytes(),
+        )?;
+        columns[JOURNAL_UNIT].write_all(
+            &journal
+                .and_then(|journal| journal.unit)
+                .map_or(NONE_U32, symbol_value)
+                .to_le_bytes(),
+        )?;
+
+        self.rows += 1;
+        Ok(())
+    }
+
+    // Sort the new entries by timestamp into the order and the indexes, making the entries readable
+    pub fn finish(mut self) -> io::Result<MappedEntries> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if self.rows > NONE_U32 as usize {
+            return Err(io::Error::other(format!("Too many entries: {}", self.rows)));
+        }
+        for column in &mut self.columns {
+            column.flush()?;
+        }
+        self.messages.flush()?;
+
+        let MappedWriter {
+            dir,
+            rows,
+            columns,
+            sorted,
+            ..
+        } = self;
+        drop(columns);
+        let columns = COLUMNS
+            .iter()
+            .map(|name| map(&dir.0.join(name)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let messages = Arc::new(map(&dir.0.join(MESSAGES))?);
+        let (sorted_rows, sorted_order, mut indexes) = match sorted {
+            Some(sorted) => (sorted.rows, Some(sorted.order), sorted.indexes),
+            None => (0, None, HashMap::new()),
+        };
+        let ts = &columns[TS];
+
+        // Replaced files are only renamed once all of them are written, so that the
+        // files of the entries reopened by a failed append are still theirs
+        let mut renames = Vec::new();
+
+        // Rows before the first position of a new row keep their position
+        let runs = sort_runs(&dir.0, sorted_rows..rows, ts)?;
+        let start = match (runs.iter().map(|run| run.first_ts).min(), &sorted_order) {
+            (Some(first_ts), Some(order)) => partition_point(0..sorted_rows, |position| {
+                u64_at(ts, u32_at(order, position) as usize) <= first_ts
+            }),
+            _ => sorted_rows,
+        };
+        let order = write_order(
+            &dir.0,
+            sorted_order.as_deref(),
+            start,
+            runs,
+            ts,
+            &mut renames,
+        )?;
+
+        for (field, column) in INDEXED_FIELDS {
+            let previous = indexes.remove(&field);
+            let index = Index::write(
+                previous,
+                &dir.0,
+                field,
+                &columns[column],
+                &order,
+                start,
+                &mut renames,
+            )?;
+            indexes.insert(field, index);
+        }
+        for (temp_path, path) in renames {
+            fs::rename(temp_path, path)?;
+        }
+
+        Ok(MappedEntries {
+            dir,
+            rows,
+            columns: Arc::new(columns),
+            messages,
+            order,
+            indexes: Arc::new(indexes),
+        })
+    }
+}
+
+// Entries of a session in memory-mapped column files, ordered by timestamp.
+// Clones share the mapped files, which stay as they are when rows are added:
+// columns are only appended to, other files are replaced.
+#[derive(Debug, Clone)]
+pub struct MappedEntries {
+    dir: Arc<ScratchDir>,
+    rows: usize,
+    columns: Arc<Vec<Mmap>>,
+    messages: Arc<Mmap>,
+    order: Arc<Mmap>,
+    indexes: Arc<HashMap<IndexedField, Index>>,
+}
+
+impl MappedEntries {
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    // Row of the entry at a position
+    fn row(&self, position: usize) -> usize {
+        u32_at(&self.order, position) as usize
+    }
+
+    // Timestamp of the entry at a position, in nanoseconds
+    pub fn ts(&self, position: usize) -> u64 {
+        u64_at(&self.columns[TS], self.row(position))
+    }
+
+    // Fields of the entry at a position, read as they are looked at
+    pub fn fields(&self, position: usize) -> MappedFields<'_> {
+        MappedFields {
+            entries: self,
+            row: self.row(position),
+        }
+    }
+
+    // Read the entry at a position
+    pub fn get(&self, position: usize) -> SessionEntry {
+        let fields = self.fields(position);
+        let row = fields.row;
+        let journal_timestamp = u64_at(&self.columns[JOURNAL_TIMESTAMP], row);
+        let journal = (journal_timestamp != NONE_U64).then(|| {
+            Box::new(EntryJournal {
+                realtime_timestamp: journal_timestamp,
+                pid: fields.journal_pid(),
+                unit: fields.unit(),
+            })
+        });
+
+        SessionEntry {
+            ts: fields.ts(),
+            pid: fields.pid(),
+            thread: fields.thread(),
+            level: fields.level(),
+            category: fields.category(),
+            file: fields.file(),
+            line: fields.line(),
+            function: fields.function(),
+            message: fields.message().into(),
+            object: fields.object(),
+            source: fields.source(),
+            journal,
+            line_number: fields.line_number(),
+            offset: u64_at(&self.columns[OFFSET], row),
+        }
+    }
+
+    // Positions of the entries with a timestamp in the range, in nanoseconds
+    pub fn time_range(&self, min_ts: Option<u64>, max_ts: Option<u64>) -> Range<usize> {
+        let start = min_ts.map_or(0, |min_ts| {
+            partition_point(0..self.rows, |position| self.ts(position) < min_ts)
+        });
+        let end = max_ts.map_or(self.rows, |max_ts| {
+            partition_point(0..self.rows, |position| self.ts(position) <= max_ts)
+        });
+        start..end.max(start)
+    }
+
+    // Sorted positions of the entries whose field has one of the values. Those of
+    // each value are sorted in the index and merged as they are iterated.
+    pub fn positions(
+        &self,
+        field: IndexedField,
+        values: Vec<u32>,
+    ) -> impl Iterator<Item = usize> + '_ {
+        let index = &self.indexes[&field];
+        values
+            .into_iter()
+            .filter_map(|value| index.values.get(&value))
+            .map(|ranges| {
+                ranges
+                    .iter()
+                    .flat_map(|range| range.clone().map(|i| u32_at(&index.positions, i) as usize))
+            })
+            .kmerge()
+    }
+
+    // Distinct values of an indexed field
+    pub fn values(&self, field: IndexedField) -> impl Iterator<Item = u32> + '_ {
+        self.indexes[&field].values.keys().copied()
+    }
+
+    // Open the files again to append entries, the entries have to be finished again to be read
+    pub fn reopen(self) -> io::Result<MappedWriter> {
+        let MappedEntries {
+            dir,
+            rows,
+            messages,
+            order,
+            indexes,
+            ..
+        } = self;
+        let messages_len = messages.len() as u64;
+        drop(messages);
+        let sorted = Sorted {
+            rows,
+            order,
+            indexes: Arc::unwrap_or_clone(indexes),
+        };
+        MappedWriter::open(dir, rows, messages_len, Some(sorted))
+    }
+}
+
+// Fields of an entry of mapped entries
+pub struct MappedFields<'a> {
+    entries: &'a MappedEntries,
+    row: usize,
+}
+
+impl MappedFields<'_> {
+    fn u32_at(&self, column: usize) -> u32 {
+        u32_at(&self.entries.columns[column], self.row)
+    }
+
+    fn u64_at(&self, column: usize) -> u64 {
+        u64_at(&self.entries.columns[column], self.row)
+    }
+
+    fn symbol(&self, column: usize) -> Symbol {
+        Symbol::from_index(self.u32_at(column) as usize)
+    }
+
+    fn optional(&self, column: usize) -> Option<u32> {
+        Some(self.u32_at(column)).filter(|&value| value != NONE_U32)
+    }
+}
+
+impl EntryFields for MappedFields<'_> {
+    fn ts(&self) -> u64 {
+        self.u64_at(TS)
+    }
+
+    fn pid(&self) -> u32 {
+        self.u32_at(PID)
+    }
+
+    fn thread(&self) -> Symbol {
+        self.symbol(THREAD)
+    }
+
+    fn level(&self) -> DebugLevel {
+        LEVELS[self.entries.columns[LEVEL][self.row] as usize]
+    }
+
+    fn category(&self) -> Symbol {
+        self.symbol(CATEGORY)
+    }
+
+    fn file(&self) -> Symbol {
+        self.symbol(FILE)
+    }
+
+    fn line(&self) -> u32 {
+        self.u32_at(LINE)
+    }
+
+    fn function(&self) -> Symbol {
+        self.symbol(FUNCTION)
+    }
+
+    fn message(&self) -> Cow<'_, str> {
+        let start = match self.row {
+            0 => 0,
+            row => u64_at(&self.entries.columns[MESSAGE_END], row - 1) as usize,
+        };
+        let end = self.u64_at(MESSAGE_END) as usize;
+        String::from_utf8_lossy(&self.entries.messages[start..end])
+    }
+
+    fn object(&self) -> Option<Symbol> {
+        self.optional(OBJECT)
+            .map(|object| Symbol::from_index(object as usize))
+    }
+
+    fn source(&self) -> usize {
+        self.u32_at(SOURCE) as usize
+    }
+
+    fn unit(&self) -> Option<Symbol> {
+        self.optional(JOURNAL_UNIT)
+            .map(|unit| Symbol::from_index(unit as usize))
+    }
+
+    fn journal_pid(&self) -> Option<u32> {
+        self.optional(JOURNAL_PID)
+    }
+
+    fn line_number(&self) -> u64 {
+        self.u64_at(LINE_NUMBER)
+    }
+}
+
+// New rows sorted by timestamp, in memory or in a file when there are many
+#[derive(Debug)]
+struct Run {
+    rows: RunRows,
+    first_ts: u64,
+}
+
+#[derive(Debug)]
+enum RunRows {
+    Memory(Vec<u32>),
+    File(Mmap),
+}
+
+impl Run {
+    fn rows(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        match self.rows {
+            RunRows::Memory(ref rows) => Box::new(rows.iter().copied()),
+            RunRows::File(ref rows) => Box::new((0..rows.len() / 4).map(|i| u32_at(rows, i))),
+        }
+    }
+}
+
+// Sort rows by timestamp, in runs of at most `RUN_ROWS` rows. The sort is stable, so
+// rows with the same timestamp stay in the order they were written.
+fn sort_runs(dir: &Path, rows: Range<usize>, ts: &Mmap) -> io::Result<Vec<Run>> {
+    let mut runs = Vec::new();
+    let in_memory = rows.len() <= RUN_ROWS;
+    let mut start = rows.start;
+    while start < rows.end {
+        let end = rows.end.min(start + RUN_ROWS);
+        let mut run: Vec<u32> = (start as u32..end as u32).collect();
+        run.sort_by_key(|&row| u64_at(ts, row as usize));
+        let first_ts = u64_at(ts, run[0] as usize);
+        let rows = if in_memory {
+            RunRows::Memory(run)
+        } else {
+            // The mapping outlives the file, which is only needed while merging
+            let path = dir.join(format!("run{}", runs.len()));
+            let rows = write_u32s(&path, &run)?;
+            fs::remove_file(&path)?;
+            RunRows::File(rows)
+        };
+        runs.push(Run { rows, first_ts });
+        start = end;
+    }
+    Ok(runs)
+}
+
+// Write the order of the rows, keeping the sorted rows before `start` and merging
+// the others with the runs of new rows. The order is appended to when the new rows
+// all come after the sorted ones, and replaced otherwise, by a file to rename.
+fn write_order(
+    dir: &Path,
+    sorted_order: Option<&Mmap>,
+    start: usize,
+    runs: Vec<Run>,
+    ts: &Mmap,
+    renames: &mut Vec<(PathBuf, PathBuf)>,
+) -> io::Result<Arc<Mmap>> {
+    let path = dir.join(ORDER);
+    let sorted_rows = sorted_order.map_or(0, |order| order.len() / 4);
+    let append = start == sorted_rows;
+    let temp_path = path.with_extension("tmp");
+    let mut file = if append {
+        // Only drops what a failed finish appended after the mapped order, see MappedWriter::open
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len((sorted_rows * 4) as u64)?;
+        BufWriter::new(file)
+    } else {
+        BufWriter::new(File::create(&temp_path)?)
+    };
+
+    let mut sources: Vec<Box<dyn Iterator<Item = u32> + '_>> = Vec::new();
+    if let Some(order) = sorted_order {
+        if !append {
+            file.write_all(&order[..start * 4])?;
+        }
+        sources.push(Box::new(
+            (start..sorted_rows).map(|position| u32_at(order, position)),
+        ));
+    }
+    sources.extend(runs.iter().map(Run::rows));
+
+    // Rows with the same timestamp are merged in the order they were written
+    let mut heap = BinaryHeap::new();
+    for (source, rows) in sources.iter_mut().enumerate() {
+        if let Some(row) = rows.next() {
+            heap.push(Reverse((u64_at(ts, row as usize), row, source)));
+        }
+    }
+    while let Some(Reverse((_, row, source))) = heap.pop() {
+        file.write_all(&row.to_le_bytes())?;
+        if let Some(row) = sources[source].next() {
+            heap.push(Reverse((u64_at(ts, row as usize), row, source)));
+        }
+    }
+    file.flush()?;
+    drop(file);
+
+    if append {
+        return Ok(Arc::new(map(&path)?));
+    }
+    let order = map(&temp_path)?;
+    renames.push((temp_path, path));
+    Ok(Arc::new(order))
+}
+
+// Positions of the entries for each value of a field. The positions of a value are
+// sorted, in ranges of the file that were written one after the other.
+#[derive(Debug, Clone)]
+struct Index {
+    values: HashMap<u32, Vec<Range<usize>>>,
+    positions: Arc<Mmap>,
+    // Number of positions in the file
+    len: usize,
+    // Number of positions when the file was last rewritten, the others were appended
+    compacted: usize,
+}
+
+impl Index {
+    // Index the positions from `start` on, in the order, on top of the positions
+    // before `start` of the previous index. A rewritten index is a file to rename.
+    fn write(
+        previous: Option<Index>,
+        dir: &Path,
+        field: IndexedField,
+        column: &Mmap,
+        order: &Mmap,
+        start: usize,
+        renames: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> io::Result<Self> {
+        let rows = order.len() / 4;
+        let value_at = |position: usize| {
+            let row = u32_at(order, position) as usize;
+            match field {
+                IndexedField::Level => column[row] as u32,
+                _ => u32_at(column, row),
+            }
+        };
+
+        // Positions that aren't compacted are garbage once replaced, and split the
+        // positions of values in many ranges. The file is rewritten when they're
+        // more than the others.
+        let (mut values, len, compacted, from) = match previous {
+            Some(index)
+                if start > 0 && index.len - index.compacted + rows - start <= index.compacted =>
+            {
+                let (len, compacted) = (index.len, index.compacted);
+                (index.truncate(start), len, compacted, start)
+            }
+            _ => (HashMap::new(), 0, 0, 0),
+        };
+        let rewrite = from == 0;
+
+        // Positions are laid out value after value, the number of positions of each
+        // value gives where they go
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for position in from..rows {
+            let value = value_at(position);
+            // Only the PID uses the whole range of values, no entry is without one
+            if value == NONE_U32 && field != IndexedField::Pid {
+                continue;
+            }
+            *counts.entry(value).or_default() += 1;
+        }
+        let mut next: HashMap<u32, usize> = HashMap::with_capacity(counts.len());
+        let mut added = 0;
+        for (value, count) in counts {
+            values
+                .entry(value)
+                .or_default()
+                .push(len + added..len + added + count);
+            next.insert(value, added);
+            added += count;
+        }
+
+        let path = dir.join(format!("{:?}.index", field).to_lowercase());
+        let temp_path = path.with_extension("tmp");
+        let written_path = if rewrite { &temp_path } else { &path };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(rewrite)
+            .open(written_path)?;
+        file.set_len(((len + added) * 4) as u64)?;
+        if added > 0 {
+            // Only the new part of the file is mapped, clones of the entries keep
+            // reading the previous one
+            let mut positions = unsafe {
+                MmapOptions::new()
+                    .offset((len * 4) as u64)
+                    .len(added * 4)
+                    .map_mut(&file)?
+            };
+            for position in from..rows {
+                if let Some(i) = next.get_mut(&value_at(position)) {
+                    positions[*i * 4..*i * 4 + 4].copy_from_slice(&(position as u32).to_le_bytes());
+                    *i += 1;
+                }
+            }
+            positions.flush()?;
+        }
+        drop(file);
+        let positions = Arc::new(map(written_path)?);
+        if rewrite {
+            renames.push((temp_path, path));
+        }
+
+        Ok(Index {
+            values,
+            positions,
+            len: len + added,
+            compacted: if rewrite { added } else { compacted },
+        })
+    }
+
+    // Positions of each value before `start`
+    fn truncate(self, start: usize) -> HashMap<u32, Vec<Range<usize>>> {
+        let Index {
+            mut values,
+            positions,
+            ..
+        } = self;
+        values.retain(|_, ranges| {
+            while let Some(range) = ranges.last_mut() {
+                let end =
+                    partition_point(range.clone(), |i| (u32_at(&positions, i) as usize) < start);
+                if end > range.start {
+                    range.end = end;
+                    break;
+                }
+                ranges.pop();
+            }
+            !ranges.is_empty()
+        });
+        values
+    }
+}
+
+// Value of a symbol in the columns and indexes
+pub fn symbol_value(symbol: Symbol) -> u32 {
+    symbol.index() as u32
+}
+
+// Value of a level in the columns and indexes
+pub fn level_value(level: DebugLevel) -> u32 {
+    LEVELS.iter().position(|known| *known == level).unwrap_or(0) as u32
+}
+
+// Bytes of the value of a row in a column
+fn width(column: usize) -> usize {
+    match column {
+        LEVEL => 1,
+        TS | LINE_NUMBER | OFFSET | MESSAGE_END | JOURNAL_TIMESTAMP => 8,
+        _ => 4,
+    }
+}
+
+fn map(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    // The files are private to the server, and mapped parts are never modified nor
+    // truncated: writes append after them, and truncations only drop what was
+    // written after the last finish
+    unsafe { Mmap::map(&file) }
+}
+
+// First index of a range for which `pred` is false, it being true before and false after
+fn partition_point(range: Range<usize>, pred: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (range.start, range.end);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if pred(middle) {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+// Replace a file with values, clones of entries keep the mapping of the previous one
+fn write_u32s(path: &Path, values: &[u32]) -> io::Result<Mmap> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&temp_path)?);
+    for value in values {
+        file.write_all(&value.to_le_bytes())?;
+    }
+    file.flush()?;
+    drop(file);
+    fs::rename(&temp_path, path)?;
+    map(path)
+}
+
+fn u32_at(data: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], index: usize) -> u64 {
+    u64::from_le_bytes(data[index * 8..index * 8 + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterPlan;
+    use crate::models::{Entries, Interner, LogFilter, Session, Source};
+    use crate::parser::ParseReport;
+
+    fn write(writer: &mut MappedWriter, strings: &mut Interner, entries: &[(u64, &str, &str)]) {
+        for &(ts, category, message) in entries {
+            let entry = SessionEntry::for_test(strings, ts, category, DebugLevel::Info, message);
+            writer.push(&entry);
+        }
+    }
+
+    fn messages(entries: &MappedEntries) -> Vec<String> {
+        (0..entries.len())
+            .map(|position| entries.get(position).message.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn merges_appended_rows_into_the_order_and_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut strings = Interner::default();
+        let mut writer = MappedWriter::create(dir.path().join("session")).unwrap();
+        write(
+            &mut writer,
+            &mut strings,
+            &[(3, "a", "3a"), (1, "b", "1b"), (2, "a", "2a")],
+        );
+        let entries = writer.finish().unwrap();
+        assert_eq!(messages(&entries), ["1b", "2a", "3a"]);
+        let first = entries.clone();
+
+        let mut writer = entries.reopen().unwrap();
+        write(&mut writer, &mut strings, &[(5, "b", "5b"), (2, "b", "2b")]);
+        let entries = writer.finish().unwrap();
+        // Rows with the same timestamp stay in the order they were written
+        assert_eq!(messages(&entries), ["1b", "2a", "2b", "3a", "5b"]);
+        assert_eq!(entries.time_range(Some(2), Some(3)), 1..4);
+
+        let category = |name| symbol_value(strings.get(name).unwrap());
+        let positions = |values: Vec<u32>| -> Vec<usize> {
+            entries.positions(IndexedField::Category, values).collect()
+        };
+        assert_eq!(positions(vec![category("a")]), [1, 3]);
+        assert_eq!(positions(vec![category("b")]), [0, 2, 4]);
+        assert_eq!(
+            positions(vec![category("b"), category("a")]),
+            [0, 1, 2, 3, 4]
+        );
+
+        // Copies of the entries from before the append still read their rows
+        assert_eq!(messages(&first), ["1b", "2a", "3a"]);
+    }
+
+    #[test]
+    fn indexes_agree_with_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut memory = Session::new();
+        let mut mapped = Session::new();
+        mapped.entries =
+            Entries::Writing(MappedWriter::create(dir.path().join("session")).unwrap());
+        for session in [&mut memory, &mut mapped] {
+            session.sources = ["a.log", "b.log"]
+                .map(|name| Source {
+                    name: name.to_string(),
+                    format: None,
+                    parse_report: ParseReport::default(),
+                })
+                .to_vec();
+            // Written in two batches, the second one going between the rows of the first
+            for batch in [[0, 2, 4, 6, 8, 10, 1, 3], [5, 7, 9, 11, 13, 12, 15, 14]] {
+                let strings = &mut session.strings;
+                let entries = batch
+                    .into_iter()
+                    .map(|i: usize| {
+                        let category = ["a", "b", "c"][i % 3];
+                        let level = LEVELS[i % 4 + 1];
+                        let message = format!("message {}", i);
+                        let mut entry = SessionEntry::for_test(
+                            strings,
+                            i as u64 * 1_000_000,
+                            category,
+                            level,
+                            &message,
+                        );
+                        entry.pid = 100 + (i % 2) as u32;
+                        entry.thread = strings.intern(["t1", "t2"][i / 2 % 2]);
+                        entry.object = i.is_multiple_of(3).then(|| strings.intern("src"));
+                        entry.source = i / 8;
+                        entry.journal = i.is_multiple_of(2).then(|| {
+                            Box::new(EntryJournal {
+                                realtime_timestamp: i as u64,
+                                pid: None,
+                                unit: Some(strings.intern(["x.service", "y.service"][i / 4 % 2])),
+                            })
+                        });
+                        entry
+                    })
+                    .collect();
+                session.insert_entries(entries).unwrap();
+                session.finish_entries().unwrap();
+            }
+        }
+        assert!(matches!(mapped.entries, Entries::Mapped(_)));
+
+        for filter in [
+            serde_json::json!({}),
+            serde_json::json!({ "level": "Warning" }),
+            serde_json::json!({ "thread": "t2", "pid": 100 }),
+            serde_json::json!({ "object": "src", "source": "b.log" }),
+            serde_json::json!({ "unit": "y.service", "min_timestamp": 3 }),
+            serde_json::json!({ "thread": "t1", "unit": "x.service", "max_timestamp": 12 }),
+            serde_json::json!({ "pid": 101, "message_regex": "[13579]$" }),
+            serde_json::json!({ "unit": "z.service" }),
+        ] {
+            let mut filter = filter;
+            filter["session_id"] = "session".into();
+            let filter: LogFilter = serde_json::from_value(filter).unwrap();
+            let plan = FilterPlan::compile(&filter).unwrap();
+            let page = |session: &Session| -> (usize, Vec<String>) {
+                let (total, entries) = plan.bind(session).page(1, 5).unwrap();
+                let messages = entries
+                    .iter()
+                    .map(|entry| entry.message.to_string())
+                    .collect();
+                (total, messages)
+            };
+            assert_eq!(page(&mapped), page(&memory), "{:?}", filter);
+        }
+    }
+}
@@ -0,0 +1,451 @@
+//! On-disk storage of parsed sessions, so they survive restarts of the server
+//!
+//! Each session is written to its own zstd-compressed file of JSON lines: a
+//! header with the sources and metadata of the session, followed by one line
+//! per entry. Headers are read at startup to list the stored sessions, their
+//! entries are only loaded once the session is accessed. The header is
+//! compressed in a frame of its own, so it can be replaced without
+//! recompressing the entries.
+//!
+//! Sessions can also be stored in an SQLite database, which holds their
+//! entries as well, see `sqlite`.
+
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use gstreamer::{ClockTime, DebugLevel};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::models::{
+    level_from_name, EntryJournal, Interner, Session, SessionEntry, SessionInfo, SessionMetadata,
+    SessionStatus, Source, SourceSummary,
+};
+use crate::parser::{self, JournalFields, ParseReport};
+
+mod mapped;
+mod sqlite;
+
+pub use mapped::{level_value, symbol_value, IndexedField, MappedEntries, MappedWriter};
+pub use sqlite::{SqliteDatabase, SqliteEntries};
+
+// Version of the file format, files of other versions are ignored
+const FORMAT_VERSION: u32 = 1;
+// Extension of session files
+const EXTENSION: &str = "jsonl.zst";
+// Compression level of session files, favoring speed as sessions are written after every upload
+const COMPRESSION_LEVEL: i32 = 3;
+
+// First line of a session file
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    entries: usize,
+    // Timestamps of the first and last entries in nanoseconds, to list sessions without loading them
+    time_span: Option<(u64, u64)>,
+    sources: Vec<StoredSource>,
+    metadata: SessionMetadata,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredSource {
+    name: String,
+    format: Option<String>,
+    parse_report: ParseReport,
+}
+
+// An entry of a session file, borrowing from the session when it is written
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredEntry<'a> {
+    // Timestamp in nanoseconds
+    ts: u64,
+    pid: u32,
+    thread: Cow<'a, str>,
+    level: Cow<'a, str>,
+    category: Cow<'a, str>,
+    file: Cow<'a, str>,
+    line: u32,
+    function: Cow<'a, str>,
+    message: Cow<'a, str>,
+    object: Option<Cow<'a, str>>,
+    source: usize,
+    journal: Option<JournalFields>,
+    line_number: u64,
+    offset: u64,
+}
+
+// Where sessions are stored
+pub enum SessionStore {
+    // Directory with one file per session
+    Files { dir: PathBuf },
+    // Database that also holds the entries of loaded sessions
+    Sqlite(Arc<SqliteDatabase>),
+}
+
+impl SessionStore {
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(SessionStore::Files { dir })
+    }
+
+    pub fn open_sqlite(path: &Path) -> Result<Self> {
+        Ok(SessionStore::Sqlite(Arc::new(SqliteDatabase::open(path)?)))
+    }
+
+    // Directory or database file of the store
+    pub fn path(&self) -> &Path {
+        match self {
+            SessionStore::Files { dir } => dir,
+            SessionStore::Sqlite(db) => db.path(),
+        }
+    }
+
+    // Path of the file of a session. Session IDs come from requests, only
+    // the UUIDs we generate are accepted so they can't point outside the store.
+    fn session_path(dir: &Path, session_id: &str) -> Option<PathBuf> {
+        Uuid::parse_str(session_id).ok()?;
+        Some(dir.join(format!("{}.{}", session_id, EXTENSION)))
+    }
+
+    // Write a session, replacing the stored one if any
+    pub fn save(&self, session_id: &str, session: &Session) -> Result<()> {
+        let dir = match self {
+            SessionStore::Files { dir } => dir,
+            SessionStore::Sqlite(db) => return db.save(session_id, session),
+        };
+        let path = Self::session_path(dir, session_id)
+            .ok_or_else(|| anyhow!("Invalid session ID: {}", session_id))?;
+
+        // Write to a temporary file first, so a crash never leaves a truncated session behind
+        let temp_path = path.with_extension("tmp");
+        let header = Header {
+            version: FORMAT_VERSION,
+            entries: session.entries.len(),
+            time_span: session
+                .time_span()?
+                .map(|(start, end)| (start.nseconds(), end.nseconds())),
+            sources: session.sources.iter().map(StoredSource::new).collect(),
+            metadata: session.metadata.clone(),
+        };
+        let file = write_header(BufWriter::new(File::create(&temp_path)?), &header)?;
+
+        let mut encoder = zstd::Encoder::new(file, COMPRESSION_LEVEL)?;
+        for entry in session.iter_entries() {
+            serde_json::to_writer(&mut encoder, &StoredEntry::new(&*entry?, &session.strings))?;
+            encoder.write_all(b"\n")?;
+        }
+
+        encoder.finish()?.flush()?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    // Replace the metadata of a stored session, leaving its entries as they are
+    pub fn save_metadata(&self, session_id: &str, metadata: &SessionMetadata) -> Result<()> {
+        let dir = match self {
+            SessionStore::Files { dir } => dir,
+            SessionStore::Sqlite(db) => return db.save_metadata(session_id, metadata),
+        };
+        let path = Self::session_path(dir, session_id)
+            .ok_or_else(|| anyhow!("Invalid session ID: {}", session_id))?;
+        let mut input = BufReader::new(File::open(&path)?);
+
+        // Only the first frame is decompressed, it holds the header
+        let mut lines =
+            BufReader::new(zstd::Decoder::with_buffer(&mut input)?.single_frame()).lines();
+        let mut header = read_header(&mut lines)?;
+        header.metadata = metadata.clone();
+
+        let temp_path = path.with_extension("tmp");
+        let mut file = write_header(BufWriter::new(File::create(&temp_path)?), &header)?;
+        // Frames of the entries are copied without being decompressed
+        io::copy(&mut input, &mut file)?;
+
+        file.flush()?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    // Read a stored session into an empty session, returning `None` if there is none with this ID
+    pub fn load(&self, session_id: &str, mut session: Session) -> Result<Option<Session>> {
+        let dir = match self {
+            SessionStore::Files { dir } => dir,
+            SessionStore::Sqlite(db) => return db.load(session_id, session),
+        };
+        let path = match Self::session_path(dir, session_id) {
+            Some(path) if path.exists() => path,
+            _ => return Ok(None),
+        };
+        let mut lines = open_lines(&path)?;
+        let header = read_header(&mut lines)?;
+
+        session.sources = header
+            .sources
+            .into_iter()
+            .map(StoredSource::into_source)
+            .collect();
+        session.metadata = header.metadata;
+
+        // Entries were stored in order, batches are taken as they are
+        let batch_size = session.entries.batch_size().min(header.entries);
+        let mut entries = Vec::with_capacity(batch_size);
+        for line in lines {
+            let entry: StoredEntry = serde_json::from_str(&line?)?;
+            if entry.source >= session.sources.len() {
+                return Err(anyhow!("Entry of unknown source {}", entry.source));
+            }
+            entries.push(entry.into_session_entry(&mut session.strings));
+            if entries.len() >= batch_size {
+                session.insert_entries(std::mem::take(&mut entries))?;
+            }
+        }
+        session.insert_entries(entries)?;
+        session.finish_entries()?;
+
+        Ok(Some(session))
+    }
+
+    // Summary of a stored session, without loading its entries
+    pub fn info(&self, session_id: &str, status: SessionStatus) -> Result<Option<SessionInfo>> {
+        let dir = match self {
+            SessionStore::Files { dir } => dir,
+            SessionStore::Sqlite(db) => return db.info(session_id, status),
+        };
+        let path = match Self::session_path(dir, session_id) {
+            Some(path) if path.exists() => path,
+            _ => return Ok(None),
+        };
+        let header = read_header(&mut open_lines(&path)?)?;
+
+        Ok(Some(SessionInfo::new(
+            session_id,
+            status,
+            &header.metadata,
+            header
+                .sources
+                .into_iter()
+                .map(|source| source.name)
+                .collect(),
+            header.entries,
+            header.time_span.map(|(start, end)| {
+                (
+                    ClockTime::from_nseconds(start),
+                    ClockTime::from_nseconds(end),
+                )
+            }),
+        )))
+    }
+
+    // Metadata and sources of a stored session, without loading its entries
+    pub fn summary(
+        &self,
+        session_id: &str,
+    ) -> Result<Option<(SessionMetadata, Vec<SourceSummary>)>> {
+        let dir = match self {
+            SessionStore::Files { dir } => dir,
+            SessionStore::Sqlite(db) => return db.summary(session_id),
+        };
+        let path = match Self::session_path(dir, session_id) {
+            Some(path) if path.exists() => path,
+            _ => return Ok(None),
+        };
+        let header = read_header(&mut open_lines(&path)?)?;
+
+        let sources = header
+            .sources
+            .into_iter()
+            .map(|source| source.into_source().summary())
+            .collect();
+        Ok(Some((header.metadata, sources)))
+    }
+
+    // Remove a stored session, if any
+    pub fn remove(&self, session_id: &str) -> Result<()> {
+        let dir = match self {
+            SessionStore::Files { dir } => dir,
+            SessionStore::Sqlite(db) => return db.remove(session_id),
+        };
+        match Self::session_path(dir, session_id) {
+            Some(path) if path.exists() => Ok(fs::remove_file(path)?),
+            _ => Ok(()),
+        }
+    }
+
+    // List the stored sessions along with their number of entries
+    pub fn list(&self) -> Result<Vec<(String, usize)>> {
+        let dir = match self {
+            SessionStore::Files { dir } => dir,
+            SessionStore::Sqlite(db) => return db.list(),
+        };
+        let mut sessions = Vec::new();
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            let session_id = match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(&format!(".{}", EXTENSION)))
+            {
+                Some(session_id) => session_id.to_string(),
+                None => continue,
+            };
+
+            let header = open_lines(&path).and_then(|mut lines| read_header(&mut lines));
+            match header {
+                Ok(header) => sessions.push((session_id, header.entries)),
+                Err(e) => log::warn!("Ignoring stored session {}: {}", path.display(), e),
+            }
+        }
+        Ok(sessions)
+    }
+}
+
+fn open_lines(path: &Path) -> Result<io::Lines<impl BufRead>> {
+    let file = File::open(path)?;
+    let decoder = zstd::Decoder::new(file)?;
+    Ok(BufReader::new(decoder).lines())
+}
+
+// Write the header in a frame of its own
+fn write_header<W: Write>(writer: W, header: &Header) -> Result<W> {
+    let mut encoder = zstd::Encoder::new(writer, COMPRESSION_LEVEL)?;
+    serde_json::to_writer(&mut encoder, header)?;
+    encoder.write_all(b"\n")?;
+    Ok(encoder.finish()?)
+}
+
+fn read_header(lines: &mut impl Iterator<Item = io::Result<String>>) -> Result<Header> {
+    let line = lines.next().context("Empty session file")??;
+    let header: Header = serde_json::from_str(&line)?;
+    if header.version != FORMAT_VERSION {
+        return Err(anyhow!("Unsupported version {}", header.version));
+    }
+    Ok(header)
+}
+
+impl StoredSource {
+    fn new(source: &Source) -> Self {
+        StoredSource {
+            name: source.name.clone(),
+            format: source.format.map(str::to_string),
+            parse_report: source.parse_report.clone(),
+        }
+    }
+
+    fn into_source(self) -> Source {
+        Source {
+            name: self.name,
+            // Formats are identified by their static name
+            format: self
+                .format
+                .and_then(|name| parser::find_format(&name))
+                .map(|format| format.name()),
+            parse_report: self.parse_report,
+        }
+    }
+}
+
+impl<'a> StoredEntry<'a> {
+    fn new(entry: &'a SessionEntry, strings: &'a Interner) -> Self {
+        StoredEntry {
+            ts: entry.ts,
+            pid: entry.pid,
+            thread: Cow::Borrowed(strings.resolve(entry.thread)),
+            level: Cow::Owned(format!("{:?}", entry.level)),
+            category: Cow::Borrowed(strings.resolve(entry.category)),
+            file: Cow::Borrowed(strings.resolve(entry.file)),
+            line: entry.line,
+            function: Cow::Borrowed(strings.resolve(entry.function)),
+            message: Cow::Borrowed(&entry.message),
+            object: entry
+                .object
+                .map(|object| Cow::Borrowed(strings.resolve(object))),
+            source: entry.source,
+            journal: entry
+                .journal
+                .as_ref()
+                .map(|journal| journal.resolve(strings)),
+            line_number: entry.line_number,
+            offset: entry.offset,
+        }
+    }
+
+    fn into_session_entry(self, strings: &mut Interner) -> SessionEntry {
+        SessionEntry {
+            ts: self.ts,
+            pid: self.pid,
+            thread: strings.intern(&self.thread),
+            level: level_from_name(&self.level).unwrap_or(DebugLevel::None),
+            category: strings.intern(&self.category),
+            file: strings.intern(&self.file),
+            line: self.line,
+            function: strings.intern(&self.function),
+            message: self.message.into(),
+            object: self.object.map(|object| strings.intern(&object)),
+            source: self.source,
+            journal: self
+                .journal
+                .map(|journal| Box::new(EntryJournal::new(&journal, strings))),
+            line_number: self.line_number,
+            offset: self.offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SerializableEntry;
+
+    fn serialized(session: &Session) -> Vec<serde_json::Value> {
+        session
+            .iter_entries()
+            .map(|entry| serde_json::to_value(SerializableEntry::new(&entry.unwrap(), session)))
+            .collect::<serde_json::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn loads_saved_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::open(dir.path().to_path_buf()).unwrap();
+        let session_id = Uuid::new_v4().to_string();
+
+        let mut session = Session::new();
+        session.add_source(
+            "app.log".to_string(),
+            Some("journald"),
+            ParseReport::default(),
+        );
+        let strings = &mut session.strings;
+        let mut first = SessionEntry::for_test(strings, 1, "a", DebugLevel::Warning, "first");
+        first.object = Some(strings.intern("src"));
+        first.line_number = 3;
+        first.journal = Some(Box::new(EntryJournal {
+            realtime_timestamp: 42,
+            pid: Some(7),
+            unit: Some(strings.intern("player.service")),
+        }));
+        let second = SessionEntry::for_test(strings, 2, "b", DebugLevel::Memdump, "second\nline");
+        session.insert_entries(vec![first, second]).unwrap();
+        store.save(&session_id, &session).unwrap();
+
+        let mut metadata = session.metadata.clone();
+        metadata.name = Some("renamed".to_string());
+        store.save_metadata(&session_id, &metadata).unwrap();
+
+        let loaded = store.load(&session_id, Session::new()).unwrap().unwrap();
+        assert_eq!(serialized(&loaded), serialized(&session));
+        assert_eq!(loaded.metadata.name.as_deref(), Some("renamed"));
+        assert_eq!(loaded.sources[0].format, Some("journald"));
+        assert_eq!(store.list().unwrap(), [(session_id.clone(), 2)]);
+
+        store.remove(&session_id).unwrap();
+        assert!(store.load(&session_id, Session::new()).unwrap().is_none());
+        // Only IDs of sessions are accepted, they can't point outside the store
+        assert!(store.load("../sessions", Session::new()).unwrap().is_none());
+    }
+}
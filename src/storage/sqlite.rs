@@ -0,0 +1,935 @@
+//! Sessions stored in an embedded SQLite database, along with their entries
+//!
+//! Entries of all sessions share one table, keyed by the session they belong
+//! to, with indexes on the fields that are filtered on by equality and a
+//! full-text index of the messages. The query handlers translate filters to
+//! SQL, so only the matching entries are ever read. Entries stay in the
+//! database when a session is unloaded, only the strings of loaded sessions
+//! are kept in memory.
+
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use gstreamer::{ClockTime, DebugLevel};
+use regex::Regex;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use uuid::Uuid;
+
+use super::{level_value, symbol_value, IndexedField, StoredSource};
+use crate::models::{
+    Entries, EntryJournal, Session, SessionEntry, SessionInfo, SessionMetadata, SessionStatus,
+    SourceSummary, Symbol, LEVELS,
+};
+
+// Version of the schema, databases of other versions are refused
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    -- Key of the rows of the session in the entries table
+    entries TEXT NOT NULL,
+    entry_count INTEGER NOT NULL,
+    -- Timestamps of the first and last entries in nanoseconds
+    start_ts INTEGER,
+    end_ts INTEGER,
+    -- JSON of the sources, metadata and interned strings of the session
+    sources TEXT NOT NULL,
+    metadata TEXT NOT NULL,
+    strings TEXT NOT NULL
+);
+
+-- Thread, category, file, function, object and unit are symbols of the strings
+-- of the session, levels are indexes into the known levels
+CREATE TABLE entries (
+    id INTEGER PRIMARY KEY,
+    session TEXT NOT NULL,
+    -- Position of the entry once the entries of the session are ordered by timestamp
+    position INTEGER,
+    ts INTEGER NOT NULL,
+    pid INTEGER NOT NULL,
+    thread INTEGER NOT NULL,
+    level INTEGER NOT NULL,
+    category INTEGER NOT NULL,
+    file INTEGER NOT NULL,
+    line INTEGER NOT NULL,
+    function INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    object INTEGER,
+    source INTEGER NOT NULL,
+    line_number INTEGER NOT NULL,
+    byte_offset INTEGER NOT NULL,
+    -- Set for entries of journal exports only
+    journal_timestamp INTEGER,
+    journal_pid INTEGER,
+    unit INTEGER
+);
+CREATE INDEX entries_position ON entries (session, position);
+CREATE INDEX entries_ts ON entries (session, ts);
+CREATE INDEX entries_level ON entries (session, level);
+CREATE INDEX entries_category ON entries (session, category);
+CREATE INDEX entries_thread ON entries (session, thread);
+CREATE INDEX entries_object ON entries (session, object);
+
+-- Trigrams of the messages, so substrings can be looked up
+CREATE VIRTUAL TABLE messages USING fts5(
+    message, content = 'entries', content_rowid = 'id', tokenize = 'trigram'
+);
+CREATE TRIGGER entries_insert AFTER INSERT ON entries BEGIN
+    INSERT INTO messages (rowid, message) VALUES (new.id, new.message);
+END;
+CREATE TRIGGER entries_delete AFTER DELETE ON entries BEGIN
+    INSERT INTO messages (messages, rowid, message) VALUES ('delete', old.id, old.message);
+END;
+";
+
+// Columns of an entry, in the order they are inserted and read
+const ENTRY_COLUMNS: &str = "ts, pid, thread, level, category, file, line, function, message, \
+    object, source, line_number, byte_offset, journal_timestamp, journal_pid, unit";
+
+// Number of entries copied at once into the database
+const BATCH_SIZE: usize = 64 * 1024;
+
+// Idle connections kept for reading, more are opened while they are all in use
+const MAX_IDLE_READERS: usize = 8;
+
+// Database of stored sessions and their entries
+#[derive(Debug)]
+pub struct SqliteDatabase {
+    path: PathBuf,
+    // SQLite has a single writer at a time anyway
+    conn: Mutex<Connection>,
+    // Connections for queries, which don't wait for the writer or for each other
+    // as the database is in WAL mode
+    readers: Mutex<Vec<Connection>>,
+}
+
+impl SqliteDatabase {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let conn = connect(path)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+
+        let version: i64 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        match version {
+            0 => conn.execute_batch(&format!(
+                "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+                SCHEMA, SCHEMA_VERSION
+            ))?,
+            SCHEMA_VERSION => {}
+            _ => return Err(anyhow!("Unsupported database version {}", version)),
+        }
+
+        Ok(SqliteDatabase {
+            path: path.to_path_buf(),
+            conn: Mutex::new(conn),
+            readers: Mutex::new(Vec::new()),
+        })
+    }
+
+    // Run a query on a connection of its own, opening one if all are in use
+    fn read<T>(
+        &self,
+        query: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+    ) -> rusqlite::Result<T> {
+        let reader = self.readers.lock().unwrap().pop();
+        let conn = match reader {
+            Some(conn) => conn,
+            None => connect(&self.path)?,
+        };
+        let result = query(&conn);
+        let mut readers = self.readers.lock().unwrap();
+        if readers.len() < MAX_IDLE_READERS {
+            readers.push(conn);
+        }
+        result
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Write a session, replacing the stored one if any. Entries already in the
+    // database are kept where they are, others are copied into it.
+    pub fn save(self: &Arc<Self>, session_id: &str, session: &Session) -> Result<()> {
+        let copy;
+        let entries = match session.entries {
+            Entries::Sqlite(ref entries) if Arc::ptr_eq(&entries.rows.db, self) => entries,
+            _ => {
+                let mut entries = SqliteEntries::create(self.clone());
+                let mut batch = Vec::with_capacity(BATCH_SIZE.min(session.entries.len()));
+                for entry in session.iter_entries() {
+                    batch.push(entry?.into_owned());
+                    if batch.len() >= BATCH_SIZE {
+                        entries.insert(&batch);
+                        batch.clear();
+                    }
+                }
+                entries.insert(&batch);
+                entries.finish()?;
+                copy = entries;
+                &copy
+            }
+        };
+
+        let time_span = session
+            .time_span()?
+            .map(|(start, end)| (start.nseconds() as i64, end.nseconds() as i64));
+        let sources: Vec<StoredSource> = session.sources.iter().map(StoredSource::new).collect();
+        let strings: Vec<&str> = session.strings.iter().map(|(_, string)| string).collect();
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let previous: Option<String> = tx
+            .query_row(
+                "SELECT entries FROM sessions WHERE id = ?1",
+                params![session_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        tx.execute(
+            "INSERT INTO sessions (id, entries, entry_count, start_ts, end_ts, sources, metadata, strings)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (id) DO UPDATE SET
+                entries = excluded.entries,
+                entry_count = excluded.entry_count,
+                start_ts = excluded.start_ts,
+                end_ts = excluded.end_ts,
+                sources = excluded.sources,
+                metadata = excluded.metadata,
+                strings = excluded.strings",
+            params![
+                session_id,
+                entries.rows.key,
+                entries.len() as i64,
+                time_span.map(|(start, _)| start),
+                time_span.map(|(_, end)| end),
+                serde_json::to_string(&sources)?,
+                serde_json::to_string(&session.metadata)?,
+                serde_json::to_string(&strings)?,
+            ],
+        )?;
+        // Entries copied by a previous save of a session that isn't in the database
+        if let Some(previous) = previous.filter(|previous| *previous != entries.rows.key) {
+            tx.execute("DELETE FROM entries WHERE session = ?1", params![previous])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Replace the metadata of a stored session
+    pub fn save_metadata(&self, session_id: &str, metadata: &SessionMetadata) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE sessions SET metadata = ?2 WHERE id = ?1",
+            params![session_id, serde_json::to_string(metadata)?],
+        )?;
+        Ok(())
+    }
+
+    // Open a stored session in an empty session, returning `None` if there is none with this ID.
+    // Entries are left in the database, they are read when they are queried.
+    pub fn load(
+        self: &Arc<Self>,
+        session_id: &str,
+        mut session: Session,
+    ) -> Result<Option<Session>> {
+        let row = self.read(|conn| {
+            conn.query_row(
+                "SELECT entries, entry_count, sources, metadata, strings FROM sessions WHERE id = ?1",
+                params![session_id],
+                |row| {
+                    let key: String = row.get(0)?;
+                    let entry_count: i64 = row.get(1)?;
+                    let sources: String = row.get(2)?;
+                    let metadata: String = row.get(3)?;
+                    let strings: String = row.get(4)?;
+                    Ok((key, entry_count, sources, metadata, strings))
+                },
+            )
+            .optional()
+        })?;
+        let (key, entry_count, sources, metadata, strings) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let sources: Vec<StoredSource> = serde_json::from_str(&sources)?;
+        session.sources = sources.into_iter().map(StoredSource::into_source).collect();
+        session.metadata = serde_json::from_str(&metadata)?;
+        // Interned in the same order, the symbols of the rows stay valid
+        let strings: Vec<String> = serde_json::from_str(&strings)?;
+        for string in &strings {
+            session.strings.intern(string);
+        }
+        session.entries =
+            Entries::Sqlite(SqliteEntries::open(self.clone(), key, entry_count as usize));
+
+        Ok(Some(session))
+    }
+
+    // Summary of a stored session, without loading its entries
+    pub fn info(&self, session_id: &str, status: SessionStatus) -> Result<Option<SessionInfo>> {
+        let row = self.read(|conn| {
+            conn.query_row(
+                "SELECT entry_count, start_ts, end_ts, sources, metadata FROM sessions WHERE id = ?1",
+                params![session_id],
+                |row| {
+                    let entry_count: i64 = row.get(0)?;
+                    let start_ts: Option<i64> = row.get(1)?;
+                    let end_ts: Option<i64> = row.get(2)?;
+                    let sources: String = row.get(3)?;
+                    let metadata: String = row.get(4)?;
+                    Ok((entry_count, start_ts.zip(end_ts), sources, metadata))
+                },
+            )
+            .optional()
+        })?;
+        let (entry_count, time_span, sources, metadata) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let sources: Vec<StoredSource> = serde_json::from_str(&sources)?;
+        let metadata: SessionMetadata = serde_json::from_str(&metadata)?;
+        Ok(Some(SessionInfo::new(
+            session_id,
+            status,
+            &metadata,
+            sources.into_iter().map(|source| source.name).collect(),
+            entry_count as usize,
+            time_span.map(|(start, end)| {
+                (
+                    ClockTime::from_nseconds(start as u64),
+                    ClockTime::from_nseconds(end as u64),
+                )
+            }),
+        )))
+    }
+
+    // Metadata and sources of a stored session, without opening its entries
+    pub fn summary(
+        &self,
+        session_id: &str,
+    ) -> Result<Option<(SessionMetadata, Vec<SourceSummary>)>> {
+        let row = self.read(|conn| {
+            conn.query_row(
+                "SELECT sources, metadata FROM sessions WHERE id = ?1",
+                params![session_id],
+                |row| {
+                    let sources: String = row.get(0)?;
+                    let metadata: String = row.get(1)?;
+                    Ok((sources, metadata))
+                },
+            )
+            .optional()
+        })?;
+        let (sources, metadata) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let sources: Vec<StoredSource> = serde_json::from_str(&sources)?;
+        let metadata: SessionMetadata = serde_json::from_str(&metadata)?;
+        Ok(Some((
+            metadata,
+            sources
+                .into_iter()
+                .map(|source| source.into_source().summary())
+                .collect(),
+        )))
+    }
+
+    // Remove a stored session and its entries, if any
+    pub fn remove(&self, session_id: &str) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM entries WHERE session = (SELECT entries FROM sessions WHERE id = ?1)",
+            params![session_id],
+        )?;
+        tx.execute("DELETE FROM sessions WHERE id = ?1", params![session_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // List the stored sessions along with their number of entries
+    pub fn list(&self) -> Result<Vec<(String, usize)>> {
+        let sessions = self.read(|conn| {
+            let mut statement = conn.prepare("SELECT id, entry_count FROM sessions")?;
+            let sessions = statement
+                .query_map(params![], |row| {
+                    let session_id: String = row.get(0)?;
+                    let entry_count: i64 = row.get(1)?;
+                    Ok((session_id, entry_count as usize))
+                })?
+                .collect();
+            sessions
+        })?;
+        Ok(sessions)
+    }
+}
+
+// Entries of a session in the database. They are ordered by timestamp once
+// finished, until then they can only be added to.
+#[derive(Debug)]
+pub struct SqliteEntries {
+    // Shared with the copies of the session being stored
+    rows: Arc<Rows>,
+    len: usize,
+    // Rows were added since positions were last assigned
+    writing: bool,
+    // First error while adding rows, reported once all entries are added
+    error: Option<rusqlite::Error>,
+}
+
+// Rows of the entries of a session
+#[derive(Debug)]
+struct Rows {
+    db: Arc<SqliteDatabase>,
+    // Value of the session column of the rows, a stored session refers to it
+    key: String,
+}
+
+impl SqliteEntries {
+    pub fn create(db: Arc<SqliteDatabase>) -> Self {
+        SqliteEntries {
+            writing: true,
+            ..Self::open(db, Uuid::new_v4().to_string(), 0)
+        }
+    }
+
+    fn open(db: Arc<SqliteDatabase>, key: String, len: usize) -> Self {
+        SqliteEntries {
+            rows: Arc::new(Rows { db, key }),
+            len,
+            writing: false,
+            error: None,
+        }
+    }
+
+    // The same entries, as long as they aren't being added to
+    pub fn snapshot(&self) -> Option<Self> {
+        (!self.writing).then(|| SqliteEntries {
+            rows: self.rows.clone(),
+            len: self.len,
+            writing: false,
+            error: None,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_writing(&self) -> bool {
+        self.writing
+    }
+
+    // Add entries, their symbols are those of the strings of the session
+    pub fn insert(&mut self, entries: &[SessionEntry]) {
+        if self.error.is_some() || entries.is_empty() {
+            return;
+        }
+        self.writing = true;
+        match self.insert_rows(entries) {
+            Ok(()) => self.len += entries.len(),
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    fn insert_rows(&self, entries: &[SessionEntry]) -> rusqlite::Result<()> {
+        let mut conn = self.rows.db.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut statement = tx.prepare_cached(&format!(
+                "INSERT INTO entries (session, {}) VALUES ({})",
+                ENTRY_COLUMNS,
+                vec!["?"; 17].join(", ")
+            ))?;
+            for entry in entries {
+                let journal = entry.journal.as_deref();
+                statement.execute(params![
+                    self.rows.key,
+                    entry.ts as i64,
+                    entry.pid,
+                    symbol_value(entry.thread),
+                    level_value(entry.level),
+                    symbol_value(entry.category),
+                    symbol_value(entry.file),
+                    entry.line,
+                    symbol_value(entry.function),
+                    &*entry.message,
+                    entry.object.map(symbol_value),
+                    entry.source as i64,
+                    entry.line_number as i64,
+                    entry.offset as i64,
+                    journal.map(|journal| journal.realtime_timestamp as i64),
+                    journal.and_then(|journal| journal.pid),
+                    journal.and_then(|journal| journal.unit).map(symbol_value),
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    // Order the new rows by timestamp among the others, making the entries readable
+    // once all of them are added
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(io::Error::other(e));
+        }
+        if !self.writing {
+            return Ok(());
+        }
+
+        self.order_added().map_err(io::Error::other)?;
+        self.writing = false;
+        Ok(())
+    }
+
+    // Assign positions to the rows added since the last finish
+    fn order_added(&self) -> rusqlite::Result<()> {
+        let conn = self.rows.db.conn.lock().unwrap();
+        let key = &self.rows.key;
+        let first_ts: Option<i64> = conn.query_row(
+            "SELECT min(ts) FROM entries WHERE session = ?1 AND position IS NULL",
+            params![key],
+            |row| row.get(0),
+        )?;
+        if let Some(first_ts) = first_ts {
+            // Ordered rows keep their position up to the first one the new rows go
+            // before. New rows usually come after all of them and are only numbered.
+            let start: i64 = conn.query_row(
+                "SELECT ifnull(
+                    (SELECT min(position) FROM entries
+                     WHERE session = ?1 AND ts > ?2 AND position IS NOT NULL),
+                    (SELECT ifnull(max(position) + 1, 0) FROM entries WHERE session = ?1)
+                 )",
+                params![key, first_ts],
+                |row| row.get(0),
+            )?;
+
+            // Rows with the same timestamp keep the order they were added in
+            conn.execute(
+                "UPDATE entries SET position = ranked.position
+                 FROM (
+                    SELECT id, ?2 + row_number() OVER (ORDER BY ts, id) - 1 AS position
+                    FROM entries
+                    WHERE session = ?1 AND (position IS NULL OR position >= ?2)
+                 ) AS ranked
+                 WHERE entries.id = ranked.id",
+                params![key, start],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Add rows again, the entries can't be read until they are finished
+    pub fn reopen(&mut self) {
+        self.writing = true;
+    }
+
+    // Remove the rows added since the entries were last finished
+    pub fn discard_added(&self) -> io::Result<()> {
+        self.rows
+            .db
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM entries WHERE session = ?1 AND position IS NULL",
+                params![self.rows.key],
+            )
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    // Timestamp of the entry at a position, in nanoseconds
+    pub fn ts(&self, position: usize) -> io::Result<u64> {
+        let ts: i64 = self.read(|conn| {
+            conn.query_row(
+                "SELECT ts FROM entries WHERE session = ?1 AND position = ?2",
+                params![self.rows.key, position as i64],
+                |row| row.get(0),
+            )
+        })?;
+        Ok(ts as u64)
+    }
+
+    pub fn get(&self, position: usize) -> io::Result<SessionEntry> {
+        self.get_range(position..position + 1)?
+            .pop()
+            .ok_or_else(|| io::Error::other(rusqlite::Error::QueryReturnedNoRows))
+    }
+
+    // Entries at a range of positions, read at once
+    pub fn get_range(&self, positions: Range<usize>) -> io::Result<Vec<SessionEntry>> {
+        self.read(|conn| {
+            let mut statement = conn.prepare_cached(&format!(
+                "SELECT {} FROM entries WHERE session = ?1 AND position >= ?2 AND position < ?3
+                 ORDER BY position",
+                ENTRY_COLUMNS
+            ))?;
+            let entries = statement
+                .query_map(
+                    params![self.rows.key, positions.start as i64, positions.end as i64],
+                    read_entry,
+                )?
+                .collect();
+            entries
+        })
+    }
+
+    // Number of entries matching an SQL condition on the columns of the entries
+    // table. The condition uses `?` for its parameters.
+    pub fn count(&self, condition: &str, params: Vec<Value>) -> io::Result<usize> {
+        let count: i64 = self.read(|conn| {
+            let params = std::iter::once(Value::Text(self.rows.key.clone())).chain(params);
+            conn.query_row(
+                &format!(
+                    "SELECT count(*) FROM entries WHERE session = ? AND ({})",
+                    condition
+                ),
+                params_from_iter(params),
+                |row| row.get(0),
+            )
+        })?;
+        Ok(count as usize)
+    }
+
+    // Entries matching an SQL condition in order, from the `offset`th one on and
+    // at most `limit` of them
+    pub fn select_page(
+        &self,
+        condition: &str,
+        mut params: Vec<Value>,
+        offset: usize,
+        limit: usize,
+    ) -> io::Result<Vec<SessionEntry>> {
+        params.push(Value::Integer(i64::try_from(limit).unwrap_or(i64::MAX)));
+        params.push(Value::Integer(i64::try_from(offset).unwrap_or(i64::MAX)));
+        self.read(|conn| {
+            let mut statement = conn.prepare(&format!(
+                "SELECT {} FROM entries WHERE session = ? AND ({}) ORDER BY position
+                 LIMIT ? OFFSET ?",
+                ENTRY_COLUMNS, condition
+            ))?;
+            let params = std::iter::once(Value::Text(self.rows.key.clone())).chain(params);
+            let entries = statement
+                .query_map(params_from_iter(params), read_entry)?
+                .collect();
+            entries
+        })
+    }
+
+    // Call `f` with the timestamp in nanoseconds of each entry matching an SQL
+    // condition, in order. Rows are read as they are needed.
+    pub fn for_each_ts(
+        &self,
+        condition: &str,
+        params: Vec<Value>,
+        mut f: impl FnMut(u64),
+    ) -> io::Result<()> {
+        self.scan(
+            "ts",
+            condition,
+            params,
+            |row| row.get(0),
+            |ts: i64| f(ts as u64),
+        )
+    }
+
+    // Call `f` with each entry matching an SQL condition, in order
+    pub fn for_each_entry(
+        &self,
+        condition: &str,
+        params: Vec<Value>,
+        f: impl FnMut(SessionEntry),
+    ) -> io::Result<()> {
+        self.scan(ENTRY_COLUMNS, condition, params, read_entry, f)
+    }
+
+    // Call `f` with the columns of each row matching an SQL condition as read by `read_row`
+    fn scan<T>(
+        &self,
+        columns: &str,
+        condition: &str,
+        params: Vec<Value>,
+        read_row: impl Fn(&Row) -> rusqlite::Result<T>,
+        mut f: impl FnMut(T),
+    ) -> io::Result<()> {
+        self.read(|conn| {
+            let mut statement = conn.prepare(&format!(
+                "SELECT {} FROM entries WHERE session = ? AND ({}) ORDER BY position",
+                columns, condition
+            ))?;
+            let params = std::iter::once(Value::Text(self.rows.key.clone())).chain(params);
+            let mut rows = statement.query(params_from_iter(params))?;
+            while let Some(row) = rows.next()? {
+                f(read_row(row)?);
+            }
+            Ok(())
+        })
+    }
+
+    // Distinct values of a field over the entries matching an SQL condition
+    pub fn values(
+        &self,
+        field: IndexedField,
+        condition: &str,
+        params: Vec<Value>,
+    ) -> io::Result<Vec<u32>> {
+        let column = match field {
+            IndexedField::Source => "source",
+            IndexedField::Level => "level",
+            IndexedField::Category => "category",
+            IndexedField::Thread => "thread",
+            IndexedField::Pid => "pid",
+            IndexedField::Object => "object",
+            IndexedField::Unit => "unit",
+        };
+        self.read(|conn| {
+            let mut statement = conn.prepare(&format!(
+                "SELECT DISTINCT {0} FROM entries WHERE session = ? AND {0} IS NOT NULL AND ({1})",
+                column, condition
+            ))?;
+            let params = std::iter::once(Value::Text(self.rows.key.clone())).chain(params);
+            let values = statement
+                .query_map(params_from_iter(params), |row| row.get(0))?
+                .collect();
+            values
+        })
+    }
+
+    // Run a query on the rows. Errors are reported as IO errors, like those of
+    // the entries of other backends.
+    fn read<T>(&self, query: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> io::Result<T> {
+        self.rows.db.read(query).map_err(io::Error::other)
+    }
+}
+
+impl Drop for Rows {
+    // Rows of sessions that aren't stored go away with the session
+    fn drop(&mut self) {
+        let result = self.db.conn.lock().unwrap().execute(
+            "DELETE FROM entries WHERE session = ?1
+             AND NOT EXISTS (SELECT 1 FROM sessions WHERE entries = ?1)",
+            params![self.key],
+        );
+        if let Err(e) = result {
+            log::error!("Failed to remove entries {}: {}", self.key, e);
+        }
+    }
+}
+
+// Open a connection to the database, with the functions filters use
+fn connect(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    // Message filters are regexes, `message REGEXP pattern` calls this
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            // The pattern is the same for every row, it's only compiled once
+            let regex: Arc<Regex> = ctx.get_or_create_aux(
+                0,
+                |pattern| -> Result<Regex, Box<dyn std::error::Error + Send + Sync>> {
+                    Ok(Regex::new(pattern.as_str()?)?)
+                },
+            )?;
+            let text = ctx
+                .get_raw(1)
+                .as_str()
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            Ok(regex.is_match(text))
+        },
+    )?;
+    Ok(conn)
+}
+
+fn read_entry(row: &Row) -> rusqlite::Result<SessionEntry> {
+    let symbol = |index: usize| -> rusqlite::Result<Symbol> {
+        let value: u32 = row.get(index)?;
+        Ok(Symbol::from_index(value as usize))
+    };
+    let ts: i64 = row.get(0)?;
+    let level: u32 = row.get(3)?;
+    let object: Option<u32> = row.get(9)?;
+    let source: i64 = row.get(10)?;
+    let line_number: i64 = row.get(11)?;
+    let offset: i64 = row.get(12)?;
+    let journal_timestamp: Option<i64> = row.get(13)?;
+    let journal_pid: Option<u32> = row.get(14)?;
+    let unit: Option<u32> = row.get(15)?;
+    let message: String = row.get(8)?;
+
+    Ok(SessionEntry {
+        ts: ts as u64,
+        pid: row.get(1)?,
+        thread: symbol(2)?,
+        level: LEVELS
+            .get(level as usize)
+            .copied()
+            .unwrap_or(DebugLevel::None),
+        category: symbol(4)?,
+        file: symbol(5)?,
+        line: row.get(6)?,
+        function: symbol(7)?,
+        message: message.into_boxed_str(),
+        object: object.map(|object| Symbol::from_index(object as usize)),
+        source: source as usize,
+        journal: journal_timestamp.map(|realtime_timestamp| {
+            Box::new(EntryJournal {
+                realtime_timestamp: realtime_timestamp as u64,
+                pid: journal_pid,
+                unit: unit.map(|unit| Symbol::from_index(unit as usize)),
+            })
+        }),
+        line_number: line_number as u64,
+        offset: offset as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterPlan;
+    use crate::models::{LogFilter, Source};
+    use crate::parser::ParseReport;
+
+    fn session() -> Session {
+        let mut session = Session::new();
+        session.sources = ["a.log", "b.log"]
+            .map(|name| Source {
+                name: name.to_string(),
+                format: None,
+                parse_report: ParseReport::default(),
+            })
+            .to_vec();
+        let strings = &mut session.strings;
+        let levels = [
+            DebugLevel::Error,
+            DebugLevel::Warning,
+            DebugLevel::Info,
+            DebugLevel::Debug,
+        ];
+        let entries: Vec<SessionEntry> = (0..12)
+            .map(|i| {
+                let category = ["a", "b", "GST_PADS"][i % 3];
+                let message = format!("message {}", i);
+                let mut entry = SessionEntry::for_test(
+                    strings,
+                    i as u64 * 1_000_000,
+                    category,
+                    levels[i % 4],
+                    &message,
+                );
+                entry.pid = 100 + (i % 2) as u32;
+                entry.thread = strings.intern(["t1", "t2"][i % 2]);
+                entry.function = strings.intern(["start", "stop", "loop"][i % 3]);
+                entry.object = (i % 3 == 0).then(|| strings.intern("src"));
+                entry.source = i % 2;
+                entry.line_number = i as u64 + 1;
+                if i % 2 == 0 {
+                    let unit = ["player.service", "other.service"][i / 2 % 2];
+                    entry.journal = Some(Box::new(EntryJournal {
+                        realtime_timestamp: i as u64,
+                        pid: Some(7 + (i / 4) as u32),
+                        unit: Some(strings.intern(unit)),
+                    }));
+                }
+                entry
+            })
+            .collect();
+        session.insert_entries(entries).unwrap();
+        session
+    }
+
+    fn page(plan: &FilterPlan, session: &Session, offset: usize) -> (usize, Vec<String>) {
+        let (total, entries) = plan.bind(session).page(offset, 4).unwrap();
+        let messages = entries
+            .iter()
+            .map(|entry| entry.message.to_string())
+            .collect();
+        (total, messages)
+    }
+
+    #[test]
+    fn sql_conditions_agree_with_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(SqliteDatabase::open(&dir.path().join("sessions.db")).unwrap());
+        let session = session();
+        db.save("session", &session).unwrap();
+        let stored = db.load("session", Session::new()).unwrap().unwrap();
+        assert!(matches!(stored.entries, Entries::Sqlite(_)));
+
+        for (filter, categories) in [
+            (serde_json::json!({}), vec![]),
+            (serde_json::json!({}), vec!["a", " b "]),
+            (
+                serde_json::json!({ "level": "Warning", "pid": 101 }),
+                vec![],
+            ),
+            (
+                serde_json::json!({ "thread": "t1", "object": "src" }),
+                vec![],
+            ),
+            (
+                serde_json::json!({ "function_regex": "^st", "source": "b.log" }),
+                vec![],
+            ),
+            (
+                serde_json::json!({ "unit": "player.service", "journal_pid": 7 }),
+                vec![],
+            ),
+            (
+                serde_json::json!({ "message_regex": "1$|2", "line_from": 2, "line_to": 11 }),
+                vec![],
+            ),
+            (
+                serde_json::json!({ "min_timestamp": 2, "max_timestamp": 8 }),
+                vec![],
+            ),
+            (serde_json::json!({ "thread": "missing" }), vec![]),
+            (
+                serde_json::json!({ "q": "unit:player* or not (category:a or level>=warn)" }),
+                vec![],
+            ),
+            (
+                serde_json::json!({ "q": "journal_pid > 7 and message:~\"[0-4]$\"" }),
+                vec![],
+            ),
+            (
+                serde_json::json!({ "q": "not unit:other.service and line_number < 9" }),
+                vec![],
+            ),
+            (
+                serde_json::json!({ "gst_debug": "*:WARN,GST_PADS:DEBUG" }),
+                vec![],
+            ),
+        ] {
+            let mut filter = filter;
+            filter["session_id"] = "session".into();
+            let mut filter: LogFilter = serde_json::from_value(filter).unwrap();
+            filter.categories = categories.into_iter().map(str::to_string).collect();
+            let plan = FilterPlan::compile(&filter).unwrap();
+            for offset in [0, 2] {
+                assert_eq!(
+                    page(&plan, &stored, offset),
+                    page(&plan, &session, offset),
+                    "{:?} from {}",
+                    filter,
+                    offset
+                );
+            }
+        }
+    }
+}